@@ -1,11 +1,20 @@
-use anyhow::{ensure, Result};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use log::warn;
 use rand::Rng;
-use zerocopy::{FromBytes, Immutable, IntoBytes};
+use subtle::ConstantTimeEq;
+use zerocopy::{byteorder::network_endian::U64, FromBytes, FromZeros, Immutable, IntoBytes};
+use zeroize::Zeroizing;
 
-use super::hash_domain::HashDomain;
+use super::{
+    hash_domain::HashDomain,
+    ml_kem::{self, Ciphertext, DecapsulationKey, EncapsulationKey, SharedSecret},
+    ClientHello, Message, ProtocolFeatures, ServerHello,
+};
 use crate::internal::{
-    etsi014::Etsi014Key,
-    util::{CascadeExt, UuidBytes},
+    key_source::QkdKey,
+    util::{CascadeExt, ConstLenExt, UuidBytes},
 };
 
 pub const KEY_LENGTH: usize = 32;
@@ -16,6 +25,7 @@ pub const REKEY_INTERVAL: u64 = 120;
 pub type Key = [u8; KEY_LENGTH];
 pub type Nonce = Key;
 pub type HashValue = Key;
+pub type Mac = Key;
 
 enum ProtocolDomains {}
 
@@ -30,6 +40,22 @@ impl ProtocolDomains {
     pub fn derive_key() -> HashDomain {
         Self::root().mix(b"derive key")
     }
+
+    pub fn message_authentication() -> HashDomain {
+        Self::root().mix(b"message authentication")
+    }
+
+    pub fn key_confirmation() -> HashDomain {
+        Self::root().mix(b"key confirmation")
+    }
+
+    pub fn ratchet() -> HashDomain {
+        Self::root().mix(b"ratchet")
+    }
+
+    pub fn transcript() -> HashDomain {
+        Self::root().mix(b"transcript")
+    }
 }
 
 /// WireGuard public key
@@ -43,6 +69,53 @@ pub struct DaisywayProtocolParameters {
     pub psk: Key,
     pub local_peer_id: PeerId,
     pub remote_peer_id: PeerId,
+    /// Optional protocol features we are willing to use
+    pub features: ProtocolFeatures,
+    /// Key material for the post-quantum KEM; present if [ProtocolFeatures::PQ_KEM] is offered
+    pub kem: Option<KemKeys>,
+}
+
+/// ML-KEM-768 key material for mixing a post-quantum shared secret into every output key
+///
+/// The output key then stays secure as long as either the QKD key or the KEM is secure.
+/// The peer sending the rekey request encapsulates a fresh shared secret to the receiver's
+/// public key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct KemKeys {
+    pub secret_key: Arc<DecapsulationKey>,
+    pub peer_public_key: Arc<EncapsulationKey>,
+}
+
+impl KemKeys {
+    pub fn encapsulate(&self) -> Result<(Ciphertext, SharedSecret)> {
+        ml_kem::encapsulate(&self.peer_public_key)
+    }
+
+    pub fn decapsulate(&self, ciphertext: &Ciphertext) -> SharedSecret {
+        ml_kem::decapsulate(&self.secret_key, ciphertext)
+    }
+}
+
+impl std::fmt::Debug for KemKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KemKeys")
+            .field("secret_key", &"...")
+            .field("peer_public_key", &"...")
+            .finish()
+    }
+}
+
+impl DaisywayProtocolParameters {
+    pub fn connection_id(&self) -> WireGuardConnectionId {
+        WireGuardConnectionId::new(self.local_peer_id, self.remote_peer_id)
+    }
+
+    /// Hash domain keyed with the PSK and the connection ID; used to authenticate protocol messages
+    fn mac_key(&self) -> HashDomain {
+        ProtocolDomains::message_authentication()
+            .mix(&self.psk)
+            .mix(self.connection_id().as_bytes())
+    }
 }
 
 #[repr(C, packed)]
@@ -66,75 +139,554 @@ impl WireGuardConnectionId {
 #[derive(Debug, FromBytes, IntoBytes, Immutable)]
 #[allow(dead_code)] // Used through zerocopy conversion
 struct KdfInput {
-    psk: Key,                                       // +32 = 64
-    nonce: Nonce,                                   // +32 = 96
-    qkd_key: Key,                                   // +32 = 128
-    qkd_key_id: UuidBytes,                          // +16 = 144
-    wireguard_connection_id: WireGuardConnectionId, // +64 = 208
+    psk: Key,                                       // +32 = 32
+    nonce: Nonce,                                   // +32 = 64
+    wireguard_connection_id: WireGuardConnectionId, // +64 = 128
+    kem_shared_secret: SharedSecret,                // +32 = 160
 }
 
-impl KdfInput {
-    pub fn new(
-        psk: Key,
-        nonce: Key,
-        qkd_key: Etsi014Key,
-        wireguard_connection_id: WireGuardConnectionId,
-    ) -> Self {
-        let Etsi014Key {
-            key: qkd_key,
-            id: qkd_key_id,
-        } = qkd_key;
-        let qkd_key_id = qkd_key_id.to_bytes_le();
-        Self {
-            psk,
-            nonce,
-            qkd_key,
-            qkd_key_id,
-            wireguard_connection_id,
-        }
-    }
+/// A single QKD key as mixed into the output key
+///
+/// The key material may have any length; it is followed by the fixed-length key ID, so the
+/// encoding stays unambiguous.
+fn kdf_qkd_key(key: &QkdKey) -> Zeroizing<Vec<u8>> {
+    let key_id: UuidBytes = key.id.to_bytes_le();
+    let mut buf = Zeroizing::new(Vec::with_capacity(key.key.len() + key_id.len()));
+    buf.extend_from_slice(&key.key);
+    buf.extend_from_slice(&key_id);
+    buf
 }
 
+/// Derive the output key from the QKD keys of all key sources
+///
+/// The output key stays secret as long as a single one of the QKD keys does. The KEM shared
+/// secret is only present if the KEM was negotiated.
 pub fn derive_daisyway_key(
     params: &DaisywayProtocolParameters,
     nonce: Nonce,
-    key: Etsi014Key,
+    keys: &[QkdKey],
+    kem_shared_secret: Option<SharedSecret>,
 ) -> Key {
-    let conn_id = WireGuardConnectionId::new(params.local_peer_id, params.remote_peer_id);
-    let kdf_input = KdfInput::new(params.psk, nonce, key, conn_id);
-    ProtocolDomains::derive_key()
-        .mix(kdf_input.as_bytes())
+    let kdf_input = KdfInput {
+        psk: params.psk,
+        nonce,
+        wireguard_connection_id: params.connection_id(),
+        kem_shared_secret: kem_shared_secret.unwrap_or_else(SharedSecret::new_zeroed),
+    };
+    keys.iter()
+        .fold(
+            ProtocolDomains::derive_key().mix(kdf_input.as_bytes()),
+            |domain, key| domain.mix(&kdf_qkd_key(key)),
+        )
         .into_key()
 }
 
+/// Chain key linking every output key to all previous ones within a session
+///
+/// Each output key is mixed with the chain key, which is then irreversibly advanced using the
+/// same output key. A compromised QKD key therefore does not reveal later output keys, and a
+/// single QKD key unknown to an attacker heals a compromised chain.
+///
+/// The chain is deliberately not persisted: it starts over from the PSK and the hello messages
+/// with every connection, so both peers can not fall out of step after a crash or a lost
+/// acknowledgement. The protection therefore only covers output keys of the same session.
+#[derive(Debug, Clone)]
+pub struct KeyRatchet {
+    chain: HashDomain,
+}
+
+impl KeyRatchet {
+    /// Start a new chain for the session established by the given hello messages
+    pub fn new(
+        params: &DaisywayProtocolParameters,
+        client_hello: &ClientHello,
+        server_hello: &ServerHello,
+    ) -> Self {
+        let chain = ProtocolDomains::ratchet()
+            .mix(&params.psk)
+            .mix(params.connection_id().as_bytes())
+            .mix(&client_hello.encode())
+            .mix(server_hello.as_bytes());
+        Self { chain }
+    }
+
+    /// Derive the ratcheted output key and the next state of the chain
+    ///
+    /// The current state is left untouched, so the caller can discard the result if the
+    /// rekey fails.
+    pub fn advance(&self, key: &Key) -> (Self, Key) {
+        let (chain, osk) = self.chain.clone().mix_fork(key);
+        (Self { chain }, osk.into_key())
+    }
+}
+
+/// Hash of the hello messages, identifying a single session
+///
+/// The [ServerHello] carries a fresh challenge, so rekey requests bound to the transcript can
+/// not be replayed into another session.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionTranscript(HashValue);
+
+impl SessionTranscript {
+    pub fn new(client_hello: &ClientHello, server_hello: &ServerHello) -> Self {
+        let hash = ProtocolDomains::transcript()
+            .mix(&client_hello.encode())
+            .mix(server_hello.as_bytes())
+            .into_key();
+        Self(hash)
+    }
+}
+
+/// Counter of the rekey requests within a single session
+///
+/// Requests of other sessions already fail the MAC, as it covers the [SessionTranscript], so
+/// the counter only orders the requests of one session. It starts over with every handshake
+/// and needs no persistent state, so peers can not fall out of step after losing their state
+/// files. A replay of a whole session, hello messages included, reuses its QKD key IDs and is
+/// rejected by the replay cache of the [StateStore](crate::internal::daisyway::state::StateStore).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SessionCounter(u64);
+
+impl SessionCounter {
+    /// Counter for the next request we send
+    pub fn advance(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
+
+    /// Accept a received counter if it exceeds every counter accepted before in this session
+    pub fn accept(&mut self, counter: u64) -> Result<()> {
+        let last = self.0;
+        if counter <= last {
+            warn!("Replay detected: Rekey request counter {counter} does not exceed previous counter {last}");
+            bail!(
+                "Rekey request counter {counter} is not greater than the counter {last} \
+                of a previous request in this session. The request was replayed or reordered."
+            );
+        }
+        self.0 = counter;
+        Ok(())
+    }
+}
+
+/// Compare two MACs or hash values in constant time
+fn hash_values_eq(a: &HashValue, b: &HashValue) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Answer to the challenge in the [ServerHello]
+///
+/// Proves knowledge of the PSK before the server spends any QKD key material on the connection.
+/// The MAC covers both hello messages, so the version negotiation is authenticated too.
 #[repr(C, packed)]
-#[derive(Debug, FromBytes, IntoBytes, Immutable)]
+#[derive(Debug, FromBytes, IntoBytes, Immutable, Clone, Copy)]
+pub struct ClientAuth {
+    pub mac: Mac,
+}
+
+impl ClientAuth {
+    pub fn new(
+        params: &DaisywayProtocolParameters,
+        client_hello: &ClientHello,
+        server_hello: &ServerHello,
+    ) -> Self {
+        Self {
+            mac: Self::compute_mac(params, client_hello, server_hello),
+        }
+    }
+
+    fn compute_mac(
+        params: &DaisywayProtocolParameters,
+        client_hello: &ClientHello,
+        server_hello: &ServerHello,
+    ) -> Mac {
+        params
+            .mac_key()
+            .mix(b"client authentication")
+            .mix(&client_hello.encode())
+            .mix(server_hello.as_bytes())
+            .into_key()
+    }
+
+    pub fn validate(
+        &self,
+        params: &DaisywayProtocolParameters,
+        client_hello: &ClientHello,
+        server_hello: &ServerHello,
+    ) -> Result<()> {
+        let mac = self.mac;
+        ensure!(
+            hash_values_eq(&mac, &Self::compute_mac(params, client_hello, server_hello)),
+            "Client authentication failed. The peer does not know our PSK \
+            or the handshake was tampered with."
+        );
+        Ok(())
+    }
+}
+
+/// Fixed-size part at the start of an encoded [RekeyReq]
+#[repr(C, packed)]
+#[derive(Debug, FromBytes, IntoBytes, Immutable, Clone, Copy)]
+struct RekeyReqHeader {
+    counter: U64,
+    nonce: Nonce,
+    qkd_key_count: u8,
+}
+
+/// Request to derive a new output key
+///
+/// Wire format: `counter || nonce || qkd_key_count || qkd_key_ids || [kem_ciphertext] || mac`
+#[derive(Debug, Clone)]
 pub struct RekeyReq {
-    pub qkd_key_id: UuidBytes,
+    /// Strictly increasing within a session; see [SessionCounter]
+    pub counter: u64,
     pub nonce: Nonce,
+    /// One key ID for every QKD key source, in the order the key sources are configured
+    pub qkd_key_ids: Vec<UuidBytes>,
+    /// ML-KEM encapsulation to the receiver's public key; present if the KEM was negotiated
+    pub kem_ciphertext: Option<Box<Ciphertext>>,
+    pub mac: Mac,
 }
 
 impl RekeyReq {
-    pub fn new(qkd_key_id: UuidBytes) -> Self {
+    pub fn new(
+        params: &DaisywayProtocolParameters,
+        transcript: &SessionTranscript,
+        counter: u64,
+        qkd_key_ids: Vec<UuidBytes>,
+        kem_ciphertext: Option<Box<Ciphertext>>,
+    ) -> Result<Self> {
+        ensure!(
+            qkd_key_ids.len() <= u8::MAX as usize,
+            "Rekey requests can carry at most {} QKD key IDs",
+            u8::MAX
+        );
         let nonce: Nonce = rand::rng().random();
-        Self { qkd_key_id, nonce }
+        let mut req = Self {
+            counter,
+            nonce,
+            qkd_key_ids,
+            kem_ciphertext,
+            mac: Mac::new_zeroed(),
+        };
+        req.mac = req.compute_mac(params, transcript);
+        Ok(req)
+    }
+
+    /// The encoded message without the MAC
+    fn authenticated_bytes(&self) -> Vec<u8> {
+        let header = RekeyReqHeader {
+            counter: self.counter.into(),
+            nonce: self.nonce,
+            qkd_key_count: self.qkd_key_ids.len() as u8,
+        };
+        let mut buf = header.as_bytes().to_vec();
+        buf.extend(self.qkd_key_ids.iter().flatten());
+        if let Some(ciphertext) = &self.kem_ciphertext {
+            buf.extend_from_slice(&ciphertext[..]);
+        }
+        buf
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = self.authenticated_bytes();
+        buf.extend_from_slice(&self.mac);
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let (header, rest) = RekeyReqHeader::read_from_prefix(payload)
+            .map_err(|_| anyhow!("Rekey request of {} bytes is too short", payload.len()))?;
+        let (qkd_key_ids, rest) =
+            <[UuidBytes]>::ref_from_prefix_with_elems(rest, header.qkd_key_count as usize)
+                .map_err(|_| anyhow!("Rekey request of {} bytes is too short", payload.len()))?;
+        ensure!(
+            rest.len() >= Mac::LEN,
+            "Rekey request of {} bytes is too short",
+            payload.len()
+        );
+        let (kem_ciphertext, mac) = rest.split_at(rest.len() - Mac::LEN);
+        let kem_ciphertext = match kem_ciphertext.len() {
+            0 => None,
+            Ciphertext::LEN => Some(Box::new(kem_ciphertext.try_into()?)),
+            len => bail!("Rekey request contains a KEM ciphertext of invalid length {len}"),
+        };
+        Ok(Self {
+            counter: header.counter.get(),
+            nonce: header.nonce,
+            qkd_key_ids: qkd_key_ids.to_vec(),
+            kem_ciphertext,
+            mac: mac.try_into()?,
+        })
+    }
+
+    fn compute_mac(
+        &self,
+        params: &DaisywayProtocolParameters,
+        transcript: &SessionTranscript,
+    ) -> Mac {
+        params
+            .mac_key()
+            .mix(b"rekey request")
+            .mix(&transcript.0)
+            .mix(&self.authenticated_bytes())
+            .into_key()
+    }
+
+    /// Check that the request was produced in this session by a peer knowing the PSK
+    pub fn validate(
+        &self,
+        params: &DaisywayProtocolParameters,
+        transcript: &SessionTranscript,
+    ) -> Result<()> {
+        let mac = self.mac;
+        ensure!(
+            hash_values_eq(&mac, &self.compute_mac(params, transcript)),
+            "Rekey request carries an invalid MAC. The peer does not know our PSK, \
+            the request belongs to another session or the message was tampered with."
+        );
+        Ok(())
     }
 }
 
 #[repr(C, packed)]
-#[derive(Debug, FromBytes, IntoBytes, Immutable, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, FromBytes, IntoBytes, Immutable, Clone, Copy)]
 pub struct RekeyAck {
-    pub dummy_data: u8,
+    /// Proves that the peer derived the same output key
+    pub key_confirmation: HashValue,
+    pub mac: Mac,
 }
 
 impl RekeyAck {
-    pub fn validate(&self) -> Result<()> {
+    pub fn new(params: &DaisywayProtocolParameters, req: &RekeyReq, osk: &Key) -> Self {
+        let mut ack = Self {
+            key_confirmation: Self::key_confirmation_for(osk),
+            mac: Mac::new_zeroed(),
+        };
+        ack.mac = ack.compute_mac(params, req);
+        ack
+    }
+
+    fn key_confirmation_for(osk: &Key) -> HashValue {
+        ProtocolDomains::key_confirmation().mix(osk).into_key()
+    }
+
+    /// The MAC binds the acknowledgement to the request it answers
+    fn compute_mac(&self, params: &DaisywayProtocolParameters, req: &RekeyReq) -> Mac {
+        let key_confirmation = self.key_confirmation;
+        params
+            .mac_key()
+            .mix(b"rekey acknowledgement")
+            .mix(&req.encode())
+            .mix(&key_confirmation)
+            .into_key()
+    }
+
+    /// Check that the acknowledgement is authentic and that the peer derived the same output key
+    pub fn validate(
+        &self,
+        params: &DaisywayProtocolParameters,
+        req: &RekeyReq,
+        osk: &Key,
+    ) -> Result<()> {
+        let Self {
+            key_confirmation,
+            mac,
+        } = *self;
         ensure!(
-            self == &REKEY_ACK,
-            "Rekey acknowledgement is invalid: Expected {REKEY_ACK:?} but received {self:?}"
+            hash_values_eq(&mac, &self.compute_mac(params, req)),
+            "Rekey acknowledgement carries an invalid MAC. The peer does not know our PSK \
+            or the message was tampered with."
+        );
+        ensure!(
+            hash_values_eq(&key_confirmation, &Self::key_confirmation_for(osk)),
+            "Key confirmation failed: The peer derived a different output key."
         );
         Ok(())
     }
 }
 
-pub const REKEY_ACK: RekeyAck = RekeyAck { dummy_data: 1 };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params_pair(psk: Key) -> (DaisywayProtocolParameters, DaisywayProtocolParameters) {
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        let params = |local_peer_id, remote_peer_id| DaisywayProtocolParameters {
+            psk,
+            local_peer_id,
+            remote_peer_id,
+            features: ProtocolFeatures::NONE,
+            kem: None,
+        };
+        (params(a, b), params(b, a))
+    }
+
+    fn hellos() -> (ClientHello, ServerHello) {
+        let client_hello = ClientHello::new(ProtocolFeatures::NONE, [1u8; 32]);
+        let server_hello = ServerHello::new(1, ProtocolFeatures::NONE);
+        (client_hello, server_hello)
+    }
+
+    /// Transcript of a fresh session
+    fn transcript() -> SessionTranscript {
+        let (client_hello, server_hello) = hellos();
+        SessionTranscript::new(&client_hello, &server_hello)
+    }
+
+    #[test]
+    fn client_auth_accepts_same_psk() {
+        let (client, server) = params_pair([7u8; 32]);
+        let (client_hello, server_hello) = hellos();
+        let auth = ClientAuth::new(&client, &client_hello, &server_hello);
+        auth.validate(&server, &client_hello, &server_hello)
+            .unwrap();
+    }
+
+    #[test]
+    fn client_auth_rejects_other_psk() {
+        let (client, _) = params_pair([7u8; 32]);
+        let (_, server) = params_pair([8u8; 32]);
+        let (client_hello, server_hello) = hellos();
+        let auth = ClientAuth::new(&client, &client_hello, &server_hello);
+        assert!(auth
+            .validate(&server, &client_hello, &server_hello)
+            .is_err());
+    }
+
+    #[test]
+    fn client_auth_rejects_other_challenge() {
+        let (client, server) = params_pair([7u8; 32]);
+        let (client_hello, server_hello) = hellos();
+        let auth = ClientAuth::new(&client, &client_hello, &server_hello);
+        let other_server_hello = ServerHello::new(1, ProtocolFeatures::NONE);
+        assert!(auth
+            .validate(&server, &client_hello, &other_server_hello)
+            .is_err());
+    }
+
+    #[test]
+    fn rekey_req_round_trip() {
+        let (client, server) = params_pair([7u8; 32]);
+        let session = transcript();
+        let req = RekeyReq::new(&client, &session, 5, vec![[3u8; 16], [4u8; 16]], None).unwrap();
+        let decoded = RekeyReq::decode(&req.encode()).unwrap();
+        assert_eq!(decoded.counter, 5);
+        assert_eq!(decoded.nonce, req.nonce);
+        assert_eq!(decoded.qkd_key_ids, req.qkd_key_ids);
+        assert!(decoded.kem_ciphertext.is_none());
+        decoded.validate(&server, &session).unwrap();
+    }
+
+    #[test]
+    fn rekey_req_rejects_tampering() {
+        let (client, server) = params_pair([7u8; 32]);
+        let session = transcript();
+        let req = RekeyReq::new(&client, &session, 5, vec![[3u8; 16]], None).unwrap();
+        let encoded = req.encode();
+        for idx in 0..encoded.len() {
+            let mut tampered = encoded.clone();
+            tampered[idx] ^= 1;
+            let res = RekeyReq::decode(&tampered).and_then(|req| req.validate(&server, &session));
+            assert!(res.is_err(), "Flipping byte {idx} was not detected");
+        }
+    }
+
+    #[test]
+    fn rekey_req_rejects_other_psk() {
+        let (client, _) = params_pair([7u8; 32]);
+        let (_, server) = params_pair([8u8; 32]);
+        let session = transcript();
+        let req = RekeyReq::new(&client, &session, 5, vec![[3u8; 16]], None).unwrap();
+        assert!(req.validate(&server, &session).is_err());
+    }
+
+    #[test]
+    fn rekey_req_rejects_other_session() {
+        let (client, server) = params_pair([7u8; 32]);
+        let (client_hello, server_hello) = hellos();
+        let session = SessionTranscript::new(&client_hello, &server_hello);
+        let req = RekeyReq::new(&client, &session, 5, vec![[3u8; 16]], None).unwrap();
+        req.validate(&server, &session).unwrap();
+
+        let other_session =
+            SessionTranscript::new(&client_hello, &ServerHello::new(1, ProtocolFeatures::NONE));
+        assert!(req.validate(&server, &other_session).is_err());
+    }
+
+    #[test]
+    fn session_counter_increases() {
+        let mut counter = SessionCounter::default();
+        assert_eq!(counter.advance(), 1);
+        assert_eq!(counter.advance(), 2);
+    }
+
+    #[test]
+    fn session_counter_rejects_replays() {
+        let mut counter = SessionCounter::default();
+        counter.accept(1).unwrap();
+        counter.accept(3).unwrap();
+        assert!(counter.accept(3).is_err());
+        assert!(counter.accept(2).is_err());
+        counter.accept(4).unwrap();
+        // A new session starts over
+        SessionCounter::default().accept(1).unwrap();
+    }
+
+    #[test]
+    fn rekey_ack_confirms_key() {
+        let (client, server) = params_pair([7u8; 32]);
+        let session = transcript();
+        let req = RekeyReq::new(&client, &session, 5, vec![[3u8; 16]], None).unwrap();
+        let osk = [9u8; 32];
+        let ack = RekeyAck::new(&server, &req, &osk);
+        ack.validate(&client, &req, &osk).unwrap();
+    }
+
+    #[test]
+    fn rekey_ack_rejects_other_key() {
+        let (client, server) = params_pair([7u8; 32]);
+        let session = transcript();
+        let req = RekeyReq::new(&client, &session, 5, vec![[3u8; 16]], None).unwrap();
+        let ack = RekeyAck::new(&server, &req, &[9u8; 32]);
+        let err = ack.validate(&client, &req, &[10u8; 32]).unwrap_err();
+        assert!(err.to_string().contains("Key confirmation failed"));
+    }
+
+    #[test]
+    fn rekey_ack_is_bound_to_request() {
+        let (client, server) = params_pair([7u8; 32]);
+        let session = transcript();
+        let req = RekeyReq::new(&client, &session, 5, vec![[3u8; 16]], None).unwrap();
+        let other_req = RekeyReq::new(&client, &session, 6, vec![[3u8; 16]], None).unwrap();
+        let osk = [9u8; 32];
+        let ack = RekeyAck::new(&server, &req, &osk);
+        assert!(ack.validate(&client, &other_req, &osk).is_err());
+    }
+
+    #[test]
+    fn rekey_ack_rejects_tampered_mac() {
+        let (client, server) = params_pair([7u8; 32]);
+        let session = transcript();
+        let req = RekeyReq::new(&client, &session, 5, vec![[3u8; 16]], None).unwrap();
+        let osk = [9u8; 32];
+        let mut ack = RekeyAck::new(&server, &req, &osk);
+        ack.mac[0] ^= 1;
+        assert!(ack.validate(&client, &req, &osk).is_err());
+    }
+
+    #[test]
+    fn derived_key_depends_on_every_qkd_key() {
+        let (client, server) = params_pair([7u8; 32]);
+        let keys = |second: u8| {
+            [1u8, second].map(|byte| QkdKey {
+                id: uuid::Uuid::from_u128(byte as u128),
+                key: Zeroizing::new(vec![byte; 32]),
+            })
+        };
+        let nonce = [5u8; 32];
+        let osk = derive_daisyway_key(&client, nonce, &keys(2), None);
+        assert_eq!(osk, derive_daisyway_key(&server, nonce, &keys(2), None));
+        assert_ne!(osk, derive_daisyway_key(&server, nonce, &keys(3), None));
+    }
+}
@@ -1,72 +1,209 @@
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
-use log::debug;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
 use uuid::Uuid;
-use zerocopy::{FromZeros, IntoBytes};
 
-use super::{derive_daisyway_key, DaisywayProtocolParameters, Key, RekeyReq};
-use crate::internal::{daisyway::crypto::REKEY_ACK, etsi014::Etsi014Connection, osk::OskHandler};
+use super::{
+    derive_daisyway_key, ml_kem::SharedSecret, ClientAuth, ClientHello, DaisywayProtocolParameters,
+    FramedStream, Key, KeyRatchet, ProtocolFeatures, RekeyAck, RekeyReq, ServerHello,
+    SessionCounter, SessionTranscript, HANDSHAKE_TIMEOUT,
+};
+use crate::internal::{
+    daisyway::state::StateStore,
+    key_source::{KeySourceSet, QkdKeySource},
+    osk::OskHandler,
+};
 
-pub struct DaisywayClientProtocol<O, Stream>
+pub struct DaisywayClientProtocol<O, Stream, K>
 where
     O: OskHandler,
     Stream: AsyncRead + AsyncWrite + Unpin,
+    K: QkdKeySource,
 {
     pub protocol_params: DaisywayProtocolParameters,
-    pub stream: Stream,
-    pub etsi_client: Arc<Etsi014Connection>,
+    pub stream: FramedStream<Stream>,
+    pub key_sources: Arc<KeySourceSet<K>>,
     pub osk_handler: O,
+    pub state: Arc<StateStore>,
+    /// Features negotiated for this session
+    pub features: ProtocolFeatures,
+    /// Present if the ratchet was negotiated for this session
+    pub ratchet: Option<KeyRatchet>,
+    /// Counter of the last rekey request accepted in this session
+    pub counter: SessionCounter,
 }
 
-impl<O, Stream> DaisywayClientProtocol<O, Stream>
+impl<O, Stream, K> DaisywayClientProtocol<O, Stream, K>
 where
     O: OskHandler,
     Stream: AsyncRead + AsyncWrite + Unpin,
+    K: QkdKeySource,
 {
     pub fn new(
         protocol_params: DaisywayProtocolParameters,
         stream: Stream,
-        etsi_client: Arc<Etsi014Connection>,
+        key_sources: Arc<KeySourceSet<K>>,
         osk_handler: O,
+        state: Arc<StateStore>,
     ) -> Self {
         Self {
             protocol_params,
-            stream,
-            etsi_client,
+            stream: FramedStream::new(stream),
+            key_sources,
             osk_handler,
+            state,
+            features: ProtocolFeatures::NONE,
+            ratchet: None,
+            counter: SessionCounter::default(),
         }
     }
 
     pub async fn event_loop(&mut self) -> Result<()> {
+        let transcript = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.handshake())
+            .await
+            .context("Peer did not complete the handshake in time")??;
         loop {
-            let key = self.wait_for_key_negotiation().await?;
+            let key = self.wait_for_key_negotiation(&transcript).await?;
             self.osk_handler.set_fresh_osk(key).await?;
         }
     }
 
-    async fn wait_for_key_negotiation(&mut self) -> Result<Key> {
-        let mut rekey_req = RekeyReq::new_zeroed();
+    async fn handshake(&mut self) -> Result<SessionTranscript> {
+        let client_hello = ClientHello::new(
+            self.protocol_params.features,
+            self.protocol_params.local_peer_id,
+        );
+        self.stream.send(&client_hello).await?;
+
+        let server_hello: ServerHello = self
+            .stream
+            .recv()
+            .await
+            .context("Failed to receive server hello")?;
+        let version = server_hello
+            .validate(&client_hello)
+            .context("Failed to negotiate protocol version")?;
+        self.stream.set_version(version);
+        let features = server_hello.features();
+        info!("[SERVER] Negotiated protocol version {version} with features {features:?}");
+        if let Err(err) = self
+            .protocol_params
+            .features
+            .ensure_mandatory_negotiated(features)
+        {
+            self.stream.abort(&err.to_string()).await?;
+            return Err(err);
+        }
+        self.features = features;
+        self.counter = SessionCounter::default();
+
+        let auth = ClientAuth::new(&self.protocol_params, &client_hello, &server_hello);
         self.stream
-            .read_exact(rekey_req.as_mut_bytes())
+            .send(&auth)
+            .await
+            .context("Failed to answer authentication challenge")?;
+
+        if features.contains(ProtocolFeatures::RATCHET) {
+            self.ratchet = Some(KeyRatchet::new(
+                &self.protocol_params,
+                &client_hello,
+                &server_hello,
+            ));
+        } else if self
+            .protocol_params
+            .features
+            .contains(ProtocolFeatures::RATCHET)
+        {
+            warn!("[SERVER] Peer does not support the key ratchet; continuing without it");
+        }
+
+        Ok(SessionTranscript::new(&client_hello, &server_hello))
+    }
+
+    async fn wait_for_key_negotiation(&mut self, transcript: &SessionTranscript) -> Result<Key> {
+        let rekey_req: RekeyReq = self
+            .stream
+            .recv()
             .await
             .context("Failed to read rekey request message")?;
+        rekey_req
+            .validate(&self.protocol_params, transcript)
+            .context("Refusing to process rekey request")?;
+        self.counter
+            .accept(rekey_req.counter)
+            .context("Refusing to process rekey request")?;
+
+        let kem_shared_secret = self
+            .decapsulate(&rekey_req)
+            .context("Refusing to process rekey request")?;
 
         let nonce = rekey_req.nonce;
-        let key = self
-            .etsi_client
-            .fetch_specific_key(Uuid::from_bytes(rekey_req.qkd_key_id))
+        let key_ids: Vec<Uuid> = rekey_req
+            .qkd_key_ids
+            .iter()
+            .copied()
+            .map(Uuid::from_bytes)
+            .collect();
+        for key_id in &key_ids {
+            self.state
+                .ensure_key_unused(key_id)
+                .context("Refusing to process rekey request")?;
+        }
+        let keys = self
+            .key_sources
+            .fetch_specific_keys(&key_ids)
             .await
-            .context("Failed to fetch key from QKD device")?;
+            .context("Failed to fetch keys from QKD devices")?;
+
+        debug!("[SERVER] Received QKD IDs: {key_ids:?}");
+
+        let osk = derive_daisyway_key(&self.protocol_params, nonce, &keys, kem_shared_secret);
+        let osk = match &self.ratchet {
+            Some(ratchet) => {
+                let (ratchet, osk) = ratchet.advance(&osk);
+                self.ratchet = Some(ratchet);
+                osk
+            }
+            None => osk,
+        };
 
+        // Persist before acknowledging, so the peer never installs a key whose QKD keys
+        // could be replayed after a restart
+        let epoch = self.state.record_rekey(&key_ids);
+        self.state
+            .persist()
+            .await
+            .context("Refusing to acknowledge a rekey that could not be persisted")?;
+
+        let ack = RekeyAck::new(&self.protocol_params, &rekey_req, &osk);
         self.stream
-            .write_all(REKEY_ACK.as_bytes())
+            .send(&ack)
             .await
             .context("Failed to send rekey acknowledgement message")?;
+        info!("[SERVER] Completed rekey epoch {epoch}");
 
-        debug!("[SERVER] Received QKD ID: {}", key.id);
+        Ok(osk)
+    }
 
-        Ok(derive_daisyway_key(&self.protocol_params, nonce, key))
+    /// Recover the KEM shared secret from the request, if the KEM was negotiated
+    fn decapsulate(&self, rekey_req: &RekeyReq) -> Result<Option<SharedSecret>> {
+        let kem_negotiated = self.features.contains(ProtocolFeatures::PQ_KEM);
+        match (&rekey_req.kem_ciphertext, kem_negotiated) {
+            (None, false) => Ok(None),
+            (Some(ciphertext), true) => {
+                let kem = self
+                    .protocol_params
+                    .kem
+                    .as_ref()
+                    .context("KEM negotiated without KEM keys. This is a bug.")?;
+                Ok(Some(kem.decapsulate(ciphertext)))
+            }
+            (None, true) => bail!("Rekey request lacks the KEM ciphertext"),
+            (Some(_), false) => {
+                bail!("Rekey request carries a KEM ciphertext, but the KEM was not negotiated")
+            }
+        }
     }
 }
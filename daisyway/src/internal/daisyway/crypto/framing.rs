@@ -0,0 +1,546 @@
+//! Framing layer for daisyway protocol messages
+//!
+//! Every message is prefixed with a [FrameHeader] containing a magic value, the protocol
+//! version, the message type and the payload length. This allows the wire format to evolve
+//! and makes sure that incompatible peers are detected instead of misinterpreting each
+//! other's messages.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zerocopy::{
+    byteorder::network_endian::{U16, U32},
+    FromBytes, FromZeros, Immutable, IntoBytes,
+};
+
+use super::{ClientAuth, Nonce, PeerId, RekeyAck, RekeyReq};
+use crate::internal::util::ConstLenExt;
+
+pub const FRAME_MAGIC: [u8; 4] = *b"DSYW";
+
+pub type ProtocolVersion = u8;
+
+/// Lowest protocol version supported by this implementation
+pub const MIN_PROTOCOL_VERSION: ProtocolVersion = 1;
+
+/// Highest protocol version supported by this implementation
+pub const MAX_PROTOCOL_VERSION: ProtocolVersion = 1;
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MessageType {
+    /// Tells the peer why the connection is being closed; payload is a UTF-8 string
+    Abort = 0,
+    ClientHello = 1,
+    ServerHello = 2,
+    RekeyReq = 3,
+    RekeyAck = 4,
+    ClientAuth = 5,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use MessageType as T;
+        let r = match value {
+            0 => T::Abort,
+            1 => T::ClientHello,
+            2 => T::ServerHello,
+            3 => T::RekeyReq,
+            4 => T::RekeyAck,
+            5 => T::ClientAuth,
+            _ => bail!("Received message of unknown type {value}"),
+        };
+        Ok(r)
+    }
+}
+
+/// Set of optional protocol features, negotiated during the handshake
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub struct ProtocolFeatures(u32);
+
+impl ProtocolFeatures {
+    pub const NONE: Self = Self(0);
+    /// Chain every output key into the next one; see [super::KeyRatchet]
+    pub const RATCHET: Self = Self(1 << 0);
+    /// Mix an ML-KEM shared secret into every output key; see [super::KemKeys]
+    pub const PQ_KEM: Self = Self(1 << 1);
+
+    /// Features that must be used by both peers if either one enables them
+    pub const MANDATORY: Self = Self::PQ_KEM;
+
+    const NAMES: &[(Self, &str)] = &[(Self::RATCHET, "ratchet"), (Self::PQ_KEM, "pq-kem")];
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Ensure that every mandatory feature we offered has been negotiated
+    pub fn ensure_mandatory_negotiated(self, negotiated: Self) -> Result<()> {
+        let missing = Self(self.intersection(Self::MANDATORY).0 & !negotiated.0);
+        ensure!(
+            missing == Self::NONE,
+            "Peer does not support the protocol features {missing:?}, \
+            which are required by our configuration"
+        );
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for ProtocolFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut set = f.debug_set();
+        let mut unknown = self.0;
+        for (feature, name) in Self::NAMES {
+            if self.contains(*feature) {
+                set.entry(&format_args!("{name}"));
+                unknown &= !feature.0;
+            }
+        }
+        if unknown != 0 {
+            set.entry(&format_args!("{unknown:#x}"));
+        }
+        set.finish()
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug, FromBytes, IntoBytes, Immutable, Clone, Copy)]
+pub struct FrameHeader {
+    pub magic: [u8; 4],
+    pub version: ProtocolVersion,
+    pub message_type: u8,
+    pub length: U16,
+}
+
+/// A protocol message that can be sent through a [FramedStream]
+pub trait Message: Sized {
+    const TYPE: MessageType;
+
+    fn encode(&self) -> Vec<u8>;
+    fn decode(payload: &[u8]) -> Result<Self>;
+}
+
+/// A message whose wire format is just its memory layout
+pub trait FixedSizeMessage: FromBytes + IntoBytes + Immutable + Sized {
+    const TYPE: MessageType;
+}
+
+impl<M: FixedSizeMessage> Message for M {
+    const TYPE: MessageType = <M as FixedSizeMessage>::TYPE;
+
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self> {
+        Self::read_from_bytes(payload).map_err(|_| {
+            anyhow!(
+                "{:?} message has invalid length {}; expected {}",
+                Self::TYPE,
+                payload.len(),
+                size_of::<Self>()
+            )
+        })
+    }
+}
+
+/// Fixed-size part at the start of an encoded [ClientHello]
+#[repr(C, packed)]
+#[derive(Debug, FromBytes, IntoBytes, Immutable, Clone, Copy)]
+struct ClientHelloHeader {
+    min_version: ProtocolVersion,
+    max_version: ProtocolVersion,
+    features: U32,
+}
+
+/// First message of the handshake
+///
+/// Wire format: `min_version || max_version || features || [peer_id]`
+#[derive(Debug, Clone, Copy)]
+pub struct ClientHello {
+    pub min_version: ProtocolVersion,
+    pub max_version: ProtocolVersion,
+    /// Optional protocol features the client is willing to use
+    pub features: ProtocolFeatures,
+    /// WireGuard public key of the client; lets a server shared by several peers pick the
+    /// peer. Absent in hellos of older clients. Authenticated by the [ClientAuth] message.
+    pub peer_id: Option<PeerId>,
+}
+
+impl ClientHello {
+    pub fn new(features: ProtocolFeatures, peer_id: PeerId) -> Self {
+        Self {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: MAX_PROTOCOL_VERSION,
+            features,
+            peer_id: Some(peer_id),
+        }
+    }
+
+    pub fn features(&self) -> ProtocolFeatures {
+        self.features
+    }
+
+    /// Choose the highest protocol version supported by both peers
+    pub fn negotiate_version(&self) -> Result<ProtocolVersion> {
+        let Self {
+            min_version,
+            max_version,
+            ..
+        } = *self;
+        let version = max_version.min(MAX_PROTOCOL_VERSION);
+        ensure!(
+            version >= min_version && version >= MIN_PROTOCOL_VERSION,
+            "No common protocol version: Peer supports versions {min_version}..={max_version}, \
+            we support versions {MIN_PROTOCOL_VERSION}..={MAX_PROTOCOL_VERSION}"
+        );
+        Ok(version)
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug, FromBytes, IntoBytes, Immutable, Clone, Copy)]
+pub struct ServerHello {
+    pub version: ProtocolVersion,
+    /// Optional protocol features used in this session; a subset of those offered by the client
+    pub features: U32,
+    /// Random challenge the client must answer with a [ClientAuth] message
+    pub challenge: Nonce,
+}
+
+impl ServerHello {
+    pub fn new(version: ProtocolVersion, features: ProtocolFeatures) -> Self {
+        Self {
+            version,
+            features: features.bits().into(),
+            challenge: rand::rng().random(),
+        }
+    }
+
+    pub fn features(&self) -> ProtocolFeatures {
+        ProtocolFeatures::from_bits(self.features.get())
+    }
+
+    /// Check that the version and features chosen by the server are ones we offered
+    pub fn validate(&self, client_hello: &ClientHello) -> Result<ProtocolVersion> {
+        let version = self.version;
+        ensure!(
+            (MIN_PROTOCOL_VERSION..=MAX_PROTOCOL_VERSION).contains(&version),
+            "Peer chose protocol version {version}, which we do not support. \
+            We support versions {MIN_PROTOCOL_VERSION}..={MAX_PROTOCOL_VERSION}"
+        );
+        ensure!(
+            client_hello.features().contains(self.features()),
+            "Peer chose protocol features {:?}, but we only offered {:?}",
+            self.features(),
+            client_hello.features()
+        );
+        Ok(version)
+    }
+}
+
+impl Message for ClientHello {
+    const TYPE: MessageType = MessageType::ClientHello;
+
+    fn encode(&self) -> Vec<u8> {
+        let header = ClientHelloHeader {
+            min_version: self.min_version,
+            max_version: self.max_version,
+            features: self.features.bits().into(),
+        };
+        let mut buf = header.as_bytes().to_vec();
+        if let Some(peer_id) = &self.peer_id {
+            buf.extend_from_slice(peer_id);
+        }
+        buf
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self> {
+        let (header, rest) = ClientHelloHeader::read_from_prefix(payload)
+            .map_err(|_| anyhow!("Client hello of {} bytes is too short", payload.len()))?;
+        let peer_id = match rest.len() {
+            0 => None,
+            PeerId::LEN => Some(rest.try_into()?),
+            len => bail!("Client hello contains a peer ID of invalid length {len}"),
+        };
+        Ok(Self {
+            min_version: header.min_version,
+            max_version: header.max_version,
+            features: ProtocolFeatures::from_bits(header.features.get()),
+            peer_id,
+        })
+    }
+}
+
+impl FixedSizeMessage for ServerHello {
+    const TYPE: MessageType = MessageType::ServerHello;
+}
+
+impl FixedSizeMessage for ClientAuth {
+    const TYPE: MessageType = MessageType::ClientAuth;
+}
+
+impl Message for RekeyReq {
+    const TYPE: MessageType = MessageType::RekeyReq;
+
+    fn encode(&self) -> Vec<u8> {
+        self.encode()
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self> {
+        Self::decode(payload)
+    }
+}
+
+impl FixedSizeMessage for RekeyAck {
+    const TYPE: MessageType = MessageType::RekeyAck;
+}
+
+/// Wrapper around a byte stream sending and receiving framed [Message]s
+#[derive(Debug)]
+pub struct FramedStream<Stream>
+where
+    Stream: AsyncRead + AsyncWrite + Unpin,
+{
+    stream: Stream,
+    /// The protocol version, once it has been negotiated
+    version: Option<ProtocolVersion>,
+}
+
+impl<Stream> FramedStream<Stream>
+where
+    Stream: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: Stream) -> Self {
+        Self {
+            stream,
+            version: None,
+        }
+    }
+
+    /// The underlying stream; nothing is buffered, so no data is lost
+    pub fn into_inner(self) -> Stream {
+        self.stream
+    }
+
+    /// Set the negotiated protocol version; from then on, every frame must carry this version
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = Some(version);
+    }
+
+    pub async fn send<M: Message>(&mut self, msg: &M) -> Result<()> {
+        self.send_frame(M::TYPE, &msg.encode())
+            .await
+            .with_context(|| format!("Failed to send {:?} message", M::TYPE))
+    }
+
+    pub async fn recv<M: Message>(&mut self) -> Result<M> {
+        let (message_type, payload) = self.recv_frame().await?;
+        ensure!(
+            message_type == M::TYPE,
+            "Expected {:?} message, but received {message_type:?} message",
+            M::TYPE
+        );
+        M::decode(&payload)
+    }
+
+    /// Tell the peer why we are closing the connection
+    pub async fn abort(&mut self, reason: &str) -> Result<()> {
+        let reason = &reason.as_bytes()[..reason.len().min(u16::MAX as usize)];
+        self.send_frame(MessageType::Abort, reason)
+            .await
+            .context("Failed to send abort message")
+    }
+
+    async fn send_frame(&mut self, message_type: MessageType, payload: &[u8]) -> Result<()> {
+        let length: u16 = payload
+            .len()
+            .try_into()
+            .with_context(|| format!("Message payload of {} bytes is too long", payload.len()))?;
+        let header = FrameHeader {
+            magic: FRAME_MAGIC,
+            version: self.version.unwrap_or(MAX_PROTOCOL_VERSION),
+            message_type: message_type as u8,
+            length: length.into(),
+        };
+        self.stream.write_all(header.as_bytes()).await?;
+        self.stream.write_all(payload).await?;
+        Ok(())
+    }
+
+    async fn recv_frame(&mut self) -> Result<(MessageType, Vec<u8>)> {
+        let mut header = FrameHeader::new_zeroed();
+        self.stream
+            .read_exact(header.as_mut_bytes())
+            .await
+            .context("Failed to read frame header")?;
+
+        ensure!(
+            header.magic == FRAME_MAGIC,
+            "Received a message without the daisyway frame magic. \
+            The peer is probably running an older, incompatible version of daisyway."
+        );
+        if let Some(version) = self.version {
+            let received = header.version;
+            ensure!(
+                received == version,
+                "Received a message for protocol version {received}, \
+                but version {version} was negotiated"
+            );
+        }
+        let message_type = MessageType::try_from(header.message_type)?;
+
+        let mut payload = vec![0u8; header.length.get() as usize];
+        self.stream
+            .read_exact(&mut payload)
+            .await
+            .with_context(|| format!("Failed to read {message_type:?} message payload"))?;
+
+        if message_type == MessageType::Abort {
+            bail!(
+                "Peer aborted the connection: {}",
+                String::from_utf8_lossy(&payload)
+            );
+        }
+
+        Ok((message_type, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    fn stream_pair() -> (FramedStream<DuplexStream>, FramedStream<DuplexStream>) {
+        let (a, b) = duplex(4096);
+        (FramedStream::new(a), FramedStream::new(b))
+    }
+
+    #[test]
+    fn client_hello_round_trip() {
+        let hello = ClientHello::new(ProtocolFeatures::RATCHET, [4u8; 32]);
+        let decoded = ClientHello::decode(&hello.encode()).unwrap();
+        assert_eq!(decoded.min_version, MIN_PROTOCOL_VERSION);
+        assert_eq!(decoded.max_version, MAX_PROTOCOL_VERSION);
+        assert_eq!(decoded.features, ProtocolFeatures::RATCHET);
+        assert_eq!(decoded.peer_id, Some([4u8; 32]));
+    }
+
+    #[test]
+    fn client_hello_without_peer_id() {
+        let decoded = ClientHello::decode(&[1, 1, 0, 0, 0, 0]).unwrap();
+        assert_eq!(decoded.peer_id, None);
+        assert_eq!(decoded.features, ProtocolFeatures::NONE);
+    }
+
+    #[test]
+    fn client_hello_rejects_invalid_length() {
+        assert!(ClientHello::decode(&[1, 1, 0]).is_err());
+        assert!(ClientHello::decode(&[1, 1, 0, 0, 0, 0, 4]).is_err());
+    }
+
+    #[test]
+    fn version_negotiation() {
+        let mut hello = ClientHello::new(ProtocolFeatures::NONE, [4u8; 32]);
+        hello.max_version = MAX_PROTOCOL_VERSION + 1;
+        assert_eq!(hello.negotiate_version().unwrap(), MAX_PROTOCOL_VERSION);
+
+        hello.min_version = MAX_PROTOCOL_VERSION + 1;
+        assert!(hello.negotiate_version().is_err());
+    }
+
+    #[test]
+    fn server_hello_validation() {
+        let hello = ClientHello::new(ProtocolFeatures::RATCHET, [4u8; 32]);
+        let server_hello = ServerHello::new(MAX_PROTOCOL_VERSION, ProtocolFeatures::RATCHET);
+        assert_eq!(server_hello.validate(&hello).unwrap(), MAX_PROTOCOL_VERSION);
+
+        let server_hello = ServerHello::new(MAX_PROTOCOL_VERSION + 1, ProtocolFeatures::NONE);
+        assert!(server_hello.validate(&hello).is_err());
+
+        let server_hello = ServerHello::new(MAX_PROTOCOL_VERSION, ProtocolFeatures::PQ_KEM);
+        assert!(server_hello.validate(&hello).is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let (mut a, mut b) = stream_pair();
+        let hello = ClientHello::new(ProtocolFeatures::NONE, [4u8; 32]);
+        a.send(&hello).await.unwrap();
+        let received: ClientHello = b.recv().await.unwrap();
+        assert_eq!(received.peer_id, hello.peer_id);
+
+        let server_hello = ServerHello::new(MAX_PROTOCOL_VERSION, ProtocolFeatures::NONE);
+        b.send(&server_hello).await.unwrap();
+        let received: ServerHello = a.recv().await.unwrap();
+        assert_eq!(received.challenge, server_hello.challenge);
+    }
+
+    #[tokio::test]
+    async fn rejects_unexpected_message_type() {
+        let (mut a, mut b) = stream_pair();
+        a.send(&ClientHello::new(ProtocolFeatures::NONE, [4u8; 32]))
+            .await
+            .unwrap();
+        let err = b.recv::<ServerHello>().await.unwrap_err();
+        assert!(err.to_string().contains("Expected ServerHello message"));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_magic() {
+        let (mut a, b) = duplex(4096);
+        let mut b = FramedStream::new(b);
+        a.write_all(b"XXXX\x01\x01\x00\x00").await.unwrap();
+        let err = b.recv::<ClientHello>().await.unwrap_err();
+        assert!(err.to_string().contains("frame magic"));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_message_type() {
+        let (mut a, b) = duplex(4096);
+        let mut b = FramedStream::new(b);
+        a.write_all(b"DSYW\x01\x63\x00\x00").await.unwrap();
+        let err = b.recv::<ClientHello>().await.unwrap_err();
+        assert!(err.to_string().contains("unknown type"));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_version_once_negotiated() {
+        let (mut a, mut b) = stream_pair();
+        a.set_version(MAX_PROTOCOL_VERSION + 1);
+        b.set_version(MAX_PROTOCOL_VERSION);
+        a.send(&ClientHello::new(ProtocolFeatures::NONE, [4u8; 32]))
+            .await
+            .unwrap();
+        let err = b.recv::<ClientHello>().await.unwrap_err();
+        assert!(err.to_string().contains("was negotiated"));
+    }
+
+    #[tokio::test]
+    async fn abort_carries_reason() {
+        let (mut a, mut b) = stream_pair();
+        a.abort("Unknown peer").await.unwrap();
+        let err = b.recv::<ServerHello>().await.unwrap_err();
+        assert_eq!(err.to_string(), "Peer aborted the connection: Unknown peer");
+    }
+}
@@ -0,0 +1,164 @@
+//! ML-KEM-768 key encapsulation mechanism as specified in FIPS 203
+//!
+//! The mechanism itself is provided by RustCrypto's `ml-kem` crate; this module only adapts it to
+//! the fixed-size byte arrays used on the wire and in key files. Secret keys are stored in their
+//! 64-byte seed form `d || z` and expanded when loaded.
+
+use anyhow::{anyhow, ensure, Result};
+use ml_kem::{
+    kem::{Decapsulate, DecapsulationKey as ExpandedDecapsulationKey},
+    EncapsulateDeterministic, EncodedSizeUser, KemCore, MlKem768, MlKem768Params,
+};
+use rand::Rng;
+use zeroize::Zeroizing;
+
+type ExpandedEncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+pub const SEED_LEN: usize = 64;
+pub const ENCAPSULATION_KEY_LEN: usize = 1184;
+pub const CIPHERTEXT_LEN: usize = 1088;
+pub const SHARED_SECRET_LEN: usize = 32;
+
+pub type Seed = [u8; SEED_LEN];
+pub type EncapsulationKey = [u8; ENCAPSULATION_KEY_LEN];
+pub type Ciphertext = [u8; CIPHERTEXT_LEN];
+pub type SharedSecret = [u8; SHARED_SECRET_LEN];
+
+/// Expanded decapsulation key; its secret parts are zeroized when it is dropped
+#[derive(Clone, PartialEq)]
+pub struct DecapsulationKey(ExpandedDecapsulationKey<MlKem768Params>);
+
+impl Eq for DecapsulationKey {}
+
+impl std::fmt::Debug for DecapsulationKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DecapsulationKey(...)")
+    }
+}
+
+/// Expand a key pair from its seed `d || z`; FIPS 203, Algorithm 16
+pub fn keypair_from_seed(seed: &Seed) -> (DecapsulationKey, EncapsulationKey) {
+    let (d, z) = seed.split_at(32);
+    let d: Zeroizing<[u8; 32]> = Zeroizing::new(d.try_into().unwrap());
+    let z: Zeroizing<[u8; 32]> = Zeroizing::new(z.try_into().unwrap());
+    let (dk, ek) = MlKem768::generate_deterministic((&*d).into(), (&*z).into());
+    (DecapsulationKey(dk), ek.as_bytes().into())
+}
+
+pub fn generate_seed() -> Seed {
+    let mut seed = [0u8; SEED_LEN];
+    rand::rng().fill(&mut seed[..]);
+    seed
+}
+
+/// Check that the encapsulation key is properly encoded; FIPS 203, Section 7.2
+///
+/// Decoding reduces every coefficient modulo q, so a key with unreduced coefficients does not
+/// survive being encoded again.
+pub fn validate_encapsulation_key(ek: &EncapsulationKey) -> Result<()> {
+    let reencoded: EncapsulationKey = parse_encapsulation_key(ek).as_bytes().into();
+    ensure!(
+        reencoded == *ek,
+        "ML-KEM encapsulation key contains coefficients that are not reduced modulo q"
+    );
+    Ok(())
+}
+
+fn parse_encapsulation_key(ek: &EncapsulationKey) -> ExpandedEncapsulationKey {
+    ExpandedEncapsulationKey::from_bytes(ek.into())
+}
+
+/// Generate a fresh shared secret and encapsulate it to the given key
+pub fn encapsulate(ek: &EncapsulationKey) -> Result<(Ciphertext, SharedSecret)> {
+    validate_encapsulation_key(ek)?;
+    let m: Zeroizing<[u8; 32]> = Zeroizing::new(rand::rng().random());
+    let (ciphertext, shared_secret) = parse_encapsulation_key(ek)
+        .encapsulate_deterministic((&*m).into())
+        .map_err(|_| anyhow!("ML-KEM encapsulation failed"))?;
+    Ok((ciphertext.into(), shared_secret.into()))
+}
+
+/// Recover the shared secret from a ciphertext; FIPS 203, Algorithm 18
+///
+/// Invalid ciphertexts yield a pseudorandom shared secret (implicit rejection).
+pub fn decapsulate(dk: &DecapsulationKey, c: &Ciphertext) -> SharedSecret {
+    // Decapsulation in the ml-kem crate never fails
+    let shared_secret = dk.0.decapsulate(c.into()).unwrap();
+    shared_secret.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let (dk, ek) = keypair_from_seed(&generate_seed());
+        let (ciphertext, shared_secret) = encapsulate(&ek).unwrap();
+        assert_eq!(decapsulate(&dk, &ciphertext), shared_secret);
+    }
+
+    #[test]
+    fn keypair_is_derived_from_seed() {
+        let seed = generate_seed();
+        assert_eq!(keypair_from_seed(&seed), keypair_from_seed(&seed));
+        assert_ne!(
+            keypair_from_seed(&seed).1,
+            keypair_from_seed(&generate_seed()).1
+        );
+    }
+
+    /// Pins the key expansion, so key files written by earlier versions keep working
+    #[test]
+    fn known_answer() {
+        use sha3::{Digest, Sha3_256};
+
+        let seed: Seed = std::array::from_fn(|i| i as u8);
+        let (dk, ek) = keypair_from_seed(&seed);
+        assert_eq!(
+            format!("{:x}", Sha3_256::digest(ek)),
+            "a24e16d8f8f9383a95b77050f4d9fd2f5733eec1d63ef3c23ebf9918173669a7"
+        );
+
+        let ciphertext: Ciphertext = std::array::from_fn(|i| i as u8);
+        assert_eq!(
+            decapsulate(&dk, &ciphertext),
+            [
+                0x2d, 0xce, 0xc6, 0xea, 0xcd, 0x41, 0xf4, 0x1e, 0x98, 0xbb, 0xf1, 0xe6, 0xc4, 0xb6,
+                0xd1, 0xcf, 0xa7, 0x6d, 0x83, 0x07, 0x9f, 0xa7, 0x8d, 0xec, 0xb4, 0x9d, 0x77, 0xf6,
+                0xff, 0x9b, 0x79, 0x6d,
+            ]
+        );
+    }
+
+    #[test]
+    fn tampered_ciphertext_yields_other_secret() {
+        let (dk, ek) = keypair_from_seed(&generate_seed());
+        let (mut ciphertext, shared_secret) = encapsulate(&ek).unwrap();
+        ciphertext[0] ^= 1;
+        let rejected = decapsulate(&dk, &ciphertext);
+        assert_ne!(rejected, shared_secret);
+        // Implicit rejection is deterministic and does not reveal that the ciphertext was
+        // rejected
+        assert_eq!(decapsulate(&dk, &ciphertext), rejected);
+    }
+
+    #[test]
+    fn other_key_yields_other_secret() {
+        let (_, ek) = keypair_from_seed(&generate_seed());
+        let (other_dk, _) = keypair_from_seed(&generate_seed());
+        let (ciphertext, shared_secret) = encapsulate(&ek).unwrap();
+        assert_ne!(decapsulate(&other_dk, &ciphertext), shared_secret);
+    }
+
+    #[test]
+    fn unreduced_encapsulation_key_is_rejected() {
+        let (_, mut ek) = keypair_from_seed(&generate_seed());
+        validate_encapsulation_key(&ek).unwrap();
+        // The first coefficient is encoded in the low 12 bits of the first 3 bytes
+        ek[0] = 0xff;
+        ek[1] |= 0x0f;
+        assert!(validate_encapsulation_key(&ek).is_err());
+        assert!(encapsulate(&ek).is_err());
+    }
+}
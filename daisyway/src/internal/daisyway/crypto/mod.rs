@@ -1,9 +1,12 @@
 pub mod hash_domain;
+pub mod ml_kem;
 
 mod basics;
 mod client;
+mod framing;
 mod server;
 
 pub use basics::*;
 pub use client::*;
+pub use framing::*;
 pub use server::*;
@@ -1,77 +1,255 @@
 use std::{sync::Arc, time::Duration};
 
-use anyhow::{anyhow, Context, Result};
-use log::debug;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use zerocopy::{FromZeros, IntoBytes};
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
 
-use super::{derive_daisyway_key, DaisywayProtocolParameters, Key, RekeyReq};
-use crate::internal::{daisyway::crypto::RekeyAck, etsi014::Etsi014Connection, osk::OskHandler};
+use super::{
+    derive_daisyway_key,
+    ml_kem::{Ciphertext, SharedSecret},
+    ClientAuth, ClientHello, DaisywayProtocolParameters, FramedStream, Key, KeyRatchet,
+    ProtocolFeatures, RekeyReq, ServerHello, SessionCounter, SessionTranscript,
+};
+use crate::internal::{
+    daisyway::{crypto::RekeyAck, state::StateStore},
+    etsi014::is_transient_error,
+    key_source::{KeySourceSet, QkdKey, QkdKeySource},
+    osk::OskHandler,
+    util::key_to_base64,
+};
 
-pub struct DaisywayServerProtocol<O, Stream>
+/// Time the peer has to complete the handshake; keeps unauthenticated connections short-lived
+///
+/// Applies to both sides, so a client does not hang on a server that never answers.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time to wait before retrying a rekey after a KME temporarily failed to deliver keys
+const KEY_FETCH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct DaisywayServerProtocol<O, Stream, K>
 where
     O: OskHandler,
     Stream: AsyncRead + AsyncWrite + Unpin,
+    K: QkdKeySource,
 {
     pub protocol_params: DaisywayProtocolParameters,
-    pub stream: Stream,
-    pub etsi_client: Arc<Etsi014Connection>,
+    pub stream: FramedStream<Stream>,
+    pub key_sources: Arc<KeySourceSet<K>>,
     pub osk_handler: O,
+    pub state: Arc<StateStore>,
     pub rekey_interval: u64,
+    /// Features negotiated for this session
+    pub features: ProtocolFeatures,
+    /// Present if the ratchet was negotiated for this session
+    pub ratchet: Option<KeyRatchet>,
+    /// Client hello received before the protocol handler was set up
+    pub client_hello: Option<ClientHello>,
+    /// Counter of the last rekey request sent in this session
+    pub counter: SessionCounter,
+    /// QKD keys fetched for a rekey whose remaining keys could not be fetched yet
+    pub pending_keys: Vec<QkdKey>,
 }
 
-impl<O, Stream> DaisywayServerProtocol<O, Stream>
+impl<O, Stream, K> DaisywayServerProtocol<O, Stream, K>
 where
     O: OskHandler,
     Stream: AsyncRead + AsyncWrite + Unpin,
+    K: QkdKeySource,
 {
     pub fn new(
         protocol_params: DaisywayProtocolParameters,
         stream: Stream,
-        etsi_client: Arc<Etsi014Connection>,
+        key_sources: Arc<KeySourceSet<K>>,
         osk_handler: O,
+        state: Arc<StateStore>,
         rekey_interval: u64,
     ) -> Self {
         Self {
             protocol_params,
-            stream,
-            etsi_client,
+            stream: FramedStream::new(stream),
+            key_sources,
             osk_handler,
+            state,
             rekey_interval,
+            features: ProtocolFeatures::NONE,
+            ratchet: None,
+            client_hello: None,
+            counter: SessionCounter::default(),
+            pending_keys: Vec::new(),
         }
     }
 
+    /// Continue a handshake whose client hello has already been received
+    pub fn with_client_hello(mut self, client_hello: ClientHello) -> Self {
+        self.client_hello = Some(client_hello);
+        self
+    }
+
     pub async fn event_loop(&mut self) -> Result<()> {
+        let transcript = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.handshake())
+            .await
+            .context("Peer did not complete the handshake in time")??;
         loop {
-            let key = self.negotiate_key().await?;
+            let key = match self.negotiate_key(&transcript).await {
+                Ok(key) => key,
+                // Keys are fetched before anything is sent, so the session can be kept
+                Err(err) if is_transient_error(&err) => {
+                    warn!(
+                        "[CLIENT] Rekey failed, retrying in {}s: {err:#}",
+                        KEY_FETCH_RETRY_INTERVAL.as_secs()
+                    );
+                    tokio::time::sleep(KEY_FETCH_RETRY_INTERVAL).await;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
             self.osk_handler.set_fresh_osk(key).await?;
             tokio::time::sleep(Duration::from_secs(self.rekey_interval)).await;
         }
     }
 
-    async fn negotiate_key(&mut self) -> Result<Key> {
-        let key = self
-            .etsi_client
-            .fetch_any_key()
+    /// Negotiate the protocol version and authenticate the peer
+    ///
+    /// No QKD key material is requested before this has succeeded.
+    async fn handshake(&mut self) -> Result<SessionTranscript> {
+        let client_hello = match self.client_hello.take() {
+            Some(client_hello) => client_hello,
+            None => self
+                .stream
+                .recv()
+                .await
+                .context("Failed to receive client hello")?,
+        };
+        if let Some(peer_id) = client_hello.peer_id {
+            if peer_id != self.protocol_params.remote_peer_id {
+                self.stream.abort("Unknown peer").await?;
+                bail!(
+                    "Peer identified itself as WireGuard peer {}, which is not configured",
+                    key_to_base64(&peer_id)
+                );
+            }
+        }
+
+        let version = match client_hello.negotiate_version() {
+            Ok(version) => version,
+            Err(err) => {
+                self.stream.abort(&err.to_string()).await?;
+                return Err(err);
+            }
+        };
+
+        let features = client_hello
+            .features()
+            .intersection(self.protocol_params.features);
+        if let Err(err) = self
+            .protocol_params
+            .features
+            .ensure_mandatory_negotiated(features)
+        {
+            self.stream.abort(&err.to_string()).await?;
+            return Err(err);
+        }
+        self.features = features;
+        self.counter = SessionCounter::default();
+        let server_hello = ServerHello::new(version, features);
+        self.stream.send(&server_hello).await?;
+        self.stream.set_version(version);
+        info!("[CLIENT] Negotiated protocol version {version} with features {features:?}");
+
+        let auth: ClientAuth = self
+            .stream
+            .recv()
             .await
-            .context("Failed to fetch a QKD key.")?;
-        debug!("[CLIENT] Sending QKD ID: {:?}", key.id);
+            .context("Failed to receive answer to authentication challenge")?;
+        if let Err(err) = auth.validate(&self.protocol_params, &client_hello, &server_hello) {
+            self.stream.abort("Authentication failed").await?;
+            return Err(err);
+        }
+        info!("[CLIENT] Peer authenticated successfully");
+
+        if features.contains(ProtocolFeatures::RATCHET) {
+            self.ratchet = Some(KeyRatchet::new(
+                &self.protocol_params,
+                &client_hello,
+                &server_hello,
+            ));
+        } else if self
+            .protocol_params
+            .features
+            .contains(ProtocolFeatures::RATCHET)
+        {
+            warn!("[CLIENT] Peer does not support the key ratchet; continuing without it");
+        }
 
-        let rekey_req = RekeyReq::new(key.id.as_bytes().to_owned());
+        Ok(SessionTranscript::new(&client_hello, &server_hello))
+    }
+
+    async fn negotiate_key(&mut self, transcript: &SessionTranscript) -> Result<Key> {
+        self.key_sources
+            .fetch_any_keys(&mut self.pending_keys)
+            .await
+            .context("Failed to fetch QKD keys.")?;
+        let keys = std::mem::take(&mut self.pending_keys);
+        let key_ids: Vec<Uuid> = keys.iter().map(|key| key.id).collect();
+        debug!("[CLIENT] Sending QKD IDs: {key_ids:?}");
+        for key_id in &key_ids {
+            self.state.ensure_key_unused(key_id)?;
+        }
+
+        let (kem_ciphertext, kem_shared_secret) = self.encapsulate()?;
+
+        let rekey_req = RekeyReq::new(
+            &self.protocol_params,
+            transcript,
+            self.counter.advance(),
+            key_ids.iter().map(|id| id.into_bytes()).collect(),
+            kem_ciphertext,
+        )?;
         let nonce = rekey_req.nonce;
         self.stream
-            .write_all(rekey_req.as_bytes())
+            .send(&rekey_req)
             .await
             .context("Could not send QKD key and nonce to server")?;
 
-        let mut ack = RekeyAck::new_zeroed();
+        let osk = derive_daisyway_key(&self.protocol_params, nonce, &keys, kem_shared_secret);
+        let (ratchet, osk) = match &self.ratchet {
+            Some(ratchet) => {
+                let (ratchet, osk) = ratchet.advance(&osk);
+                (Some(ratchet), osk)
+            }
+            None => (None, osk),
+        };
+
         self.stream
-            .read_exact(ack.as_mut_bytes())
+            .recv::<RekeyAck>()
             .await
-            .map_err(|e| anyhow!(e))
-            .and_then(|_| ack.validate())
+            .and_then(|ack| ack.validate(&self.protocol_params, &rekey_req, &osk))
             .context("Failed to receive rekey acknoledgement message")?;
+        self.ratchet = ratchet;
+
+        // The peer has already installed the key, so a failure to persist must not end the
+        // session; the key IDs are still remembered in memory
+        let epoch = self.state.record_rekey(&key_ids);
+        if let Err(err) = self.state.persist().await {
+            error!("[CLIENT] {err:?}");
+        }
+        info!("[CLIENT] Completed rekey epoch {epoch}");
 
-        Ok(derive_daisyway_key(&self.protocol_params, nonce, key))
+        Ok(osk)
+    }
+
+    /// Encapsulate a fresh KEM shared secret to the peer, if the KEM was negotiated
+    fn encapsulate(&self) -> Result<(Option<Box<Ciphertext>>, Option<SharedSecret>)> {
+        if !self.features.contains(ProtocolFeatures::PQ_KEM) {
+            return Ok((None, None));
+        }
+        let kem = self
+            .protocol_params
+            .kem
+            .as_ref()
+            .context("KEM negotiated without KEM keys. This is a bug.")?;
+        let (ciphertext, shared_secret) = kem.encapsulate()?;
+        Ok((Some(Box::new(ciphertext)), Some(shared_secret)))
     }
 }
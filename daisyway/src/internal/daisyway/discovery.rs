@@ -0,0 +1,388 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+
+use super::{
+    net::DaisywayServerPeer,
+    setup::{group_participants, setup_peer, PeerEntry, PeerSetupContext},
+};
+use crate::internal::{
+    daisyway::crypto::Key,
+    key_source::AnyKeySource,
+    osk::{OskDeadman, UserspaceWireGuardOskHandler},
+    util::key_to_base64,
+};
+
+/// Runs the configured peers only while they exist on the WireGuard interface
+///
+/// The interface is scanned periodically. A peer that appears on the interface is set up and
+/// started, and a peer that disappears is stopped, which erases its PSK. A peer that can not be
+/// set up is retried on the next scan. Peers sharing a `listen` address share a server, which
+/// is restarted whenever one of its peers comes or goes; the other peers keep their PSK and
+/// their clients simply reconnect.
+pub struct PeerDiscovery {
+    ctx: PeerSetupContext,
+    entries: Vec<PeerEntry>,
+    interval: Duration,
+}
+
+impl PeerDiscovery {
+    pub(super) fn new(ctx: PeerSetupContext, entries: Vec<PeerEntry>, interval: Duration) -> Self {
+        Self {
+            ctx,
+            entries,
+            interval,
+        }
+    }
+
+    pub async fn event_loop(self) -> Result<()> {
+        let interface = self
+            .ctx
+            .interface
+            .as_deref()
+            .context("Discovering peers requires a WireGuard interface")?;
+        info!(
+            "Discovering peers on WireGuard interface {interface} every {}s",
+            self.interval.as_secs()
+        );
+
+        let mut discovered: DiscoveredPeers<DaisywayServerPeer<OskDeadman, AnyKeySource>> =
+            DiscoveredPeers::default();
+        let mut unknown_peers = HashSet::new();
+
+        let mut ticker = tokio::time::interval(self.interval);
+        loop {
+            ticker.tick().await;
+            let present = match self.interface_peers(interface).await {
+                Ok(present) => present,
+                Err(err) => {
+                    warn!("{err:?}");
+                    continue;
+                }
+            };
+
+            for peer in &present {
+                let known = self.entries.iter().any(|e| &e.remote_peer_id == peer);
+                if !known && unknown_peers.insert(peer.clone()) {
+                    info!("Ignoring WireGuard peer {peer} on interface {interface}, since it has no [[peers]] entry");
+                }
+            }
+
+            let ids: Vec<_> = self
+                .entries
+                .iter()
+                .map(|e| e.remote_peer_id.as_str())
+                .collect();
+            let changed = discovered
+                .update(interface, &ids, &present, |idx| {
+                    setup_peer(&self.ctx, &self.entries[idx])
+                })
+                .await;
+            if !changed {
+                continue;
+            }
+
+            // Keep the OSK handlers of the remaining peers, so their PSK is not erased
+            let peers = self
+                .entries
+                .iter()
+                .filter_map(|entry| {
+                    let server_peer = discovered.active.get(&entry.remote_peer_id)?;
+                    Some((entry, server_peer.clone()))
+                })
+                .collect();
+            let participants = group_participants(peers)
+                .into_iter()
+                .map(|participant| (participant.name.clone(), participant.event_loop()))
+                .collect();
+            discovered.run(participants).await;
+        }
+    }
+
+    /// The WireGuard public keys of the peers on the interface
+    async fn interface_peers(&self, interface: &str) -> Result<HashSet<String>> {
+        let peers = match &self.ctx.uapi_socket {
+            Some(socket_path) => UserspaceWireGuardOskHandler::peers(socket_path).await?,
+            None => self.kernel_interface_peers(interface)?,
+        };
+        Ok(peers.iter().map(key_to_base64).collect())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn kernel_interface_peers(&self, interface: &str) -> Result<Vec<Key>> {
+        use wireguard_uapi::DeviceInterface;
+
+        let socket = self
+            .ctx
+            .wg_socket
+            .as_ref()
+            .context("WireGuard control socket is not connected")?;
+        let device = socket
+            .lock()
+            .unwrap()
+            .get_device(DeviceInterface::from_name(interface.to_string()))
+            .with_context(|| format!("Failed to access WireGuard interface {interface}"))?;
+        Ok(device.peers.iter().map(|peer| peer.public_key).collect())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn kernel_interface_peers(&self, _interface: &str) -> Result<Vec<Key>> {
+        anyhow::bail!("Discovering peers is only supported on Linux.")
+    }
+}
+
+/// The peers found on the interface and the tasks running their participants
+struct DiscoveredPeers<P> {
+    /// Peers that were set up, by WireGuard public key
+    active: HashMap<String, P>,
+    /// Running participants by name; the name lists the peers of the participant
+    tasks: HashMap<String, JoinHandle<()>>,
+}
+
+impl<P> Default for DiscoveredPeers<P> {
+    fn default() -> Self {
+        Self {
+            active: HashMap::new(),
+            tasks: HashMap::new(),
+        }
+    }
+}
+
+impl<P> DiscoveredPeers<P> {
+    /// Drop the peers that left the interface and set up the configured peers that appeared
+    ///
+    /// `ids` lists the configured peers; `setup` is called with the index of a peer in `ids`.
+    /// Returns whether the active peers changed.
+    async fn update<F, Fut>(
+        &mut self,
+        interface: &str,
+        ids: &[&str],
+        present: &HashSet<String>,
+        mut setup: F,
+    ) -> bool
+    where
+        F: FnMut(usize) -> Fut,
+        Fut: Future<Output = Result<P>>,
+    {
+        let mut changed = false;
+        self.active.retain(|remote_peer_id, _| {
+            let keep = present.contains(remote_peer_id);
+            if !keep {
+                info!("WireGuard peer {remote_peer_id} was removed from interface {interface}; stopping it");
+                changed = true;
+            }
+            keep
+        });
+        for (idx, remote_peer_id) in ids.iter().enumerate() {
+            if !present.contains(*remote_peer_id) || self.active.contains_key(*remote_peer_id) {
+                continue;
+            }
+            info!(
+                "WireGuard peer {remote_peer_id} was found on interface {interface}; starting it"
+            );
+            let res = setup(idx)
+                .await
+                .with_context(|| format!("Could not set up peer {remote_peer_id}"));
+            match res {
+                Ok(peer) => {
+                    self.active.insert(remote_peer_id.to_string(), peer);
+                    changed = true;
+                }
+                Err(err) => error!("{err:?}"),
+            }
+        }
+        changed
+    }
+
+    /// Run exactly the given participants
+    ///
+    /// Participants that are already running under the same name are kept; the others are
+    /// stopped before their replacements start, which may listen on the same address.
+    async fn run<F>(&mut self, mut participants: Vec<(String, F)>)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let stale: Vec<_> = self
+            .tasks
+            .keys()
+            .filter(|name| !participants.iter().any(|(p, _)| p == *name))
+            .cloned()
+            .collect();
+        for name in stale {
+            if let Some(task) = self.tasks.remove(&name) {
+                task.abort();
+                let _ = task.await;
+            }
+        }
+        participants.retain(|(name, _)| !self.tasks.contains_key(name));
+        for (name, participant) in participants {
+            self.tasks.insert(name, tokio::spawn(participant));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::pending,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use anyhow::bail;
+
+    use super::*;
+
+    /// Records when participants start and stop
+    #[derive(Clone, Default)]
+    struct Events(Arc<Mutex<Vec<String>>>);
+
+    impl Events {
+        fn push(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+
+        /// Take the events recorded so far, after letting the spawned participants run
+        async fn take(&self) -> Vec<String> {
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    struct Stopped(Events, String);
+
+    impl Drop for Stopped {
+        fn drop(&mut self) {
+            self.0.push(format!("stop {}", self.1));
+        }
+    }
+
+    fn participant(events: &Events, name: &str) -> (String, impl Future<Output = ()>) {
+        let (events, task_name) = (events.clone(), name.to_owned());
+        let participant = async move {
+            let name = task_name;
+            events.push(format!("start {name}"));
+            let _stopped = Stopped(events, name);
+            pending::<()>().await
+        };
+        (name.to_owned(), participant)
+    }
+
+    fn present(peers: &[&str]) -> HashSet<String> {
+        peers.iter().map(|peer| peer.to_string()).collect()
+    }
+
+    const PEERS: [&str; 2] = ["alice", "bob"];
+
+    /// Scan the interface, setting up peers as their index and counting the setups
+    async fn scan(
+        discovered: &mut DiscoveredPeers<usize>,
+        peers: &[&str],
+        setups: &AtomicUsize,
+    ) -> bool {
+        discovered
+            .update("wg0", &PEERS, &present(peers), |idx| async move {
+                setups.fetch_add(1, Ordering::Relaxed);
+                Ok(idx)
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn appearing_peer_is_started() {
+        let (mut discovered, setups, events) = (
+            DiscoveredPeers::default(),
+            AtomicUsize::new(0),
+            Events::default(),
+        );
+
+        assert!(scan(&mut discovered, &["bob", "mallory"], &setups).await);
+        assert_eq!(discovered.active, HashMap::from([("bob".to_owned(), 1)]));
+        discovered.run(vec![participant(&events, "bob")]).await;
+        assert_eq!(events.take().await, ["start bob"]);
+    }
+
+    #[tokio::test]
+    async fn disappearing_peer_is_stopped() {
+        let (mut discovered, setups, events) = (
+            DiscoveredPeers::default(),
+            AtomicUsize::new(0),
+            Events::default(),
+        );
+        scan(&mut discovered, &["alice", "bob"], &setups).await;
+        discovered
+            .run(vec![
+                participant(&events, "alice"),
+                participant(&events, "bob"),
+            ])
+            .await;
+        events.take().await;
+
+        assert!(scan(&mut discovered, &["alice"], &setups).await);
+        assert_eq!(discovered.active, HashMap::from([("alice".to_owned(), 0)]));
+        discovered.run(vec![participant(&events, "alice")]).await;
+        assert_eq!(events.take().await, ["stop bob"]);
+        assert!(!discovered.tasks.contains_key("bob"));
+    }
+
+    #[tokio::test]
+    async fn peer_joining_a_listener_restarts_its_server() {
+        let (mut discovered, setups, events) = (
+            DiscoveredPeers::default(),
+            AtomicUsize::new(0),
+            Events::default(),
+        );
+        scan(&mut discovered, &["alice"], &setups).await;
+        discovered.run(vec![participant(&events, "alice")]).await;
+        events.take().await;
+
+        // Bob shares the listener of alice, so their server now serves both
+        assert!(scan(&mut discovered, &["alice", "bob"], &setups).await);
+        discovered
+            .run(vec![participant(&events, "alice, bob")])
+            .await;
+        assert_eq!(events.take().await, ["stop alice", "start alice, bob"]);
+        // Alice was not set up again, so her OSK handler and PSK are kept
+        assert_eq!(setups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn unchanged_peer_is_left_running() {
+        let (mut discovered, setups, events) = (
+            DiscoveredPeers::default(),
+            AtomicUsize::new(0),
+            Events::default(),
+        );
+        scan(&mut discovered, &["alice"], &setups).await;
+        discovered.run(vec![participant(&events, "alice")]).await;
+        events.take().await;
+
+        assert!(!scan(&mut discovered, &["alice"], &setups).await);
+        assert_eq!(setups.load(Ordering::Relaxed), 1);
+        // A scan that changed other peers keeps alice's participant running
+        discovered.run(vec![participant(&events, "alice")]).await;
+        assert_eq!(events.take().await, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn failed_setup_is_retried_on_the_next_scan() {
+        let mut discovered: DiscoveredPeers<usize> = DiscoveredPeers::default();
+        let changed = discovered
+            .update("wg0", &PEERS, &present(&["alice"]), |_| async {
+                bail!("WireGuard peer is not ready")
+            })
+            .await;
+        assert!(!changed);
+        assert!(scan(&mut discovered, &["alice"], &AtomicUsize::new(0)).await);
+    }
+}
@@ -1,5 +1,8 @@
 pub mod crypto;
 pub mod net;
+pub mod replay_cache;
+pub mod state;
 
+mod discovery;
 mod setup;
 pub use setup::*;
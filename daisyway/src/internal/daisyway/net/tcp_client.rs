@@ -5,39 +5,47 @@ use log::{debug, info, warn};
 use tokio::net::{TcpStream, ToSocketAddrs};
 
 use crate::internal::{
-    daisyway::crypto::{DaisywayClientProtocol, DaisywayProtocolParameters},
-    etsi014::Etsi014Connection,
+    daisyway::{
+        crypto::{DaisywayClientProtocol, DaisywayProtocolParameters},
+        state::StateStore,
+    },
+    key_source::{KeySourceSet, QkdKeySource},
     osk::OskHandler,
 };
 
 #[derive(Debug, Clone)]
-pub struct DaisywayTcpClient<O, Addr>
+pub struct DaisywayTcpClient<O, Addr, K>
 where
     O: OskHandler + Clone,
     Addr: ToSocketAddrs + std::fmt::Debug,
+    K: QkdKeySource + Send + Sync + 'static,
 {
     pub protocol_params: DaisywayProtocolParameters,
     pub endpoint: Addr,
-    pub etsi_client: Arc<Etsi014Connection>,
+    pub key_sources: Arc<KeySourceSet<K>>,
     pub osk_handler: O,
+    pub state: Arc<StateStore>,
 }
 
-impl<O, Addr> DaisywayTcpClient<O, Addr>
+impl<O, Addr, K> DaisywayTcpClient<O, Addr, K>
 where
     O: OskHandler + Clone,
     Addr: ToSocketAddrs + std::fmt::Debug,
+    K: QkdKeySource + Send + Sync + 'static,
 {
     pub fn new(
         protocol_params: DaisywayProtocolParameters,
         endpoint: Addr,
-        etsi_client: Arc<Etsi014Connection>,
+        key_sources: Arc<KeySourceSet<K>>,
         osk_handler: O,
+        state: Arc<StateStore>,
     ) -> Self {
         Self {
             protocol_params,
             endpoint,
-            etsi_client,
+            key_sources,
             osk_handler,
+            state,
         }
     }
 
@@ -65,8 +73,9 @@ where
         let mut handler = DaisywayClientProtocol::new(
             self.protocol_params.clone(),
             stream,
-            self.etsi_client.clone(),
+            self.key_sources.clone(),
             self.osk_handler.clone(),
+            self.state.clone(),
         );
         handler.event_loop().await
     }
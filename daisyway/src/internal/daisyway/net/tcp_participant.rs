@@ -1,14 +1,11 @@
-use std::sync::Arc;
-
 use serde::{Deserialize, Serialize};
 use tokio::net::ToSocketAddrs;
 
 use super::{DaisywayTcpClient, DaisywayTcpServer};
-use crate::internal::{
-    daisyway::crypto::DaisywayProtocolParameters, etsi014::Etsi014Connection, osk::OskHandler,
-};
+use crate::internal::{key_source::QkdKeySource, osk::OskHandler};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Peers configured with the same `listen` address share a single server
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum DaisywayTcpParticipantConfig {
     Client { endpoint: String },
@@ -16,52 +13,21 @@ pub enum DaisywayTcpParticipantConfig {
 }
 
 #[derive(Debug, Clone)]
-pub enum DaisywayTcpParticipant<O, Addr>
+pub enum DaisywayTcpParticipant<O, Addr, K>
 where
     O: OskHandler + Clone,
     Addr: ToSocketAddrs + std::fmt::Debug,
+    K: QkdKeySource + Send + Sync + 'static,
 {
-    Client(DaisywayTcpClient<O, Addr>),
-    Server(DaisywayTcpServer<O, Addr>),
-}
-
-impl<O> DaisywayTcpParticipant<O, String>
-where
-    O: OskHandler + Clone,
-{
-    pub fn from_config(
-        protocol_params: DaisywayProtocolParameters,
-        config: &DaisywayTcpParticipantConfig,
-        etsi_client: Arc<Etsi014Connection>,
-        osk_handler: O,
-        rekey_interval: u64,
-    ) -> Self {
-        match config {
-            DaisywayTcpParticipantConfig::Client { endpoint } => {
-                Self::Client(DaisywayTcpClient::new(
-                    protocol_params.clone(),
-                    endpoint.clone(),
-                    etsi_client,
-                    osk_handler,
-                ))
-            }
-            DaisywayTcpParticipantConfig::Server { listen } => {
-                Self::Server(DaisywayTcpServer::new(
-                    protocol_params.clone(),
-                    listen.clone(),
-                    etsi_client,
-                    osk_handler,
-                    rekey_interval,
-                ))
-            }
-        }
-    }
+    Client(DaisywayTcpClient<O, Addr, K>),
+    Server(DaisywayTcpServer<O, Addr, K>),
 }
 
-impl<O, Addr> DaisywayTcpParticipant<O, Addr>
+impl<O, Addr, K> DaisywayTcpParticipant<O, Addr, K>
 where
     O: OskHandler + Clone,
     Addr: ToSocketAddrs + std::fmt::Debug,
+    K: QkdKeySource + Send + Sync + 'static,
 {
     pub async fn event_loop(&mut self) -> anyhow::Result<()> {
         match self {
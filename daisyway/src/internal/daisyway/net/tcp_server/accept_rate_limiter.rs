@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Token bucket of a single source address
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Limits the rate at which connections from any single source address are accepted
+///
+/// Each address gets a token bucket holding up to `burst` tokens, refilled at one token
+/// per `refill_interval`. Accepting a connection costs one token. IPv6 addresses are
+/// throttled per /64 prefix, since a single host usually controls a whole /64.
+///
+/// Once `max_tracked_addresses` are tracked, the least recently used bucket is evicted.
+/// An attacker spraying source addresses can thus only reset buckets, but never lock out
+/// other addresses.
+#[derive(Debug)]
+pub struct AcceptRateLimiter {
+    burst: u32,
+    refill_interval: Duration,
+    max_tracked_addresses: usize,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl AcceptRateLimiter {
+    pub fn new(burst: u32, refill_interval: Duration, max_tracked_addresses: usize) -> Self {
+        Self {
+            burst,
+            refill_interval,
+            max_tracked_addresses,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Returns true if a connection from the given address may be accepted
+    pub fn check(&mut self, addr: IpAddr) -> bool {
+        let now = Instant::now();
+        let addr = source_prefix(addr);
+
+        if !self.buckets.contains_key(&addr) && self.buckets.len() >= self.max_tracked_addresses {
+            self.prune(now);
+        }
+        if !self.buckets.contains_key(&addr) && self.buckets.len() >= self.max_tracked_addresses {
+            self.evict_least_recently_used();
+        }
+
+        let burst = self.burst as f64;
+        let bucket = self.buckets.entry(addr).or_insert(Bucket {
+            tokens: burst,
+            last_refill: now,
+        });
+        bucket.tokens = Self::refilled_tokens(bucket, now, self.refill_interval, burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Forget all addresses whose bucket has been refilled completely
+    fn prune(&mut self, now: Instant) {
+        let Self {
+            refill_interval,
+            burst,
+            ..
+        } = *self;
+        self.buckets.retain(|_addr, bucket| {
+            Self::refilled_tokens(bucket, now, refill_interval, burst as f64) < burst as f64
+        });
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self
+            .buckets
+            .iter()
+            .min_by_key(|(_addr, bucket)| bucket.last_refill)
+            .map(|(addr, _bucket)| *addr);
+        if let Some(addr) = oldest {
+            self.buckets.remove(&addr);
+        }
+    }
+
+    fn refilled_tokens(
+        bucket: &Bucket,
+        now: Instant,
+        refill_interval: Duration,
+        burst: f64,
+    ) -> f64 {
+        let elapsed = now.duration_since(bucket.last_refill);
+        let refill = elapsed.as_secs_f64() / refill_interval.as_secs_f64();
+        (bucket.tokens + refill).min(burst)
+    }
+}
+
+/// The address the rate limit is applied to: the address itself for IPv4, the /64 prefix for IPv6
+fn source_prefix(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => addr,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let prefix = v6.to_bits() & !((1u128 << 64) - 1);
+                IpAddr::V6(Ipv6Addr::from_bits(prefix))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFILL_INTERVAL: Duration = Duration::from_secs(2);
+
+    fn v4(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_burst_and_refills() {
+        let mut limiter = AcceptRateLimiter::new(2, REFILL_INTERVAL, 16);
+        assert!(limiter.check(v4(1)));
+        assert!(limiter.check(v4(1)));
+        assert!(!limiter.check(v4(1)));
+        assert!(limiter.check(v4(2)));
+
+        tokio::time::advance(REFILL_INTERVAL).await;
+        assert!(limiter.check(v4(1)));
+        assert!(!limiter.check(v4(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_ipv6_per_prefix() {
+        let mut limiter = AcceptRateLimiter::new(1, REFILL_INTERVAL, 16);
+        let addr = |last: u16| IpAddr::from([0x2001, 0xdb8, 0, 1, 0, 0, 0, last]);
+        assert!(limiter.check(addr(1)));
+        assert!(!limiter.check(addr(2)));
+        assert!(limiter.check(IpAddr::from([0x2001, 0xdb8, 0, 2, 0, 0, 0, 1])));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spraying_addresses_does_not_lock_out_others() {
+        let mut limiter = AcceptRateLimiter::new(1, REFILL_INTERVAL, 4);
+        for last in 0..100 {
+            assert!(limiter.check(v4(last)));
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(limiter.buckets.len(), 4);
+        assert!(limiter.check(v4(200)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_least_recently_used() {
+        let mut limiter = AcceptRateLimiter::new(1, REFILL_INTERVAL, 2);
+        assert!(limiter.check(v4(1)));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(limiter.check(v4(2)));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(limiter.check(v4(3)));
+
+        // The bucket of the second address was kept, the first one was evicted
+        assert!(!limiter.check(v4(3)));
+        assert!(limiter.buckets.contains_key(&v4(2)));
+        assert!(!limiter.buckets.contains_key(&v4(1)));
+    }
+}
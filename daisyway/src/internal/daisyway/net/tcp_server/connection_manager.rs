@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::collections::BTreeMap;
 
 use anyhow::{Context, Result};
 use log::info;
@@ -6,55 +6,87 @@ use tokio::{net::TcpListener, sync::mpsc};
 
 use super::{
     abort_on_drop_handle::AbortOnDropHandle,
-    events::{AcceptEvent, ConnectionHandlerEvent, ExitEvent, OskEvent, StreamEvent},
+    accept_rate_limiter::AcceptRateLimiter,
+    connection_router::ConnectionRouter,
+    events::{AcceptEvent, ConnectionHandlerEvent, ExitEvent, OskEvent, RouteEvent, StreamEvent},
     fanout_connection_handler::FanoutConnectionHandler,
-    ConnectionId, MAX_BUDDING_CONNECTIONS,
-};
-use crate::internal::{
-    daisyway::crypto::DaisywayProtocolParameters, etsi014::Etsi014Connection, osk::OskHandler,
+    ConnectionId, DaisywayServerPeer, ACCEPT_RATE_LIMIT_BURST, ACCEPT_RATE_LIMIT_MAX_ADDRESSES,
+    ACCEPT_RATE_LIMIT_REFILL_INTERVAL, MAX_BUDDING_CONNECTIONS,
 };
+use crate::internal::{key_source::QkdKeySource, osk::OskHandler, util::key_to_base64};
 
-pub struct ConnectionManager<O>
+pub struct ConnectionManager<O, K>
 where
     O: OskHandler + Clone,
+    K: QkdKeySource + Send + Sync + 'static,
 {
     listener: TcpListener,
-    osk_handler: O,
+    accept_rate_limiter: AcceptRateLimiter,
 
-    fanout_connection_handler: FanoutConnectionHandler,
+    connection_router: ConnectionRouter,
     manager_notification_rx: mpsc::Receiver<ConnectionHandlerEvent>,
 
     next_connection_id: ConnectionId,
+    /// Connections whose client hello has not been received yet
+    unrouted_connections: BTreeMap<ConnectionId, AbortOnDropHandle>,
+    peers: Vec<PeerConnections<O, K>>,
+}
+
+/// The connections of a single peer; only the active one may set the output key
+struct PeerConnections<O, K>
+where
+    O: OskHandler + Clone,
+    K: QkdKeySource + Send + Sync + 'static,
+{
+    /// WireGuard public key of the peer, for log messages
+    name: String,
+    osk_handler: O,
+    fanout_connection_handler: FanoutConnectionHandler<K>,
     active_connection: Option<(ConnectionId, AbortOnDropHandle)>,
     budding_connections: BTreeMap<ConnectionId, AbortOnDropHandle>,
 }
 
-impl<O> ConnectionManager<O>
+impl<O, K> ConnectionManager<O, K>
 where
     O: OskHandler + Clone,
+    K: QkdKeySource + Send + Sync + 'static,
 {
-    pub fn new(
-        protocol_params: DaisywayProtocolParameters,
-        etsi_client: Arc<Etsi014Connection>,
-        osk_handler: O,
-        listener: TcpListener,
-        rekey_interval: u64,
-    ) -> Self {
+    pub fn new(peers: Vec<DaisywayServerPeer<O, K>>, listener: TcpListener) -> Self {
         let (manager_notification_tx, manager_notification_rx) = mpsc::channel(16);
-        let fanout_connection_handler = FanoutConnectionHandler::new(
-            protocol_params,
-            etsi_client,
-            manager_notification_tx,
-            rekey_interval,
+        let connection_router = ConnectionRouter::new(
+            peers.iter().map(|peer| peer.protocol_params.remote_peer_id),
+            manager_notification_tx.clone(),
         );
+        let peers = peers
+            .into_iter()
+            .enumerate()
+            .map(|(idx, peer)| PeerConnections {
+                name: key_to_base64(&peer.protocol_params.remote_peer_id),
+                osk_handler: peer.osk_handler,
+                fanout_connection_handler: FanoutConnectionHandler::new(
+                    idx,
+                    peer.protocol_params,
+                    peer.key_sources,
+                    peer.state,
+                    manager_notification_tx.clone(),
+                    peer.rekey_interval,
+                ),
+                active_connection: None,
+                budding_connections: BTreeMap::new(),
+            })
+            .collect();
         Self {
             listener,
-            osk_handler,
-            fanout_connection_handler,
-            active_connection: None,
-            budding_connections: BTreeMap::new(),
-            next_connection_id: 0,
+            accept_rate_limiter: AcceptRateLimiter::new(
+                ACCEPT_RATE_LIMIT_BURST,
+                ACCEPT_RATE_LIMIT_REFILL_INTERVAL,
+                ACCEPT_RATE_LIMIT_MAX_ADDRESSES,
+            ),
+            connection_router,
             manager_notification_rx,
+            next_connection_id: 0,
+            unrouted_connections: BTreeMap::new(),
+            peers,
         }
     }
 
@@ -80,45 +112,123 @@ where
         use StreamEvent as E;
         match ev {
             E::Accept(ev) => self.on_accept(ev).await,
+            E::Route(ev) => self.on_route(ev).await,
             E::Exit(ev) => self.on_exit(ev).await,
             E::Osk(ev) => self.on_osk(ev).await,
         }
     }
 
     async fn on_accept(&mut self, ev: AcceptEvent) -> Result<()> {
+        if !self.accept_rate_limiter.check(ev.addr.ip()) {
+            log::warn!(
+                "[SERVER] Rejecting connection from {:?}: Too many connection attempts",
+                ev.addr
+            );
+            return Ok(());
+        }
+
         let connection_id = self.allocate_connection_id();
         info!(
             "[SERVER] Accepted connection #{connection_id} from {:?}",
             ev.addr
         );
 
-        // Make sure there is space in the budding connections
-        if self.budding_connections.len() >= MAX_BUDDING_CONNECTIONS {
-            let (pruned_id, _handle) = self.budding_connections.pop_first().expect(
-                "Could not prune oldest budding connection to make space \
-                    for a new one, because data structure returned None. \
-                    This is a bug!",
+        // Make sure there is space for another connection without a client hello
+        prune_oldest(&mut self.unrouted_connections, connection_id);
+
+        // Set up the task receiving the client hello
+        let abort_handle = self
+            .connection_router
+            .clone()
+            .spawn(connection_id, ev.stream)
+            .into();
+        self.unrouted_connections
+            .insert(connection_id, abort_handle);
+
+        Ok(())
+    }
+
+    async fn on_route(&mut self, ev: RouteEvent) -> Result<()> {
+        let conn_id = ev.connection_id;
+        if self.unrouted_connections.remove(&conn_id).is_none() {
+            log::debug!("Connection #{conn_id} was pruned before its client hello arrived.");
+            return Ok(());
+        }
+
+        let several_peers = self.peers.len() > 1;
+        let peer = &mut self.peers[ev.peer];
+        if several_peers {
+            info!(
+                "[SERVER] Connection #{conn_id} belongs to peer {}",
+                peer.name
             );
-            log::info!(
-                "Pruning oldest budding connection #{pruned_id} \
-                to make space for new connection #{connection_id}"
+        }
+        peer.on_route(ev);
+        Ok(())
+    }
+
+    async fn on_exit(&mut self, ev: ExitEvent) -> Result<()> {
+        let conn_id = ev.connection_id;
+        match ev.peer {
+            Some(peer) => self.peers[peer].on_exit(ev),
+            None if self.unrouted_connections.remove(&conn_id).is_some() => {
+                log::debug!("Connection #{conn_id} exited before it was assigned to a peer.");
+            }
+            None => {
+                log::warn!("Received exit notification for non-existent connection #{conn_id}. This is likely a bug!");
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward the output key to the peer's handler
+    ///
+    /// A failing handler must not take down the listener of the other peers, so only the
+    /// connection that negotiated the key is dropped. Its client reconnects and negotiates a
+    /// new key, which gives the handler another chance.
+    async fn on_osk(&mut self, ev: OskEvent) -> Result<()> {
+        let conn_id = ev.connection_id;
+        let peer = &mut self.peers[ev.peer];
+        if let Err(err) = peer.on_osk(ev).await {
+            log::error!(
+                "[SERVER] Failed to set the output key of peer {}; dropping connection #{conn_id}: {err:?}",
+                peer.name
             );
+            peer.drop_connection(conn_id);
         }
+        Ok(())
+    }
+
+    fn allocate_connection_id(&mut self) -> ConnectionId {
+        let r = self.next_connection_id;
+        self.next_connection_id += 1;
+        r
+    }
+}
+
+impl<O, K> PeerConnections<O, K>
+where
+    O: OskHandler + Clone,
+    K: QkdKeySource + Send + Sync + 'static,
+{
+    fn on_route(&mut self, ev: RouteEvent) {
+        let connection_id = ev.connection_id;
+
+        // Make sure there is space in the budding connections
+        prune_oldest(&mut self.budding_connections, connection_id);
 
         // Set up the protocol handler task
         let abort_handle = self
             .fanout_connection_handler
             .clone()
-            .spawn(connection_id, ev.stream)
+            .spawn(connection_id, ev.stream, ev.client_hello)
             .into();
 
         // Register the connection as a budding connection
         self.budding_connections.insert(connection_id, abort_handle);
-
-        Ok(())
     }
 
-    async fn on_exit(&mut self, ev: ExitEvent) -> Result<()> {
+    fn on_exit(&mut self, ev: ExitEvent) {
         let conn_id = ev.connection_id;
 
         if Some(conn_id) == self.active_connection_id() {
@@ -131,8 +241,6 @@ where
         } else {
             log::warn!("Received exit notification for non-existent connection #{conn_id}. This is likely a bug!");
         }
-
-        Ok(())
     }
 
     async fn on_osk(&mut self, ev: OskEvent) -> Result<()> {
@@ -203,15 +311,189 @@ where
         self.osk_handler.set_osk(ev.key, ev.reason).await
     }
 
+    /// Abort the connection; aborted connections send no exit notification
+    fn drop_connection(&mut self, conn_id: ConnectionId) {
+        if Some(conn_id) == self.active_connection_id() {
+            self.active_connection.take();
+        } else {
+            self.budding_connections.remove(&conn_id);
+        }
+    }
+
     fn active_connection_id(&self) -> Option<ConnectionId> {
         self.active_connection
             .as_ref()
             .map(|(active_conn_id, _rec)| *active_conn_id)
     }
+}
+
+/// Drop the oldest connection if there are too many, to make space for a new one
+fn prune_oldest(
+    connections: &mut BTreeMap<ConnectionId, AbortOnDropHandle>,
+    connection_id: ConnectionId,
+) {
+    if connections.len() >= MAX_BUDDING_CONNECTIONS {
+        let (pruned_id, _handle) = connections.pop_first().expect(
+            "Could not prune oldest budding connection to make space \
+                for a new one, because data structure returned None. \
+                This is a bug!",
+        );
+        log::info!(
+            "Pruning oldest budding connection #{pruned_id} \
+            to make space for new connection #{connection_id}"
+        );
+    }
+}
 
-    fn allocate_connection_id(&mut self) -> ConnectionId {
-        let r = self.next_connection_id;
-        self.next_connection_id += 1;
-        r
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::pending,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use anyhow::{bail, ensure};
+    use tokio::spawn;
+    use uuid::Uuid;
+
+    use super::{super::PeerIndex, *};
+    use crate::internal::{
+        daisyway::{
+            crypto::{DaisywayProtocolParameters, Key, ProtocolFeatures},
+            state::StateStore,
+        },
+        key_source::{KeySourceSet, QkdKey},
+        osk::{OskDeadman, SetOskReason},
+    };
+
+    struct NoKeys;
+
+    impl QkdKeySource for NoKeys {
+        async fn fetch_any_key(&self) -> Result<QkdKey> {
+            bail!("No keys")
+        }
+
+        async fn fetch_specific_key(&self, _id: Uuid) -> Result<QkdKey> {
+            bail!("No keys")
+        }
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct TestOskHandler {
+        fail: Arc<AtomicBool>,
+        keys: Arc<Mutex<Vec<Key>>>,
+    }
+
+    impl OskHandler for TestOskHandler {
+        async fn set_osk(&self, key: Key, _reason: SetOskReason) -> Result<()> {
+            ensure!(!self.fail.load(Ordering::Relaxed), "WireGuard peer is gone");
+            self.keys.lock().unwrap().push(key);
+            Ok(())
+        }
+    }
+
+    async fn manager<O: OskHandler + Clone>(osk_handlers: &[O]) -> ConnectionManager<O, NoKeys> {
+        let peers = osk_handlers
+            .iter()
+            .enumerate()
+            .map(|(idx, osk_handler)| DaisywayServerPeer {
+                protocol_params: DaisywayProtocolParameters {
+                    psk: [0; 32],
+                    local_peer_id: [0; 32],
+                    remote_peer_id: [idx as u8 + 1; 32],
+                    features: ProtocolFeatures::NONE,
+                    kem: None,
+                },
+                key_sources: Arc::new(KeySourceSet::new(vec![NoKeys]).unwrap()),
+                osk_handler: osk_handler.clone(),
+                state: Arc::new(StateStore::in_memory(16)),
+                rekey_interval: 120,
+            })
+            .collect();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        ConnectionManager::new(peers, listener)
+    }
+
+    /// Register a budding connection and let it deliver an output key
+    async fn osk_from<O: OskHandler + Clone>(
+        manager: &mut ConnectionManager<O, NoKeys>,
+        peer: PeerIndex,
+        connection_id: ConnectionId,
+    ) {
+        let connections = &mut manager.peers[peer].budding_connections;
+        connections.insert(connection_id, spawn(pending::<()>()).into());
+        let ev = OskEvent {
+            connection_id,
+            peer,
+            key: [connection_id as u8; 32],
+            reason: SetOskReason::Fresh,
+        };
+        manager.on_event(StreamEvent::Osk(ev)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failing_osk_handler_only_drops_its_connection() {
+        let handlers = [TestOskHandler::default(), TestOskHandler::default()];
+        handlers[0].fail.store(true, Ordering::Relaxed);
+        let mut manager = manager(&handlers).await;
+
+        osk_from(&mut manager, 0, 1).await;
+        osk_from(&mut manager, 1, 2).await;
+
+        assert_eq!(manager.peers[0].active_connection_id(), None);
+        assert!(manager.peers[0].budding_connections.is_empty());
+        assert_eq!(manager.peers[1].active_connection_id(), Some(2));
+        assert_eq!(*handlers[1].keys.lock().unwrap(), [[2; 32]]);
+    }
+
+    #[tokio::test]
+    async fn osk_handler_is_retried_on_the_next_connection() {
+        let handlers = [TestOskHandler::default()];
+        let mut manager = manager(&handlers).await;
+
+        osk_from(&mut manager, 0, 1).await;
+        assert_eq!(manager.peers[0].active_connection_id(), Some(1));
+
+        handlers[0].fail.store(true, Ordering::Relaxed);
+        let ev = OskEvent {
+            connection_id: 1,
+            peer: 0,
+            key: [3; 32],
+            reason: SetOskReason::Fresh,
+        };
+        manager.on_event(StreamEvent::Osk(ev)).await.unwrap();
+        assert_eq!(manager.peers[0].active_connection_id(), None);
+
+        handlers[0].fail.store(false, Ordering::Relaxed);
+        osk_from(&mut manager, 0, 2).await;
+        assert_eq!(manager.peers[0].active_connection_id(), Some(2));
+        assert_eq!(*handlers[0].keys.lock().unwrap(), [[1; 32], [2; 32]]);
+    }
+
+    #[tokio::test]
+    async fn failing_deadman_only_drops_its_connection() {
+        let handlers = [TestOskHandler::default(), TestOskHandler::default()];
+        handlers[0].fail.store(true, Ordering::Relaxed);
+        let deadmen: Vec<_> = handlers
+            .iter()
+            .map(|handler| {
+                let handler = handler.clone();
+                OskDeadman::start(Duration::from_secs(3600), move || handler)
+            })
+            .collect();
+        let mut manager = manager(&deadmen).await;
+
+        osk_from(&mut manager, 0, 1).await;
+        osk_from(&mut manager, 1, 2).await;
+
+        assert_eq!(manager.peers[0].active_connection_id(), None);
+        assert!(manager.peers[0].budding_connections.is_empty());
+        assert_eq!(manager.peers[1].active_connection_id(), Some(2));
+        // The deadman erases the key on startup before setting the fresh one
+        assert_eq!(handlers[1].keys.lock().unwrap().last(), Some(&[2; 32]));
     }
 }
@@ -0,0 +1,178 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use tokio::{net::TcpStream, spawn, sync::mpsc, task::JoinHandle};
+
+use super::{
+    events::{ConnectionHandlerEvent, ExitEvent, RouteEvent},
+    ConnectionId, PeerIndex,
+};
+use crate::internal::{
+    daisyway::crypto::{ClientHello, FramedStream, PeerId, HANDSHAKE_TIMEOUT},
+    util::key_to_base64,
+};
+
+/// Assigns new connections to peers using the WireGuard public key in the client hello
+///
+/// With a single peer, every connection is assigned to it; clients of older versions do not
+/// send their key. The key is authenticated later on, when the handshake is completed using
+/// the parameters of the peer.
+#[derive(Clone)]
+pub struct ConnectionRouter {
+    peers: Arc<HashMap<PeerId, PeerIndex>>,
+    manager_notification_tx: mpsc::Sender<ConnectionHandlerEvent>,
+}
+
+impl ConnectionRouter {
+    pub fn new(
+        peer_ids: impl IntoIterator<Item = PeerId>,
+        manager_notification_tx: mpsc::Sender<ConnectionHandlerEvent>,
+    ) -> Self {
+        let peers = peer_ids.into_iter().enumerate().map(|(idx, id)| (id, idx));
+        Self {
+            peers: Arc::new(peers.collect()),
+            manager_notification_tx,
+        }
+    }
+
+    pub fn spawn(self, connection_id: ConnectionId, stream: TcpStream) -> JoinHandle<()> {
+        spawn(async move { self.init_task(connection_id, stream).await })
+    }
+
+    async fn init_task(self, connection_id: ConnectionId, stream: TcpStream) {
+        let ev = match self.route(stream).await {
+            Ok((peer, stream, client_hello)) => ConnectionHandlerEvent::Route(RouteEvent {
+                connection_id,
+                peer,
+                stream,
+                client_hello,
+            }),
+            Err(err) => {
+                log::warn!("[SERVER] Error in connection #{connection_id}: {err}");
+                log::debug!(
+                    "[SERVER] Error in connection #{connection_id} (full error message): {err:?}"
+                );
+                ConnectionHandlerEvent::Exit(ExitEvent {
+                    connection_id,
+                    peer: None,
+                })
+            }
+        };
+
+        if let Err(err) = self.manager_notification_tx.send(ev).await {
+            log::warn!("[SERVER] Failed to hand connection #{connection_id} to the connection manager: {err}");
+        }
+    }
+
+    async fn route(&self, stream: TcpStream) -> Result<(PeerIndex, TcpStream, ClientHello)> {
+        let mut stream = FramedStream::new(stream);
+        let client_hello: ClientHello = tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.recv())
+            .await
+            .context("Peer did not send the client hello in time")?
+            .context("Failed to receive client hello")?;
+
+        let peer = match (client_hello.peer_id, self.peers.len()) {
+            (_, 1) => 0,
+            (Some(peer_id), _) => match self.peers.get(&peer_id) {
+                Some(peer) => *peer,
+                None => {
+                    stream.abort("Unknown peer").await?;
+                    bail!(
+                        "Peer identified itself as WireGuard peer {}, which is not configured",
+                        key_to_base64(&peer_id)
+                    );
+                }
+            },
+            (None, _) => {
+                stream
+                    .abort(
+                        "Several peers are served on this address; the client must identify itself",
+                    )
+                    .await?;
+                bail!(
+                    "Peer did not identify itself, but several peers are served on this address. \
+                    The peer is probably running an older version of daisyway."
+                );
+            }
+        };
+        Ok((peer, stream.into_inner(), client_hello))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::internal::daisyway::crypto::ProtocolFeatures;
+
+    const BOB: PeerId = [1; 32];
+    const CAROL: PeerId = [2; 32];
+
+    /// Route a connection sending the given client hello; returns the manager notification
+    async fn route(peer_ids: &[PeerId], client_hello: ClientHello) -> ConnectionHandlerEvent {
+        let (tx, mut rx) = mpsc::channel(1);
+        let router = ConnectionRouter::new(peer_ids.iter().copied(), tx);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        router.spawn(7, server);
+
+        let mut client = FramedStream::new(client);
+        client.send(&client_hello).await.unwrap();
+        rx.recv().await.unwrap()
+    }
+
+    fn anonymous_hello() -> ClientHello {
+        ClientHello {
+            peer_id: None,
+            ..ClientHello::new(ProtocolFeatures::NONE, BOB)
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_is_routed_by_peer_id() {
+        let hello = ClientHello::new(ProtocolFeatures::NONE, CAROL);
+        match route(&[BOB, CAROL], hello).await {
+            ConnectionHandlerEvent::Route(ev) => {
+                assert_eq!(ev.connection_id, 7);
+                assert_eq!(ev.peer, 1);
+                assert_eq!(ev.client_hello.peer_id, Some(CAROL));
+            }
+            _ => panic!("Connection was not routed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_peer_is_rejected() {
+        let hello = ClientHello::new(ProtocolFeatures::NONE, [3; 32]);
+        match route(&[BOB, CAROL], hello).await {
+            ConnectionHandlerEvent::Exit(ev) => assert_eq!(ev.peer, None),
+            _ => panic!("Connection of an unknown peer was routed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn anonymous_client_is_rejected_with_several_peers() {
+        match route(&[BOB, CAROL], anonymous_hello()).await {
+            ConnectionHandlerEvent::Exit(ev) => assert_eq!(ev.peer, None),
+            _ => panic!("Anonymous connection was routed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn single_peer_gets_every_connection() {
+        for hello in [
+            anonymous_hello(),
+            ClientHello::new(ProtocolFeatures::NONE, CAROL),
+        ] {
+            match route(&[BOB], hello).await {
+                ConnectionHandlerEvent::Route(ev) => assert_eq!(ev.peer, 0),
+                _ => panic!("Connection was not routed"),
+            }
+        }
+    }
+}
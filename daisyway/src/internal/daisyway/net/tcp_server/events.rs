@@ -2,31 +2,47 @@ use std::net::SocketAddr;
 
 use tokio::net::TcpStream;
 
-use super::ConnectionId;
-use crate::internal::{daisyway::crypto::Key, osk::SetOskReason};
+use super::{ConnectionId, PeerIndex};
+use crate::internal::{
+    daisyway::crypto::{ClientHello, Key},
+    osk::SetOskReason,
+};
 
 pub struct AcceptEvent {
     pub stream: TcpStream,
     pub addr: SocketAddr,
 }
 
+/// The client hello of a connection has been received and names the peer it belongs to
+pub struct RouteEvent {
+    pub connection_id: ConnectionId,
+    pub peer: PeerIndex,
+    pub stream: TcpStream,
+    pub client_hello: ClientHello,
+}
+
 pub struct ExitEvent {
     pub connection_id: ConnectionId,
+    /// Absent if the connection exited before it was assigned to a peer
+    pub peer: Option<PeerIndex>,
 }
 
 pub struct OskEvent {
     pub connection_id: ConnectionId,
+    pub peer: PeerIndex,
     pub key: Key,
     pub reason: SetOskReason,
 }
 
 pub enum ConnectionHandlerEvent {
+    Route(RouteEvent),
     Exit(ExitEvent),
     Osk(OskEvent),
 }
 
 pub enum StreamEvent {
     Accept(AcceptEvent),
+    Route(RouteEvent),
     Exit(ExitEvent),
     Osk(OskEvent),
 }
@@ -36,6 +52,7 @@ impl From<ConnectionHandlerEvent> for StreamEvent {
         use ConnectionHandlerEvent as C;
         use StreamEvent as S;
         match value {
+            C::Route(route) => S::Route(route),
             C::Exit(exit) => S::Exit(exit),
             C::Osk(osk) => S::Osk(osk),
         }
@@ -6,48 +6,84 @@ use tokio::{net::TcpStream, spawn, sync::mpsc, task::JoinHandle};
 use super::{
     events::{ConnectionHandlerEvent, ExitEvent},
     fanout_osk_handler::FanoutOskHandler,
-    ConnectionId,
+    ConnectionId, PeerIndex,
 };
 use crate::internal::{
-    daisyway::crypto::{DaisywayProtocolParameters, DaisywayServerProtocol},
-    etsi014::Etsi014Connection,
+    daisyway::{
+        crypto::{ClientHello, DaisywayProtocolParameters, DaisywayServerProtocol},
+        state::StateStore,
+    },
+    key_source::{KeySourceSet, QkdKeySource},
 };
 
-#[derive(Clone)]
-pub struct FanoutConnectionHandler {
+pub struct FanoutConnectionHandler<K: QkdKeySource> {
+    peer: PeerIndex,
     protocol_params: DaisywayProtocolParameters,
-    etsi_client: Arc<Etsi014Connection>,
+    key_sources: Arc<KeySourceSet<K>>,
+    state: Arc<StateStore>,
     manager_notification_tx: mpsc::Sender<ConnectionHandlerEvent>,
     rekey_interval: u64,
 }
 
-impl FanoutConnectionHandler {
+// Implemented manually, since deriving would require K: Clone
+impl<K: QkdKeySource> Clone for FanoutConnectionHandler<K> {
+    fn clone(&self) -> Self {
+        Self {
+            peer: self.peer,
+            protocol_params: self.protocol_params.clone(),
+            key_sources: self.key_sources.clone(),
+            state: self.state.clone(),
+            manager_notification_tx: self.manager_notification_tx.clone(),
+            rekey_interval: self.rekey_interval,
+        }
+    }
+}
+
+impl<K> FanoutConnectionHandler<K>
+where
+    K: QkdKeySource + Send + Sync + 'static,
+{
     pub fn new(
+        peer: PeerIndex,
         protocol_params: DaisywayProtocolParameters,
-        etsi_client: Arc<Etsi014Connection>,
+        key_sources: Arc<KeySourceSet<K>>,
+        state: Arc<StateStore>,
         manager_notification_tx: mpsc::Sender<ConnectionHandlerEvent>,
         rekey_interval: u64,
     ) -> Self {
         Self {
+            peer,
             protocol_params,
-            etsi_client,
+            key_sources,
+            state,
             manager_notification_tx,
             rekey_interval,
         }
     }
 
-    pub fn spawn(self, connection_id: ConnectionId, stream: TcpStream) -> JoinHandle<()> {
-        spawn(async move { self.init_task(connection_id, stream).await })
+    pub fn spawn(
+        self,
+        connection_id: ConnectionId,
+        stream: TcpStream,
+        client_hello: ClientHello,
+    ) -> JoinHandle<()> {
+        spawn(async move { self.init_task(connection_id, stream, client_hello).await })
     }
 
-    async fn init_task(self, connection_id: ConnectionId, stream: TcpStream) {
+    async fn init_task(
+        self,
+        connection_id: ConnectionId,
+        stream: TcpStream,
+        client_hello: ClientHello,
+    ) {
         let Self {
+            peer,
             manager_notification_tx,
             ..
         } = self.clone();
 
         // Run the connection handler, handle any errors
-        if let Err(err) = self.event_loop(connection_id, stream).await {
+        if let Err(err) = self.event_loop(connection_id, stream, client_hello).await {
             log::warn!("[SERVER] Error in connection #{connection_id}: {err}");
             log::debug!(
                 "[SERVER] Error in connection #{connection_id} (full error message): {err:?}"
@@ -56,7 +92,10 @@ impl FanoutConnectionHandler {
 
         // Tell the connection manager that this particular connection is exiting
         let res = manager_notification_tx
-            .send(ConnectionHandlerEvent::Exit(ExitEvent { connection_id }))
+            .send(ConnectionHandlerEvent::Exit(ExitEvent {
+                connection_id,
+                peer: Some(peer),
+            }))
             .await;
         if let Err(err) = res {
             log::warn!("[SERVER] Failed to inform connection manager about exit of connection #{connection_id}: {err}");
@@ -64,22 +103,31 @@ impl FanoutConnectionHandler {
         }
     }
 
-    async fn event_loop(self, connection_id: ConnectionId, stream: TcpStream) -> Result<()> {
+    async fn event_loop(
+        self,
+        connection_id: ConnectionId,
+        stream: TcpStream,
+        client_hello: ClientHello,
+    ) -> Result<()> {
         let Self {
+            peer,
             protocol_params,
-            etsi_client,
+            key_sources,
+            state,
             manager_notification_tx,
             rekey_interval,
         } = self;
 
-        let osk_handler = FanoutOskHandler::new(manager_notification_tx, connection_id);
+        let osk_handler = FanoutOskHandler::new(manager_notification_tx, peer, connection_id);
         let mut protocol_handler = DaisywayServerProtocol::new(
             protocol_params.clone(),
             stream,
-            etsi_client.clone(),
+            key_sources.clone(),
             osk_handler,
+            state,
             rekey_interval,
-        );
+        )
+        .with_client_hello(client_hello);
 
         protocol_handler.event_loop().await
     }
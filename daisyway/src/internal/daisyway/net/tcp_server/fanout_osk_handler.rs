@@ -5,7 +5,7 @@ use tokio::sync::mpsc;
 
 use super::{
     events::{ConnectionHandlerEvent, OskEvent},
-    ConnectionId,
+    ConnectionId, PeerIndex,
 };
 use crate::internal::{
     daisyway::crypto::Key,
@@ -14,26 +14,34 @@ use crate::internal::{
 
 pub struct FanoutOskHandler {
     pub manager_notification_tx: mpsc::Sender<ConnectionHandlerEvent>,
+    pub peer: PeerIndex,
     pub connection_id: ConnectionId,
 }
 
 impl FanoutOskHandler {
     pub fn new(
         manager_notification_tx: mpsc::Sender<ConnectionHandlerEvent>,
+        peer: PeerIndex,
         connection_id: ConnectionId,
     ) -> Self {
         Self {
             manager_notification_tx,
+            peer,
             connection_id,
         }
     }
 
     async fn set_osk_impl(&self, key: Key, reason: SetOskReason) -> Result<()> {
-        let Self { connection_id, .. } = *self;
+        let Self {
+            peer,
+            connection_id,
+            ..
+        } = *self;
         self.manager_notification_tx
             .send(ConnectionHandlerEvent::Osk(OskEvent {
                 key,
                 reason,
+                peer,
                 connection_id,
             }))
             .await?;
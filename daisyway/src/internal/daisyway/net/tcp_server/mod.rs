@@ -1,65 +1,97 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use tokio::net::{TcpListener, ToSocketAddrs};
 
 use crate::internal::{
-    daisyway::crypto::DaisywayProtocolParameters, etsi014::Etsi014Connection, osk::OskHandler,
+    daisyway::{crypto::DaisywayProtocolParameters, state::StateStore},
+    key_source::{KeySourceSet, QkdKeySource},
+    osk::OskHandler,
 };
 
 mod abort_on_drop_handle;
+mod accept_rate_limiter;
 mod connection_manager;
+mod connection_router;
 mod events;
 mod fanout_connection_handler;
 mod fanout_osk_handler;
 
 const MAX_BUDDING_CONNECTIONS: usize = 2000;
 
+/// Number of connections a single source address may open in quick succession
+const ACCEPT_RATE_LIMIT_BURST: u32 = 8;
+
+/// Time after which a source address may open another connection once its burst is used up
+const ACCEPT_RATE_LIMIT_REFILL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Maximum number of source addresses tracked by the accept rate limiter
+const ACCEPT_RATE_LIMIT_MAX_ADDRESSES: usize = 4096;
+
 type ConnectionId = usize;
 
-#[derive(Debug, Clone)]
-pub struct DaisywayTcpServer<O, Addr>
+/// Position of a peer in [DaisywayTcpServer::peers]
+type PeerIndex = usize;
+
+/// A peer served by a [DaisywayTcpServer]
+#[derive(Debug)]
+pub struct DaisywayServerPeer<O, K>
 where
     O: OskHandler + Clone,
-    Addr: ToSocketAddrs + std::fmt::Debug,
+    K: QkdKeySource + Send + Sync + 'static,
 {
     pub protocol_params: DaisywayProtocolParameters,
-    pub listen_addr: Addr,
-    pub etsi_client: Arc<Etsi014Connection>,
+    pub key_sources: Arc<KeySourceSet<K>>,
     pub osk_handler: O,
+    pub state: Arc<StateStore>,
     pub rekey_interval: u64,
 }
 
-impl<O, Addr> DaisywayTcpServer<O, Addr>
+// Implemented manually, since deriving would require K: Clone
+impl<O, K> Clone for DaisywayServerPeer<O, K>
 where
     O: OskHandler + Clone,
-    Addr: ToSocketAddrs + std::fmt::Debug,
+    K: QkdKeySource + Send + Sync + 'static,
 {
-    pub fn new(
-        protocol_params: DaisywayProtocolParameters,
-        listen_addr: Addr,
-        etsi_client: Arc<Etsi014Connection>,
-        osk_handler: O,
-        rekey_interval: u64,
-    ) -> Self {
+    fn clone(&self) -> Self {
         Self {
-            protocol_params,
-            listen_addr,
-            etsi_client,
-            osk_handler,
-            rekey_interval,
+            protocol_params: self.protocol_params.clone(),
+            key_sources: self.key_sources.clone(),
+            osk_handler: self.osk_handler.clone(),
+            state: self.state.clone(),
+            rekey_interval: self.rekey_interval,
         }
     }
+}
+
+/// Serves one or more peers on a single listening address
+///
+/// Clients send their WireGuard public key in the client hello, and every connection is
+/// handled using the parameters, key sources and OSK handler of the matching peer.
+#[derive(Debug, Clone)]
+pub struct DaisywayTcpServer<O, Addr, K>
+where
+    O: OskHandler + Clone,
+    Addr: ToSocketAddrs + std::fmt::Debug,
+    K: QkdKeySource + Send + Sync + 'static,
+{
+    pub listen_addr: Addr,
+    pub peers: Vec<DaisywayServerPeer<O, K>>,
+}
+
+impl<O, Addr, K> DaisywayTcpServer<O, Addr, K>
+where
+    O: OskHandler + Clone,
+    Addr: ToSocketAddrs + std::fmt::Debug,
+    K: QkdKeySource + Send + Sync + 'static,
+{
+    pub fn new(listen_addr: Addr, peers: Vec<DaisywayServerPeer<O, K>>) -> Self {
+        Self { listen_addr, peers }
+    }
 
     pub async fn event_loop(&mut self) -> Result<()> {
         let listener = TcpListener::bind(&self.listen_addr).await?;
-        let mut manager = connection_manager::ConnectionManager::new(
-            self.protocol_params.clone(),
-            self.etsi_client.clone(),
-            self.osk_handler.clone(),
-            listener,
-            self.rekey_interval,
-        );
+        let mut manager = connection_manager::ConnectionManager::new(self.peers.clone(), listener);
         manager.event_loop().await
     }
 }
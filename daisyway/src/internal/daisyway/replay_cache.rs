@@ -0,0 +1,141 @@
+use std::collections::{HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Default number of QKD key IDs remembered to detect replays
+pub const DEFAULT_REPLAY_CACHE_SIZE: usize = 1024;
+
+/// Bounded set of consumed QKD key IDs
+///
+/// Once the capacity is reached, the oldest key ID is evicted to make space for a new one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "VecDeque<Uuid>", into = "VecDeque<Uuid>")]
+pub struct ReplayCache {
+    capacity: usize,
+    /// Key IDs in the order they were consumed, oldest first
+    order: VecDeque<Uuid>,
+    members: HashSet<Uuid>,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::new(),
+            members: HashSet::new(),
+        }
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.members.contains(id)
+    }
+
+    /// Record a consumed key ID; returns false if it was already present
+    pub fn insert(&mut self, id: Uuid) -> bool {
+        if !self.members.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        self.evict();
+        true
+    }
+
+    /// Change the capacity, evicting the oldest entries if necessary
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.order.len() > self.capacity {
+            if let Some(id) = self.order.pop_front() {
+                self.members.remove(&id);
+            }
+        }
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_REPLAY_CACHE_SIZE)
+    }
+}
+
+impl From<VecDeque<Uuid>> for ReplayCache {
+    fn from(ids: VecDeque<Uuid>) -> Self {
+        let mut cache = Self::new(ids.len().max(DEFAULT_REPLAY_CACHE_SIZE));
+        for id in ids {
+            cache.insert(id);
+        }
+        cache
+    }
+}
+
+impl From<ReplayCache> for VecDeque<Uuid> {
+    fn from(cache: ReplayCache) -> Self {
+        cache.order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    #[test]
+    fn inserted_ids_are_contained() {
+        let mut cache = ReplayCache::new(4);
+        assert!(!cache.contains(&id(1)));
+        assert!(cache.insert(id(1)));
+        assert!(cache.insert(id(2)));
+        assert!(cache.contains(&id(1)));
+        assert!(cache.contains(&id(2)));
+        assert!(!cache.contains(&id(3)));
+    }
+
+    #[test]
+    fn duplicate_ids_are_detected() {
+        let mut cache = ReplayCache::new(4);
+        assert!(cache.insert(id(1)));
+        assert!(!cache.insert(id(1)));
+        // A duplicate does not take up a second slot
+        assert_eq!(cache.order, [id(1)]);
+    }
+
+    #[test]
+    fn oldest_ids_are_evicted_at_capacity() {
+        let mut cache = ReplayCache::new(2);
+        cache.insert(id(1));
+        cache.insert(id(2));
+        cache.insert(id(3));
+        assert!(!cache.contains(&id(1)));
+        assert!(cache.contains(&id(2)));
+        assert!(cache.contains(&id(3)));
+
+        cache.set_capacity(1);
+        assert!(!cache.contains(&id(2)));
+        assert!(cache.contains(&id(3)));
+    }
+
+    #[test]
+    fn cache_survives_serialization() {
+        let mut cache = ReplayCache::default();
+        for n in 1..=3 {
+            cache.insert(id(n));
+        }
+        let json = serde_json::to_string(&cache).unwrap();
+        let restored: ReplayCache = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, cache);
+
+        // The oldest entries are still evicted first after a reload
+        let mut restored = restored;
+        restored.set_capacity(2);
+        assert!(!restored.contains(&id(1)));
+        assert!(restored.contains(&id(2)));
+        assert!(restored.contains(&id(3)));
+    }
+}
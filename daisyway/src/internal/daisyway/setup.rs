@@ -1,36 +1,82 @@
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{bail, Context, Result};
-use log::info;
+use anyhow::{bail, ensure, Context, Result};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinSet,
+};
 use zerocopy::FromZeros;
 
+use super::discovery::PeerDiscovery;
 use crate::internal::{
     daisyway::{
-        crypto::{DaisywayProtocolParameters, Key, REKEY_INTERVAL},
-        net::{DaisywayTcpParticipant, DaisywayTcpParticipantConfig},
+        crypto::{
+            ml_kem, DaisywayProtocolParameters, KemKeys, Key, ProtocolFeatures, REKEY_INTERVAL,
+        },
+        net::{
+            DaisywayServerPeer, DaisywayTcpClient, DaisywayTcpParticipant,
+            DaisywayTcpParticipantConfig, DaisywayTcpServer,
+        },
+        replay_cache::DEFAULT_REPLAY_CACHE_SIZE,
+        state::StateStore,
+    },
+    etsi004::Etsi004Config,
+    etsi014::Etsi014Config,
+    key_source::{
+        AnyKeySource, KeySourceConfigs, KeySourceSet, KEY_SOURCE_STATUS_INTERVAL,
+        TLS_RELOAD_CHECK_INTERVAL,
     },
-    etsi014::{Etsi014Config, Etsi014Connection},
-    osk::{OskDeadman, OskHandler, OutfileOskHandler},
-    util::{base64_to_key, load_base64_key_file},
+    osk::{OskDeadman, OskHandler, OutfileOskHandler, UserspaceWireGuardOskHandler},
+    util::{base64_to_key, load_base64_file, load_base64_key_file, store_base64_file},
 };
 
+/// Time after which the event loop of a failed peer is restarted, if there are several peers
+const PEER_RESTART_DELAY: Duration = Duration::from_secs(10);
+
+/// Default time between two scans of the WireGuard interface when discovering peers
+const PEER_DISCOVERY_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct DaisywayConfig {
-    pub etsi014: Etsi014Config,
+    #[serde(default)]
+    pub etsi014: KeySourceConfigs<Etsi014Config>,
+    #[serde(default)]
+    pub etsi004: KeySourceConfigs<Etsi004Config>,
     pub wireguard: WireGuardConfig,
     pub outfile: Option<OutfileConfig>,
-    pub peer: PeerConfig,
+    /// The only peer; use `peers` instead for several peers
+    pub peer: Option<PeerConfig>,
+    /// Several peers served by one process
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WireGuardConfig {
     #[serde(rename = "self_public_key")]
     pub local_peer_id: String,
+    /// Only used with a single `[peer]` section
     #[serde(rename = "peer_public_key")]
-    pub remote_peer_id: String,
+    pub remote_peer_id: Option<String>,
     pub interface: Option<String>,
+    /// Set the PSK through the UAPI socket of a userspace WireGuard, e.g. wireguard-go
+    #[serde(default)]
+    pub userspace: bool,
+    /// UAPI socket of the userspace WireGuard; defaults to `/var/run/wireguard/<interface>.sock`
+    pub uapi_socket: Option<PathBuf>,
+    /// Run the `[[peers]]` entries only while the interface has a matching peer
+    #[serde(default)]
+    pub discover_peers: bool,
+    /// Seconds between two scans of the interface when discovering peers
+    pub discovery_interval_secs: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -38,15 +84,46 @@ pub struct OutfileConfig {
     path: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PeerConfig {
     #[serde(flatten)]
     pub participant: DaisywayTcpParticipantConfig,
+    /// The peer's WireGuard public key; required for every entry of `[[peers]]`
+    #[serde(rename = "peer_public_key")]
+    pub remote_peer_id: Option<String>,
+    /// Replaces the remote_sae_id of all ETSI 014 key sources for this peer
+    pub remote_sae_id: Option<String>,
+    /// Rekey interval in seconds; defaults to the largest interval of the key sources
+    pub interval_secs: Option<u64>,
+    /// File the exchanged key is stored in, instead of the `[outfile]` section
+    pub outfile: Option<String>,
     pub psk_file: Option<PathBuf>,
+    pub state_file: Option<PathBuf>,
+    /// Number of consumed QKD key IDs remembered to detect replays
+    pub replay_cache_size: Option<usize>,
+    /// Chain every output key into the next one within a session; used only if the peer
+    /// enables it too
+    #[serde(default)]
+    pub ratchet: bool,
+    /// Our ML-KEM secret key, generated using `daisyway gen-kem-keys`
+    pub kem_secret_key_file: Option<PathBuf>,
+    /// The peer's ML-KEM public key; enables the hybrid post-quantum key exchange
+    pub peer_kem_public_key_file: Option<PathBuf>,
 }
 
 pub struct Daisyway {
-    pub participant: DaisywayTcpParticipant<OskDeadman, String>,
+    pub participants: Vec<DaisywayParticipant>,
+    /// Starts and stops the participants instead, if the peers are discovered at runtime
+    discovery: Option<PeerDiscovery>,
+    /// Closed on shutdown
+    key_sources: Vec<Arc<KeySourceSet<AnyKeySource>>>,
+}
+
+/// A client connecting to a single peer, or a server for all peers sharing a `listen` address
+pub struct DaisywayParticipant {
+    /// The WireGuard public keys of the peers, for log messages
+    pub name: String,
+    pub participant: DaisywayTcpParticipant<OskDeadman, String, AnyKeySource>,
 }
 
 impl DaisywayConfig {
@@ -59,25 +136,76 @@ impl DaisywayConfig {
         log::info!("CONFIG FILE: {cfg}");
         Ok(toml::from_str(&cfg)?)
     }
+
+    /// All configured peers together with their WireGuard public keys
+    fn peers(&self) -> Result<Vec<(&PeerConfig, &str)>> {
+        let peers = match (&self.peer, self.peers.is_empty()) {
+            (Some(_), false) => {
+                bail!("The peer and peers configuration options can not be used together")
+            }
+            (None, true) => {
+                bail!("You need to specify either a [peer] section or [[peers]] entries")
+            }
+            (Some(peer), true) => {
+                let remote_peer_id = peer
+                    .remote_peer_id
+                    .as_ref()
+                    .or(self.wireguard.remote_peer_id.as_ref())
+                    .context(
+                        "You need to specify the wireguard.peer_public_key configuration option",
+                    )?;
+                vec![(peer, remote_peer_id.as_str())]
+            }
+            (None, false) => {
+                ensure!(
+                    self.wireguard.remote_peer_id.is_none(),
+                    "The wireguard.peer_public_key configuration option can not be used with [[peers]]; specify peer_public_key for every peer instead"
+                );
+                self.peers
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, peer)| {
+                        let remote_peer_id = peer.remote_peer_id.as_ref().with_context(|| {
+                            format!("You need to specify peer_public_key for peer #{idx}")
+                        })?;
+                        Ok((peer, remote_peer_id.as_str()))
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            }
+        };
+
+        ensure!(
+            self.outfile.is_none() || peers.len() == 1,
+            "The outfile.path configuration option can only be used with a single peer; specify outfile for every peer instead"
+        );
+        let mut remote_peer_ids = HashSet::new();
+        for (_, remote_peer_id) in &peers {
+            ensure!(
+                remote_peer_ids.insert(*remote_peer_id),
+                "WireGuard peer {remote_peer_id} is configured more than once"
+            );
+        }
+        // Without a PSK of its own, a client could pose as any other peer on the same listener
+        for (peer, remote_peer_id) in &peers {
+            let DaisywayTcpParticipantConfig::Server { listen } = &peer.participant else {
+                continue;
+            };
+            let shared = peers.iter().any(|(other, other_id)| {
+                other_id != remote_peer_id
+                    && matches!(&other.participant, DaisywayTcpParticipantConfig::Server { listen: other_listen } if other_listen == listen)
+            });
+            ensure!(
+                !shared || peer.psk_file.is_some(),
+                "WireGuard peer {remote_peer_id} shares listen address {listen} with other peers, so you need to specify psk_file for it"
+            );
+        }
+        Ok(peers)
+    }
 }
 
 impl Daisyway {
     pub async fn from_config(cfg: &DaisywayConfig) -> Result<Self> {
-        let rekey_interval = cfg.etsi014.interval_secs.unwrap_or(REKEY_INTERVAL);
-        info!("Rekey interval: {rekey_interval}s");
-
-        let psk = cfg
-            .peer
-            .psk_file
-            .as_ref()
-            .map(|file| {
-                info!("Loading PSK file from {file:?}");
-                load_base64_key_file(file).context("Could not load PSK file from {file:?}")
-            })
-            .unwrap_or_else(|| {
-                info!("No PSK file supplied. Using zero PSK.");
-                Ok(Key::new_zeroed())
-            })?;
+        let peers = cfg.peers()?;
 
         let local_peer_id =
             base64_to_key(cfg.wireguard.local_peer_id.as_bytes()).with_context(|| {
@@ -87,66 +215,501 @@ impl Daisyway {
                 )
             })?;
 
-        let remote_peer_id =
-            base64_to_key(cfg.wireguard.remote_peer_id.as_bytes()).with_context(|| {
-                format!(
-                    "Could not decode WireGuard remote peer id {:?}",
-                    cfg.wireguard.remote_peer_id
-                )
-            })?;
+        if cfg.wireguard.discover_peers {
+            ensure!(
+                cfg.peer.is_none(),
+                "The wireguard.discover_peers configuration option requires [[peers]] entries instead of a [peer] section"
+            );
+            ensure!(
+                cfg.wireguard.interface.is_some(),
+                "The wireguard.discover_peers configuration option requires the wireguard.interface configuration option"
+            );
+            ensure!(
+                cfg.outfile.is_none(),
+                "The wireguard.discover_peers and outfile.path configuration options can not be used together"
+            );
+        }
 
-        let protocol_params = DaisywayProtocolParameters {
-            psk,
+        let uapi_socket = match (&cfg.wireguard.interface, &cfg.wireguard.uapi_socket) {
+            (_, Some(socket_path)) => Some(socket_path.clone()),
+            (Some(interface), None) if cfg.wireguard.userspace => {
+                Some(UserspaceWireGuardOskHandler::default_socket_path(interface))
+            }
+            (None, None) if cfg.wireguard.userspace => {
+                bail!("The wireguard.userspace configuration option requires the wireguard.interface configuration option")
+            }
+            (_, None) => None,
+        };
+        #[cfg(not(target_os = "linux"))]
+        ensure!(
+            !cfg.wireguard.discover_peers || uapi_socket.is_some(),
+            "Discovering peers is only supported on Linux, unless the wireguard.userspace configuration option is used."
+        );
+
+        let remote_sae_ids: Vec<_> = peers
+            .iter()
+            .map(|(peer, _)| peer.remote_sae_id.as_deref())
+            .collect();
+        let key_sources = KeySourceSet::for_peers(&cfg.etsi014, &cfg.etsi004, &remote_sae_ids)?;
+        let key_sources: Vec<_> = key_sources.into_iter().map(Arc::new).collect();
+        info!("Using {} QKD key source(s)", key_sources[0].len());
+
+        // The peers share the HTTP clients, so watching the key sources of one peer suffices
+        let tls_watcher = key_sources[0].clone().watch_tls(TLS_RELOAD_CHECK_INTERVAL);
+        tokio::spawn(async move {
+            if let Err(err) = tls_watcher.await {
+                warn!("TLS files will not be reloaded: {err:?}");
+            }
+        });
+
+        #[cfg(target_os = "linux")]
+        let wg_socket = match (&cfg.wireguard.interface, &cfg.outfile) {
+            (Some(_), None) if uapi_socket.is_none() => {
+                Some(crate::internal::osk::WireGuardOskHandler::connect()?)
+            }
+            _ => None,
+        };
+        #[cfg(not(target_os = "linux"))]
+        let wg_socket = None;
+
+        // Rekey no faster than the slowest key source can deliver keys
+        let default_rekey_interval = cfg
+            .etsi014
+            .as_slice()
+            .iter()
+            .map(|source| source.interval_secs)
+            .chain(
+                cfg.etsi004
+                    .as_slice()
+                    .iter()
+                    .map(|source| source.interval_secs),
+            )
+            .flatten()
+            .max()
+            .unwrap_or(REKEY_INTERVAL);
+
+        let ctx = PeerSetupContext {
             local_peer_id,
-            remote_peer_id,
+            default_rekey_interval,
+            interface: cfg.wireguard.interface.clone(),
+            outfile: cfg.outfile.as_ref().map(|outfile| outfile.path.clone()),
+            wg_socket,
+            uapi_socket,
         };
+        let entries: Vec<_> = peers
+            .into_iter()
+            .zip(key_sources.clone())
+            .map(|((peer, remote_peer_id), key_sources)| PeerEntry {
+                config: peer.clone(),
+                remote_peer_id: remote_peer_id.to_owned(),
+                key_sources,
+            })
+            .collect();
+
+        if cfg.wireguard.discover_peers {
+            for entry in &entries {
+                spawn_status_monitor(entry);
+            }
+            let interval = cfg
+                .wireguard
+                .discovery_interval_secs
+                .map(Duration::from_secs)
+                .unwrap_or(PEER_DISCOVERY_INTERVAL);
+            return Ok(Self {
+                participants: Vec::new(),
+                discovery: Some(PeerDiscovery::new(ctx, entries, interval)),
+                key_sources,
+            });
+        }
+
+        // With several peers, one failing peer must not keep the others from working
+        let isolate_failures = entries.len() > 1;
+        let mut server_peers = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            let res = setup_peer(&ctx, entry)
+                .await
+                .with_context(|| format!("Could not set up peer {}", entry.remote_peer_id));
+            match res {
+                Ok(server_peer) => {
+                    spawn_status_monitor(entry);
+                    server_peers.push((entry, server_peer));
+                }
+                Err(err) if isolate_failures => error!("{err:?}"),
+                Err(err) => return Err(err),
+            }
+        }
+        let participants = group_participants(server_peers);
+        ensure!(
+            !participants.is_empty(),
+            "None of the peers could be set up"
+        );
+
+        Ok(Self {
+            participants,
+            discovery: None,
+            key_sources,
+        })
+    }
+
+    /// Run until all peers stopped or a SIGINT or SIGTERM is received
+    ///
+    /// On a signal, the key sources are closed before returning.
+    pub async fn event_loop(mut self) -> Result<()> {
+        let key_sources = std::mem::take(&mut self.key_sources);
+        let mut interrupt = signal(SignalKind::interrupt()).context("Failed to handle SIGINT")?;
+        let mut terminate = signal(SignalKind::terminate()).context("Failed to handle SIGTERM")?;
+        tokio::select! {
+            res = self.run() => return res,
+            _ = interrupt.recv() => info!("Received SIGINT, shutting down"),
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        }
+        for sources in key_sources {
+            sources.close().await;
+        }
+        Ok(())
+    }
 
-        let etsi_client = Arc::new(Etsi014Connection::from_config(&cfg.etsi014)?);
+    async fn run(mut self) -> Result<()> {
+        if let Some(discovery) = self.discovery {
+            return discovery.event_loop().await;
+        }
 
-        let osk_handler = match (&cfg.wireguard.interface, &cfg.outfile) {
-            (None, None) => bail!("You need to specify either the wireguard.interface or outfile.path configuration option"),
-            (Some(_), Some(_)) => bail!("You can not specify both the wireguard.interface and outfile.path configuration options"),
-            (None, Some(OutfileConfig { path })) => {
+        if self.participants.len() == 1 {
+            return self.participants[0].participant.event_loop().await;
+        }
+
+        let mut tasks = JoinSet::new();
+        for participant in self.participants {
+            tasks.spawn(participant.event_loop());
+        }
+        while let Some(res) = tasks.join_next().await {
+            if let Err(err) = res {
+                error!("Peer task failed: {err:?}");
+            }
+        }
+        bail!("All peers stopped")
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(super) type SharedWgSocket = Arc<std::sync::Mutex<wireguard_uapi::WgSocket>>;
+
+/// Placeholder, since WireGuard can only be accessed directly on Linux
+#[cfg(not(target_os = "linux"))]
+pub(super) type SharedWgSocket = ();
+
+/// Settings shared by all peers
+pub(super) struct PeerSetupContext {
+    local_peer_id: Key,
+    /// Rekey interval of the peers without `interval_secs`
+    default_rekey_interval: u64,
+    pub interface: Option<String>,
+    /// The global `[outfile]`
+    outfile: Option<String>,
+    pub wg_socket: Option<SharedWgSocket>,
+    /// Set if WireGuard runs in userspace
+    pub uapi_socket: Option<PathBuf>,
+}
+
+/// A configured peer together with its key sources
+pub(super) struct PeerEntry {
+    pub config: PeerConfig,
+    pub remote_peer_id: String,
+    pub key_sources: Arc<KeySourceSet<AnyKeySource>>,
+}
+
+/// Load the keys and state of a peer and set up its OSK handler
+pub(super) async fn setup_peer(
+    ctx: &PeerSetupContext,
+    entry: &PeerEntry,
+) -> Result<DaisywayServerPeer<OskDeadman, AnyKeySource>> {
+    let peer = &entry.config;
+    let remote_peer_id = entry.remote_peer_id.as_str();
+    let key_sources = entry.key_sources.clone();
+
+    let rekey_interval = peer.interval_secs.unwrap_or(ctx.default_rekey_interval);
+    info!("Rekey interval for peer {remote_peer_id}: {rekey_interval}s");
+
+    let psk = peer
+        .psk_file
+        .as_ref()
+        .map(|file| {
+            info!("Loading PSK file from {file:?}");
+            load_base64_key_file(file).context("Could not load PSK file from {file:?}")
+        })
+        .unwrap_or_else(|| {
+            info!("No PSK file supplied. Using zero PSK.");
+            Ok(Key::new_zeroed())
+        })?;
+
+    let remote_peer_id_key = base64_to_key(remote_peer_id.as_bytes())
+        .with_context(|| format!("Could not decode WireGuard remote peer id {remote_peer_id:?}"))?;
+
+    let mut features = ProtocolFeatures::NONE;
+    if peer.ratchet {
+        info!("Offering the key ratchet to the peer");
+        features = features.union(ProtocolFeatures::RATCHET);
+    }
+
+    let kem = match (&peer.kem_secret_key_file, &peer.peer_kem_public_key_file) {
+        (None, None) => None,
+        (Some(secret_key_file), Some(public_key_file)) => {
+            info!(
+                "Enabling the hybrid post-quantum key exchange; this is required from the peer too"
+            );
+            features = features.union(ProtocolFeatures::PQ_KEM);
+            Some(load_kem_keys(secret_key_file, public_key_file)?)
+        }
+        _ => bail!(
+            "The peer.kem_secret_key_file and peer.peer_kem_public_key_file configuration \
+            options must be specified together"
+        ),
+    };
+
+    let protocol_params = DaisywayProtocolParameters {
+        psk,
+        local_peer_id: ctx.local_peer_id,
+        remote_peer_id: remote_peer_id_key,
+        features,
+        kem,
+    };
+
+    let replay_cache_size = peer.replay_cache_size.unwrap_or(DEFAULT_REPLAY_CACHE_SIZE);
+    let state = match &peer.state_file {
+        Some(file) => {
+            info!("Loading state file from {file:?}");
+            StateStore::load(file, replay_cache_size)?
+        }
+        None => {
+            info!("No state file supplied. State will not persist across restarts.");
+            StateStore::in_memory(replay_cache_size)
+        }
+    };
+    let state = Arc::new(state);
+
+    key_sources
+        .check_status()
+        .await
+        .context("Incompatible QKD key source")?;
+
+    let outfile = peer.outfile.as_ref().or(ctx.outfile.as_ref());
+    let osk_handler = match (&ctx.interface, outfile, &ctx.uapi_socket) {
+            (None, None, _) => bail!("You need to specify either the wireguard.interface or outfile.path configuration option"),
+            (Some(_), Some(_), _) if peer.outfile.is_none() => bail!("You can not specify both the wireguard.interface and outfile.path configuration options"),
+            (_, Some(path), _) => {
                 info!("Using Outfile as key handler, storing key in {path:?}",);
                 start_deadman(OutfileOskHandler::new(path), rekey_interval)
             },
+            (Some(_), None, Some(socket_path)) => {
+                info!(
+                    "Using userspace WireGuard as key handler injecting PSK through {socket_path:?} for peer {remote_peer_id}",
+                );
+                start_deadman(
+                    UserspaceWireGuardOskHandler::setup(socket_path, remote_peer_id)
+                        .await
+                        .context("Could start userspace WireGuard key handler")?,
+                    rekey_interval
+                )
+            },
             #[cfg(not(target_os = "linux"))]
-            (Some(_), None) => {
-                bail!("Directly interfacing with WireGuard is only supported on Linux. Please use the outfile configuration option instead.");
+            (Some(_), None, None) => {
+                bail!("Directly interfacing with WireGuard is only supported on Linux. Please use the wireguard.userspace or outfile configuration option instead.");
             },
             #[cfg(target_os = "linux")]
-            (Some(interface), None) => {
-                let peer = &cfg.wireguard.remote_peer_id;
+            (Some(interface), None, None) => {
                 info!(
-                    "Using WireGuard as key handler injecting PSK into interface {interface} for peer {peer}",
+                    "Using WireGuard as key handler injecting PSK into interface {interface} for peer {remote_peer_id}",
                 );
+                let socket = ctx.wg_socket.clone().context("WireGuard control socket is not connected")?;
                 start_deadman(
-                    crate::internal::osk::WireGuardOskHandler::setup(peer, interface)
+                    crate::internal::osk::WireGuardOskHandler::setup(socket, remote_peer_id, interface)
                         .context("Could start WireGuard key handler")?,
                     rekey_interval
                 )
             },
         };
 
-        let participant = DaisywayTcpParticipant::from_config(
-            protocol_params,
-            &cfg.peer.participant,
-            etsi_client,
-            osk_handler,
-            rekey_interval,
-        );
+    Ok(DaisywayServerPeer {
+        protocol_params,
+        key_sources,
+        osk_handler,
+        state,
+        rekey_interval,
+    })
+}
 
-        Ok(Self { participant })
+fn spawn_status_monitor(entry: &PeerEntry) {
+    tokio::spawn(
+        entry
+            .key_sources
+            .clone()
+            .monitor_status(KEY_SOURCE_STATUS_INTERVAL),
+    );
+}
+
+/// Turn the peers into participants: a client per peer, and a server per `listen` address
+///
+/// The participants are named after their peers, in the order of the configuration.
+pub(super) fn group_participants(
+    peers: Vec<(&PeerEntry, DaisywayServerPeer<OskDeadman, AnyKeySource>)>,
+) -> Vec<DaisywayParticipant> {
+    let mut participants = Vec::with_capacity(peers.len());
+    let mut servers: Vec<(&str, Vec<&str>, Vec<DaisywayServerPeer<_, _>>)> = Vec::new();
+    for (entry, server_peer) in peers {
+        let remote_peer_id = entry.remote_peer_id.as_str();
+        match &entry.config.participant {
+            DaisywayTcpParticipantConfig::Client { endpoint } => {
+                let DaisywayServerPeer {
+                    protocol_params,
+                    key_sources,
+                    osk_handler,
+                    state,
+                    ..
+                } = server_peer;
+                participants.push(DaisywayParticipant {
+                    name: remote_peer_id.to_owned(),
+                    participant: DaisywayTcpParticipant::Client(DaisywayTcpClient::new(
+                        protocol_params,
+                        endpoint.clone(),
+                        key_sources,
+                        osk_handler,
+                        state,
+                    )),
+                });
+            }
+            DaisywayTcpParticipantConfig::Server { listen } => {
+                match servers.iter_mut().find(|(addr, _, _)| addr == listen) {
+                    Some((_, names, server_peers)) => {
+                        names.push(remote_peer_id);
+                        server_peers.push(server_peer);
+                    }
+                    None => servers.push((listen, vec![remote_peer_id], vec![server_peer])),
+                }
+            }
+        }
+    }
+    for (listen, names, server_peers) in servers {
+        if server_peers.len() > 1 {
+            info!("Serving peers {} on {listen}", names.join(", "));
+        }
+        participants.push(DaisywayParticipant {
+            name: names.join(", "),
+            participant: DaisywayTcpParticipant::Server(DaisywayTcpServer::new(
+                listen.to_owned(),
+                server_peers,
+            )),
+        });
     }
+    participants
+}
 
-    pub async fn event_loop(&mut self) -> Result<()> {
-        self.participant.event_loop().await
+impl DaisywayParticipant {
+    /// Run the event loop, restarting it whenever it fails; runs forever
+    pub(super) async fn event_loop(mut self) {
+        loop {
+            if let Err(err) = self.participant.event_loop().await {
+                error!("Peer {} failed: {err:?}", self.name);
+            }
+            info!(
+                "Restarting peer {} in {}s",
+                self.name,
+                PEER_RESTART_DELAY.as_secs()
+            );
+            tokio::time::sleep(PEER_RESTART_DELAY).await;
+        }
     }
 }
 
+fn load_kem_keys(secret_key_file: &Path, public_key_file: &Path) -> Result<KemKeys> {
+    info!("Loading ML-KEM secret key from {secret_key_file:?}");
+    let seed: ml_kem::Seed = load_base64_file(secret_key_file)
+        .with_context(|| format!("Could not load ML-KEM secret key from {secret_key_file:?}"))?;
+    let (secret_key, _) = ml_kem::keypair_from_seed(&seed);
+
+    info!("Loading the peer's ML-KEM public key from {public_key_file:?}");
+    let peer_public_key: ml_kem::EncapsulationKey = load_base64_file(public_key_file)
+        .with_context(|| format!("Could not load ML-KEM public key from {public_key_file:?}"))?;
+    ml_kem::validate_encapsulation_key(&peer_public_key)
+        .with_context(|| format!("Invalid ML-KEM public key in {public_key_file:?}"))?;
+
+    Ok(KemKeys {
+        secret_key: Arc::new(secret_key),
+        peer_public_key: Arc::new(peer_public_key),
+    })
+}
+
+/// Generate a fresh ML-KEM key pair and write it to the given files
+pub fn generate_kem_keys(secret_key_file: &Path, public_key_file: &Path) -> Result<()> {
+    let seed = ml_kem::generate_seed();
+    let (_, public_key) = ml_kem::keypair_from_seed(&seed);
+    store_base64_file(secret_key_file, &seed)
+        .with_context(|| format!("Could not write ML-KEM secret key to {secret_key_file:?}"))?;
+    store_base64_file(public_key_file, &public_key)
+        .with_context(|| format!("Could not write ML-KEM public key to {public_key_file:?}"))?;
+    Ok(())
+}
+
 fn start_deadman<O>(o: O, rekey_interval: u64) -> OskDeadman
 where
     O: OskHandler + std::fmt::Debug + Send + 'static,
 {
     OskDeadman::start(Duration::from_secs(rekey_interval + 30), move || o)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(peers: &str) -> DaisywayConfig {
+        toml::from_str(&format!(
+            r#"
+            [etsi014]
+            url = "http://localhost:12345"
+            remote_sae_id = "SAE_002"
+
+            [wireguard]
+            self_public_key = "LOCAL"
+
+            {peers}
+            "#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn peers_sharing_a_listener_need_a_psk_file() {
+        let cfg = config(
+            r#"
+            [[peers]]
+            listen = "0.0.0.0:5556"
+            peer_public_key = "BOB"
+            psk_file = "psk_bob.key"
+            [[peers]]
+            listen = "0.0.0.0:5556"
+            peer_public_key = "CAROL"
+            "#,
+        );
+        let err = cfg.peers().unwrap_err();
+        assert!(err.to_string().contains("CAROL"), "{err}");
+    }
+
+    #[test]
+    fn peers_with_psk_files_may_share_a_listener() {
+        let cfg = config(
+            r#"
+            [[peers]]
+            listen = "0.0.0.0:5556"
+            peer_public_key = "BOB"
+            psk_file = "psk_bob.key"
+            [[peers]]
+            listen = "0.0.0.0:5556"
+            peer_public_key = "CAROL"
+            psk_file = "psk_carol.key"
+            [[peers]]
+            listen = "0.0.0.0:5557"
+            peer_public_key = "DAVE"
+            "#,
+        );
+        assert_eq!(cfg.peers().unwrap().len(), 3);
+    }
+}
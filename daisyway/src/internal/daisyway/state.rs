@@ -0,0 +1,237 @@
+//! Persistent state of a daisyway instance, surviving restarts
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{ensure, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::internal::{daisyway::replay_cache::ReplayCache, util::SomeExt};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PersistentState {
+    /// Number of successful rekeys; never decreases
+    pub epoch: u64,
+    /// QKD key IDs used in the most recent rekeys
+    pub used_key_ids: ReplayCache,
+    /// Unix timestamp (in seconds) of the last successful rekey
+    pub last_success: Option<u64>,
+}
+
+impl PersistentState {
+    /// Time since the last successful rekey, if there ever was one
+    pub fn time_since_last_success(&self) -> Option<Duration> {
+        let last_success = UNIX_EPOCH + Duration::from_secs(self.last_success?);
+        SystemTime::now()
+            .duration_since(last_success)
+            .unwrap_or_default()
+            .some()
+    }
+}
+
+/// Shared access to the [PersistentState], optionally backed by a state file
+///
+/// Without a state file, the state is kept in memory only and lost on restart.
+#[derive(Debug)]
+pub struct StateStore {
+    path: Option<PathBuf>,
+    state: Mutex<PersistentState>,
+    /// Serializes writes to the state file, so an older state never overwrites a newer one
+    write_lock: Mutex<()>,
+}
+
+impl StateStore {
+    pub fn in_memory(replay_cache_size: usize) -> Self {
+        let mut state = PersistentState::default();
+        state.used_key_ids.set_capacity(replay_cache_size);
+        Self {
+            path: None,
+            state: Mutex::new(state),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Load the state from the given file; a missing file is treated as empty state
+    pub fn load<P: AsRef<Path>>(path: P, replay_cache_size: usize) -> Result<Self> {
+        let path = path.as_ref();
+        let mut state: PersistentState = match std::fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data)
+                .with_context(|| format!("Failed to parse state file {path:?}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("State file {path:?} does not exist yet; starting with empty state");
+                PersistentState::default()
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read state file {path:?}")),
+        };
+        state.used_key_ids.set_capacity(replay_cache_size);
+
+        let store = Self {
+            path: Some(path.to_owned()),
+            state: Mutex::new(state),
+            write_lock: Mutex::new(()),
+        };
+        store.log_summary();
+        Ok(store)
+    }
+
+    pub fn snapshot(&self) -> PersistentState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    /// Report the resumed epoch and how long the tunnel has gone without a fresh key
+    pub fn log_summary(&self) {
+        let state = self.snapshot();
+        match state.time_since_last_success() {
+            Some(age) => info!(
+                "Resuming at rekey epoch {}; the last fresh key was negotiated {}s ago",
+                state.epoch,
+                age.as_secs()
+            ),
+            None => info!(
+                "Resuming at rekey epoch {}; no fresh key has been negotiated yet",
+                state.epoch
+            ),
+        }
+    }
+
+    /// Make sure that the given QKD key has not been used for a previous rekey
+    pub fn ensure_key_unused(&self, key_id: &Uuid) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        let used = state.used_key_ids.contains(key_id);
+        if used {
+            warn!("Replay detected: Refusing to reuse QKD key {key_id}, which has been used for a previous rekey");
+        }
+        ensure!(
+            !used,
+            "QKD key {key_id} has already been used for a previous rekey. \
+            This is either a replay attack or a misbehaving KME."
+        );
+        Ok(())
+    }
+
+    /// Record a successful rekey using the given QKD keys
+    ///
+    /// This only updates the state in memory; use [Self::persist] to write it to the state
+    /// file. Returns the new rekey epoch.
+    pub fn record_rekey(&self, key_ids: &[Uuid]) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        for id in key_ids {
+            state.used_key_ids.insert(*id);
+        }
+        state.last_success = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .ok();
+        state.epoch
+    }
+
+    /// Write the current state to the state file, if there is one
+    ///
+    /// The file is written and synced on the blocking thread pool, so the runtime is not
+    /// stalled by slow storage.
+    pub async fn persist(self: &Arc<Self>) -> Result<()> {
+        if self.path.is_none() {
+            return Ok(());
+        }
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || store.persist_blocking())
+            .await
+            .context("State file writer panicked")?
+    }
+
+    fn persist_blocking(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let _guard = self.write_lock.lock().unwrap();
+        let state = self.snapshot();
+        write_atomically(path, &state)
+            .with_context(|| format!("Failed to write state file {path:?}"))
+    }
+}
+
+/// Write the state to a temporary file and move it into place, so the state file is never
+/// left in a partially written state
+fn write_atomically(path: &Path, state: &PersistentState) -> Result<()> {
+    let mut tmp_path = OsString::from(path.as_os_str());
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let data = serde_json::to_vec_pretty(state)?;
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&tmp_path, path)?;
+
+    // Make sure the rename itself is durable
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state file in the temporary directory that is unique to this test and process
+    fn state_file_path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("daisyway-state-{test}-{}.json", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn persisted_state_survives_reload() {
+        let path = state_file_path("survives_reload");
+        let store = Arc::new(StateStore::load(&path, 16).unwrap());
+        let key_id = Uuid::from_u128(1);
+
+        assert_eq!(store.record_rekey(&[key_id]), 1);
+        store.persist().await.unwrap();
+
+        let reloaded = StateStore::load(&path, 16).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.snapshot(), store.snapshot());
+        assert!(reloaded.ensure_key_unused(&key_id).is_err());
+    }
+
+    #[test]
+    fn state_file_with_request_counter_is_loaded() {
+        let path = state_file_path("request_counter");
+        let id = Uuid::from_u128(1);
+        std::fs::write(
+            &path,
+            format!(r#"{{"epoch": 5, "used_key_ids": ["{id}"], "last_success": null, "last_counter": 7}}"#),
+        )
+        .unwrap();
+        let store = StateStore::load(&path, 16).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(store.epoch(), 5);
+        assert!(store.ensure_key_unused(&id).is_err());
+    }
+
+    #[tokio::test]
+    async fn in_memory_state_is_not_written() {
+        let store = Arc::new(StateStore::in_memory(16));
+        store.record_rekey(&[Uuid::from_u128(1)]);
+        store.persist().await.unwrap();
+        assert_eq!(store.epoch(), 1);
+    }
+}
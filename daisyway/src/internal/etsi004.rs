@@ -0,0 +1,726 @@
+//! Client for the ETSI GS QKD 004 application interface
+//!
+//! ETSI 004 defines the OPEN_CONNECT, GET_KEY and CLOSE calls, but leaves the transport to the
+//! implementation. We exchange one JSON object per line over TLS; e.g.
+//! `{"command":"GET_KEY","key_stream_id":"…","index":null}`. Plain TCP is only used if
+//! `danger_allow_plaintext` is set, since the keys would cross the network in cleartext.
+//!
+//! Both peers open the same, preconfigured key stream. A key is identified by its index within
+//! that stream; see [Etsi004Connection::key_id] for the key IDs sent in rekey requests.
+
+use std::{
+    sync::{Arc, Mutex as SyncMutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64ct::{Base64, Encoding};
+use log::{debug, info, warn};
+use rustls::{pki_types::ServerName, ClientConfig};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tokio_rustls::TlsConnector;
+use uuid::{Builder, Uuid};
+use zeroize::Zeroizing;
+
+use crate::internal::{
+    daisyway::crypto::KEY_LENGTH,
+    key_source::{QkdKey, QkdKeySource},
+    tls::TlsOptions,
+};
+
+/// Time the KME has to answer a single call, unless overridden by the QoS timeout
+const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound on the size of a single response line
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Etsi004Config {
+    /// Address and port of the application interface of the KME
+    address: String,
+    /// URI identifying this application towards the KME
+    source: String,
+    /// URI identifying the peer application
+    destination: String,
+    /// Key stream shared by both peers; both must use the same ID
+    key_stream_id: Uuid,
+    pub interval_secs: Option<u64>,
+    #[serde(default)]
+    qos: Etsi004Qos,
+    /// Name expected in the certificate of the KME; defaults to the host of `address`
+    tls_server_name: Option<String>,
+    #[serde(flatten)]
+    tls: TlsOptions,
+    /// Talk to the KME over plain TCP, exposing the keys to anyone on the network path
+    #[serde(default)]
+    danger_allow_plaintext: bool,
+}
+
+/// Quality of service parameters passed to OPEN_CONNECT
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Etsi004Qos {
+    /// Size of each key in bytes
+    #[serde(default = "default_key_chunk_size")]
+    key_chunk_size: u32,
+    max_bps: Option<u32>,
+    min_bps: Option<u32>,
+    jitter: Option<u32>,
+    priority: Option<u32>,
+    /// Maximum time in milliseconds the KME may take to answer a call
+    timeout: Option<u32>,
+    /// Time in seconds after which the key stream expires
+    ttl: Option<u32>,
+}
+
+fn default_key_chunk_size() -> u32 {
+    KEY_LENGTH as u32
+}
+
+impl Default for Etsi004Qos {
+    fn default() -> Self {
+        Self {
+            key_chunk_size: default_key_chunk_size(),
+            max_bps: None,
+            min_bps: None,
+            jitter: None,
+            priority: None,
+            timeout: None,
+            ttl: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "command", rename_all = "SCREAMING_SNAKE_CASE")]
+enum Request<'a> {
+    OpenConnect {
+        source: &'a str,
+        destination: &'a str,
+        qos: &'a Etsi004Qos,
+        key_stream_id: Uuid,
+    },
+    GetKey {
+        key_stream_id: Uuid,
+        /// Absent to request the next unused key
+        index: Option<u32>,
+    },
+    Close {
+        key_stream_id: Uuid,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct Response {
+    status: u32,
+    key_stream_id: Option<Uuid>,
+    key_buffer: Option<String>,
+    index: Option<u32>,
+}
+
+/// Status codes defined by ETSI GS QKD 004
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Etsi004Status {
+    Successful,
+    PeerNotConnected,
+    InsufficientKeyAvailable,
+    PeerNotConnectedGetKey,
+    NoQkdConnectionAvailable,
+    KeyStreamIdInUse,
+    Timeout,
+    QosNotMet,
+    MetadataSizeInsufficient,
+    Unknown(u32),
+}
+
+impl From<u32> for Etsi004Status {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Successful,
+            1 => Self::PeerNotConnected,
+            2 => Self::InsufficientKeyAvailable,
+            3 => Self::PeerNotConnectedGetKey,
+            4 => Self::NoQkdConnectionAvailable,
+            5 => Self::KeyStreamIdInUse,
+            6 => Self::Timeout,
+            7 => Self::QosNotMet,
+            8 => Self::MetadataSizeInsufficient,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl std::fmt::Display for Etsi004Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Successful => write!(f, "successful"),
+            Self::PeerNotConnected => write!(f, "successful, but the peer is not connected"),
+            Self::InsufficientKeyAvailable => write!(f, "insufficient key available"),
+            Self::PeerNotConnectedGetKey => write!(f, "the peer application is not connected"),
+            Self::NoQkdConnectionAvailable => write!(f, "no QKD connection available"),
+            Self::KeyStreamIdInUse => write!(f, "the key stream ID is already in use"),
+            Self::Timeout => write!(f, "timeout"),
+            Self::QosNotMet => write!(f, "the requested QoS settings could not be met"),
+            Self::MetadataSizeInsufficient => write!(f, "insufficient metadata field size"),
+            Self::Unknown(status) => write!(f, "unknown status {status}"),
+        }
+    }
+}
+
+/// A TLS or, if explicitly allowed, plain TCP connection to the KME
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Transport for T {}
+
+/// A connection to the KME with an open key stream
+struct Session {
+    stream: BufReader<Box<dyn Transport>>,
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session").finish_non_exhaustive()
+    }
+}
+
+impl Session {
+    async fn call(&mut self, request: &Request<'_>, timeout: Duration) -> Result<Response> {
+        tokio::time::timeout(timeout, self.call_without_timeout(request))
+            .await
+            .context("ETSI 004 call timed out")?
+    }
+
+    async fn call_without_timeout(&mut self, request: &Request<'_>) -> Result<Response> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        let stream = self.stream.get_mut();
+        stream.write_all(&line).await?;
+        stream.flush().await?;
+
+        // The response carries key material
+        let mut line = Zeroizing::new(String::new());
+        (&mut self.stream)
+            .take(MAX_RESPONSE_LEN)
+            .read_line(&mut line)
+            .await?;
+        ensure!(
+            line.ends_with('\n'),
+            "ETSI 004 response is truncated or exceeds {MAX_RESPONSE_LEN} bytes"
+        );
+        serde_json::from_str(&line).context("Failed to parse ETSI 004 response")
+    }
+}
+
+/// TLS settings of the connection to the KME
+#[derive(Debug)]
+struct Etsi004Tls {
+    options: TlsOptions,
+    server_name: ServerName<'static>,
+    /// Replaced when the TLS files change; open sessions keep their connection
+    config: RwLock<Arc<ClientConfig>>,
+    /// Modification times of the TLS files the current config was built from
+    files_modified: SyncMutex<Vec<Option<SystemTime>>>,
+}
+
+#[derive(Debug)]
+pub struct Etsi004Connection {
+    address: String,
+    source: String,
+    destination: String,
+    key_stream_id: Uuid,
+    qos: Etsi004Qos,
+    /// Absent if plain TCP was explicitly allowed
+    tls: Option<Etsi004Tls>,
+    /// Opened on first use and reopened after connection errors
+    session: Mutex<Option<Session>>,
+}
+
+impl Etsi004Connection {
+    pub fn from_config(config: &Etsi004Config) -> Result<Self> {
+        ensure!(
+            config.qos.key_chunk_size > 0,
+            "ETSI 004 key_chunk_size must not be zero"
+        );
+
+        let tls = if config.danger_allow_plaintext {
+            ensure!(
+                !config.tls.can_verify_servers() && config.tls_server_name.is_none(),
+                "danger_allow_plaintext can not be combined with TLS settings"
+            );
+            warn!(
+                "Talking to ETSI 004 KME {} over plain TCP; keys are transmitted unencrypted",
+                config.address
+            );
+            None
+        } else {
+            ensure!(
+                config.tls.can_verify_servers(),
+                "No CA certificates configured for ETSI 004 KME {}; set tls_cacert, tls_system_roots, tls_webpki_roots or tls_pin_only",
+                config.address
+            );
+            let server_name = config
+                .tls_server_name
+                .clone()
+                .unwrap_or_else(|| host_of(&config.address).to_owned());
+            let server_name = ServerName::try_from(server_name)
+                .with_context(|| format!("Invalid TLS server name for {}", config.address))?;
+            Some(Etsi004Tls {
+                files_modified: SyncMutex::new(config.tls.files_modified()),
+                config: RwLock::new(Arc::new(config.tls.client_config()?)),
+                options: config.tls.clone(),
+                server_name,
+            })
+        };
+
+        Ok(Self {
+            address: config.address.clone(),
+            source: config.source.clone(),
+            destination: config.destination.clone(),
+            key_stream_id: config.key_stream_id,
+            qos: config.qos.clone(),
+            tls,
+            session: Mutex::new(None),
+        })
+    }
+
+    fn call_timeout(&self) -> Duration {
+        self.qos
+            .timeout
+            .map(|ms| Duration::from_millis(ms.into()))
+            .unwrap_or(DEFAULT_CALL_TIMEOUT)
+    }
+
+    async fn connect(&self) -> Result<Box<dyn Transport>> {
+        let stream = TcpStream::connect(&self.address)
+            .await
+            .with_context(|| format!("Failed to connect to ETSI 004 KME at {}", self.address))?;
+        let Some(tls) = &self.tls else {
+            return Ok(Box::new(stream));
+        };
+        let connector = TlsConnector::from(tls.config.read().unwrap().clone());
+        let stream = connector
+            .connect(tls.server_name.clone(), stream)
+            .await
+            .with_context(|| format!("TLS handshake with ETSI 004 KME {} failed", self.address))?;
+        Ok(Box::new(stream))
+    }
+
+    async fn open_session(&self) -> Result<Session> {
+        let stream = tokio::time::timeout(self.call_timeout(), self.connect())
+            .await
+            .with_context(|| format!("Connecting to ETSI 004 KME {} timed out", self.address))??;
+        let mut session = Session {
+            stream: BufReader::new(stream),
+        };
+
+        let response = session
+            .call(
+                &Request::OpenConnect {
+                    source: &self.source,
+                    destination: &self.destination,
+                    qos: &self.qos,
+                    key_stream_id: self.key_stream_id,
+                },
+                self.call_timeout(),
+            )
+            .await?;
+        match Etsi004Status::from(response.status) {
+            Etsi004Status::Successful => {}
+            Etsi004Status::PeerNotConnected => {
+                info!("Opened ETSI 004 key stream; the peer has not connected yet")
+            }
+            status => bail!("ETSI 004 OPEN_CONNECT failed: {status}"),
+        }
+        if let Some(id) = response.key_stream_id {
+            ensure!(
+                id == self.key_stream_id,
+                "KME assigned key stream {id} instead of the requested key stream {}",
+                self.key_stream_id
+            );
+        }
+        debug!(
+            "Opened ETSI 004 key stream {} at {}",
+            self.key_stream_id, self.address
+        );
+
+        Ok(session)
+    }
+
+    /// Perform a call, opening the session first if necessary
+    async fn call(&self, request: &Request<'_>) -> Result<Response> {
+        let mut session = self.session.lock().await;
+        if session.is_none() {
+            *session = Some(self.open_session().await?);
+        }
+        let res = session
+            .as_mut()
+            .unwrap()
+            .call(request, self.call_timeout())
+            .await;
+        if res.is_err() {
+            // The connection is in an unknown state; start over with the next call
+            *session = None;
+        }
+        res
+    }
+
+    /// Close the key stream and the connection to the KME
+    pub async fn close(&self) -> Result<()> {
+        let Some(mut session) = self.session.lock().await.take() else {
+            return Ok(());
+        };
+        let response = session
+            .call(
+                &Request::Close {
+                    key_stream_id: self.key_stream_id,
+                },
+                self.call_timeout(),
+            )
+            .await?;
+        let status = Etsi004Status::from(response.status);
+        ensure!(
+            status == Etsi004Status::Successful,
+            "ETSI 004 CLOSE failed: {status}"
+        );
+        debug!(
+            "Closed ETSI 004 key stream {} at {}",
+            self.key_stream_id, self.address
+        );
+        Ok(())
+    }
+
+    /// Rebuild the TLS configuration if the TLS files changed since it was built
+    ///
+    /// An open session keeps its connection; the new files are used for the next one.
+    pub fn reload_tls(&self, force: bool) -> Result<()> {
+        let Some(tls) = &self.tls else {
+            return Ok(());
+        };
+        let modified = tls.options.files_modified();
+        if !force && *tls.files_modified.lock().unwrap() == modified {
+            return Ok(());
+        }
+
+        let config = tls.options.client_config().with_context(|| {
+            format!(
+                "Failed to reload TLS files for ETSI 004 KME {}",
+                self.address
+            )
+        })?;
+        *tls.config.write().unwrap() = Arc::new(config);
+        *tls.files_modified.lock().unwrap() = modified;
+        info!("Reloaded TLS files for ETSI 004 KME {}", self.address);
+        Ok(())
+    }
+
+    async fn get_key(&self, index: Option<u32>) -> Result<QkdKey> {
+        let response = self
+            .call(&Request::GetKey {
+                key_stream_id: self.key_stream_id,
+                index,
+            })
+            .await?;
+        let status = Etsi004Status::from(response.status);
+        ensure!(
+            status == Etsi004Status::Successful,
+            "ETSI 004 GET_KEY failed: {status}"
+        );
+
+        let index = match (index, response.index) {
+            (Some(requested), Some(returned)) => {
+                ensure!(
+                    requested == returned,
+                    "KME returned key {returned} instead of the requested key {requested}"
+                );
+                requested
+            }
+            (Some(requested), None) => requested,
+            (None, Some(returned)) => returned,
+            (None, None) => bail!("KME did not tell the index of the returned key"),
+        };
+
+        let key_buffer = response
+            .key_buffer
+            .context("ETSI 004 GET_KEY response lacks the key buffer")?;
+        let mut key = Zeroizing::new(key_buffer.into_bytes());
+        let len = Base64::decode_in_place(&mut key)
+            .map_err(|e| anyhow::anyhow!(e))
+            .context("Failed to decode ETSI 004 key buffer")?
+            .len();
+        key.truncate(len);
+        ensure!(
+            len == self.qos.key_chunk_size as usize,
+            "ETSI 004 key has {len} bytes instead of {}",
+            self.qos.key_chunk_size
+        );
+
+        Ok(QkdKey {
+            id: self.key_id(index),
+            key,
+        })
+    }
+
+    /// Key ID used in rekey requests for the key at the given index
+    ///
+    /// A UUIDv8 whose first twelve bytes are taken from the SHA-256 hash of the key stream ID,
+    /// followed by the index as big-endian `u32`; the version and variant bits are set as
+    /// required by RFC 9562. Both peers derive the same ID, the ID does not reveal the key
+    /// stream, and, barring hash collisions, keys of different key streams get different IDs.
+    fn key_id(&self, index: u32) -> Uuid {
+        let digest = ring::digest::digest(&ring::digest::SHA256, self.key_stream_id.as_bytes());
+        let mut id = [0u8; 16];
+        id[..12].copy_from_slice(&digest.as_ref()[..12]);
+        id[12..].copy_from_slice(&index.to_be_bytes());
+        Builder::from_custom_bytes(id).into_uuid()
+    }
+
+    /// Inverse of [Self::key_id]
+    fn key_index(&self, id: Uuid) -> Result<u32> {
+        let index = u32::from_be_bytes(id.as_bytes()[12..].try_into()?);
+        ensure!(
+            self.key_id(index) == id,
+            "Key {id} does not belong to the ETSI 004 key stream {}",
+            self.key_stream_id
+        );
+        Ok(index)
+    }
+}
+
+/// Host part of a `host:port` address, without the brackets of IPv6 addresses
+fn host_of(address: &str) -> &str {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+impl QkdKeySource for Etsi004Connection {
+    async fn fetch_any_key(&self) -> Result<QkdKey> {
+        self.get_key(None)
+            .await
+            .context("Error fetching next key from ETSI 004 key stream.")
+    }
+
+    async fn fetch_specific_key(&self, id: Uuid) -> Result<QkdKey> {
+        let index = self.key_index(id)?;
+        self.get_key(Some(index))
+            .await
+            .with_context(|| format!("Error fetching key {index} from ETSI 004 key stream."))
+    }
+
+    /// Opens the key stream, so a misconfigured KME is noticed at startup
+    async fn check_status(&self) -> Result<()> {
+        let mut session = self.session.lock().await;
+        if session.is_none() {
+            *session = Some(self.open_session().await.with_context(|| {
+                format!("Failed to open ETSI 004 key stream at {}", self.address)
+            })?);
+        }
+        Ok(())
+    }
+
+    fn reload_tls(&self, force: bool) -> Result<()> {
+        Etsi004Connection::reload_tls(self, force)
+    }
+
+    async fn close(&self) -> Result<()> {
+        Etsi004Connection::close(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Mutex,
+    };
+
+    use rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    };
+    use rustls_pki_types::pem::PemObject;
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+
+    const KEY_STREAM_ID: &str = "6f1e0a52-8a0e-4f5c-9d5e-3b1f2c7a9e10";
+
+    /// Path of a fixture generated by `testdata/tls/generate.sh`
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    fn config(extra: &str) -> Result<Etsi004Config, toml::de::Error> {
+        toml::from_str(&format!(
+            r#"
+            address = "127.0.0.1:12347"
+            source = "daisyway://ada"
+            destination = "daisyway://bob"
+            key_stream_id = "{KEY_STREAM_ID}"
+            {extra}
+            "#
+        ))
+    }
+
+    fn plaintext_connection(key_stream_id: &str) -> Etsi004Connection {
+        let config = config("danger_allow_plaintext = true").unwrap();
+        let config = Etsi004Config {
+            key_stream_id: key_stream_id.parse().unwrap(),
+            ..config
+        };
+        Etsi004Connection::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn key_ids_are_uuidv8_with_the_index() {
+        let connection = plaintext_connection(KEY_STREAM_ID);
+        for index in [0, 7, u32::MAX] {
+            let id = connection.key_id(index);
+            assert_eq!(id.get_version_num(), 8);
+            assert_eq!(id.get_variant(), uuid::Variant::RFC4122);
+            assert_eq!(id.as_bytes()[12..], index.to_be_bytes());
+            assert_eq!(connection.key_index(id).unwrap(), index);
+        }
+    }
+
+    #[test]
+    fn key_ids_belong_to_their_key_stream() {
+        let connection = plaintext_connection(KEY_STREAM_ID);
+        // Differs from KEY_STREAM_ID in the last four bytes only
+        let other = plaintext_connection("6f1e0a52-8a0e-4f5c-9d5e-3b1f2c7a9e11");
+
+        let id = connection.key_id(7);
+        assert_ne!(id, other.key_id(7));
+        assert_ne!(
+            id.as_bytes()[..12],
+            KEY_STREAM_ID.parse::<Uuid>().unwrap().as_bytes()[..12]
+        );
+        let err = other.key_index(id).unwrap_err();
+        assert!(err.to_string().contains("does not belong"), "{err}");
+    }
+
+    #[test]
+    fn plaintext_must_be_allowed_explicitly() {
+        let err = Etsi004Connection::from_config(&config("").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("No CA certificates"), "{err}");
+
+        let cacert = fixture("ca.pem");
+        let config = config(&format!(
+            "danger_allow_plaintext = true\ntls_cacert = {cacert:?}"
+        ))
+        .unwrap();
+        let err = Etsi004Connection::from_config(&config).unwrap_err();
+        assert!(err.to_string().contains("can not be combined"), "{err}");
+    }
+
+    #[test]
+    fn server_name_defaults_to_the_host() {
+        assert_eq!(host_of("kme.example:12347"), "kme.example");
+        assert_eq!(host_of("192.0.2.1:12347"), "192.0.2.1");
+        assert_eq!(host_of("[2001:db8::1]:12347"), "2001:db8::1");
+    }
+
+    /// Serve the application interface over TLS, answering GET_KEY with keys made of their
+    /// index; records the commands of all connections
+    async fn serve_tls(commands: Arc<Mutex<Vec<String>>>) -> String {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let certs = CertificateDer::pem_file_iter(fixture("server.pem"))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_file(fixture("server.key")).unwrap();
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let Ok(stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let mut lines = BufReader::new(stream);
+                let mut line = String::new();
+                while lines.read_line(&mut line).await.unwrap() > 0 {
+                    let request: Value = serde_json::from_str(&line).unwrap();
+                    line.clear();
+                    let command = request["command"].as_str().unwrap().to_owned();
+                    commands.lock().unwrap().push(command.clone());
+                    let response = match command.as_str() {
+                        "GET_KEY" => {
+                            let index = request["index"].as_u64().unwrap_or(7);
+                            let key = Base64::encode_string(&[index as u8; KEY_LENGTH]);
+                            json!({ "status": 0, "index": index, "key_buffer": key })
+                        }
+                        _ => json!({ "status": 0, "key_stream_id": request["key_stream_id"] }),
+                    };
+                    let mut response = response.to_string();
+                    response.push('\n');
+                    lines
+                        .get_mut()
+                        .write_all(response.as_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn keys_are_fetched_over_tls() {
+        let commands = Arc::default();
+        let address = serve_tls(Arc::clone(&commands)).await;
+        let cacert = fixture("ca.pem");
+        let config = config(&format!(
+            "tls_cacert = {cacert:?}\ntls_server_name = \"kme.example\""
+        ))
+        .unwrap();
+        let connection =
+            Etsi004Connection::from_config(&Etsi004Config { address, ..config }).unwrap();
+
+        connection.check_status().await.unwrap();
+        let key = connection.fetch_any_key().await.unwrap();
+        assert_eq!(key.id, connection.key_id(7));
+        assert_eq!(*key.key, [7; KEY_LENGTH]);
+        let key = connection
+            .fetch_specific_key(connection.key_id(3))
+            .await
+            .unwrap();
+        assert_eq!(*key.key, [3; KEY_LENGTH]);
+        connection.close().await.unwrap();
+
+        // A single session is used for all calls until it is closed
+        assert_eq!(
+            *commands.lock().unwrap(),
+            ["OPEN_CONNECT", "GET_KEY", "GET_KEY", "CLOSE"]
+        );
+    }
+
+    #[tokio::test]
+    async fn untrusted_kme_is_rejected() {
+        let address = serve_tls(Arc::default()).await;
+        let cacert = fixture("other-ca.pem");
+        let config = config(&format!(
+            "tls_cacert = {cacert:?}\ntls_server_name = \"kme.example\""
+        ))
+        .unwrap();
+        let connection =
+            Etsi004Connection::from_config(&Etsi004Config { address, ..config }).unwrap();
+
+        let err = connection.check_status().await.unwrap_err();
+        assert!(format!("{err:#}").contains("TLS handshake"), "{err:#}");
+    }
+}
@@ -1,147 +1,219 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use base64ct::{Base64, Encoding};
 use log::{debug, info, warn};
-use reqwest::Client;
-use rustls::{
-    client::{
-        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
-        WebPkiServerVerifier,
-    },
-    pki_types::{CertificateDer, ServerName, UnixTime},
-    ClientConfig, DigitallySignedStruct, RootCertStore,
-};
-use rustls_pki_types::{pem::PemObject, PrivateKeyDer};
+use rand::Rng;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use uuid::Uuid;
-use zerocopy::FromZeros;
+use zeroize::Zeroizing;
 
-use crate::internal::{daisyway::crypto::Key, util::ConstLenExt};
+use crate::internal::{
+    daisyway::crypto::KEY_LENGTH,
+    key_pool::KeyPoolConfig,
+    key_source::{QkdKey, QkdKeySource},
+    tls::TlsOptions,
+    util::extend_zeroizing,
+};
 
-#[derive(Debug)]
-pub struct NoServerNameVerification {
-    inner: Arc<WebPkiServerVerifier>,
+/// Size of the keys requested from the KME, in bits, unless configured otherwise
+const DEFAULT_KEY_SIZE_BITS: u64 = (KEY_LENGTH * 8) as u64;
+
+/// Consecutive failures after which a KME URL is skipped
+const CIRCUIT_BREAKER_THRESHOLD: u32 = 3;
+
+/// Time a failing KME URL is skipped before it is tried again
+const CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// HTTP method used to request keys
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestMethod {
+    /// Parameters in the query string; only the number of keys can be specified
+    #[default]
+    Get,
+    /// Parameters in a JSON body; supports extensions, additional slave SAEs and key ID batches
+    Post,
 }
 
-// TODO add test cases for this
-impl NoServerNameVerification {
-    pub fn new(inner: Arc<WebPkiServerVerifier>) -> Self {
-        Self { inner }
-    }
+/// Parameters of the key requests besides the number of keys and the key IDs
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RequestOptions {
+    #[serde(default)]
+    pub request_method: RequestMethod,
+    /// Further SAEs that should receive the same keys; requires POST
+    #[serde(default)]
+    pub additional_slave_sae_ids: Vec<String>,
+    /// Extensions the KME must support to serve the request; requires POST
+    #[serde(default)]
+    pub extension_mandatory: Vec<Map<String, Value>>,
+    /// Extensions the KME may ignore; requires POST
+    #[serde(default)]
+    pub extension_optional: Vec<Map<String, Value>>,
+}
 
-    pub fn from_roots(roots: Arc<RootCertStore>) -> Result<NoServerNameVerification> {
-        let inner = rustls::client::WebPkiServerVerifier::builder(roots).build()?;
-        Ok(Self::new(inner))
+impl RequestOptions {
+    fn validate(&self) -> Result<()> {
+        let needs_post = !self.additional_slave_sae_ids.is_empty()
+            || !self.extension_mandatory.is_empty()
+            || !self.extension_optional.is_empty();
+        ensure!(
+            !needs_post || self.request_method == RequestMethod::Post,
+            "additional_slave_sae_ids, extension_mandatory and extension_optional \
+            require request_method = \"post\""
+        );
+        Ok(())
     }
 }
 
-impl ServerCertVerifier for NoServerNameVerification {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &CertificateDer<'_>,
-        _intermediates: &[CertificateDer<'_>],
-        _server_name: &ServerName<'_>,
-        _ocsp: &[u8],
-        _now: UnixTime,
-    ) -> Result<ServerCertVerified, rustls::Error> {
-        match self
-            .inner
-            .verify_server_cert(_end_entity, _intermediates, _server_name, _ocsp, _now)
-        {
-            Ok(scv) => {
-                debug!("Server certificate verified successfully.");
-                Ok(scv)
-            }
-            Err(rustls::Error::InvalidCertificate(cert_error)) => match cert_error {
-                rustls::CertificateError::NotValidForName => {
-                    debug!("Server certificate verification failed: NotValidForName");
-                    Ok(ServerCertVerified::assertion())
-                }
-                rustls::CertificateError::NotValidForNameContext {
-                    expected: _,
-                    presented: _,
-                } => {
-                    debug!("Server certificate verification failed: NotValidForNameContext");
-                    Ok(ServerCertVerified::assertion())
-                }
-                _ => {
-                    debug!("Server certificate verification failed: {:?}", cert_error);
-                    Err(rustls::Error::InvalidCertificate(cert_error))
-                }
-            },
-            Err(e) => Err(e),
+/// Either a single URL or a list of equivalent URLs of the same KME
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum KmeUrls {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl KmeUrls {
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            Self::Single(url) => std::slice::from_ref(url),
+            Self::Multiple(urls) => urls,
         }
     }
+}
 
-    fn verify_tls12_signature(
-        &self,
-        message: &[u8],
-        cert: &CertificateDer<'_>,
-        dss: &DigitallySignedStruct,
-    ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        self.inner.verify_tls12_signature(message, cert, dss)
-    }
+/// Timeouts of requests to the KME and retries of failed requests
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetryOptions {
+    /// Time allowed for establishing the connection to the KME
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Time allowed for the whole request, including the connection setup
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Number of times a request is repeated after connection errors, timeouts and
+    /// server errors; requests rejected by the KME (4xx) are never repeated
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled with every further retry
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    #[serde(default = "default_max_retry_backoff_ms")]
+    pub max_retry_backoff_ms: u64,
+}
 
-    fn verify_tls13_signature(
-        &self,
-        message: &[u8],
-        cert: &CertificateDer<'_>,
-        dss: &DigitallySignedStruct,
-    ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        self.inner.verify_tls13_signature(message, cert, dss)
+fn default_connect_timeout_secs() -> u64 {
+    5
+}
+
+fn default_request_timeout_secs() -> u64 {
+    10
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    200
+}
+
+fn default_max_retry_backoff_ms() -> u64 {
+    5000
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: default_connect_timeout_secs(),
+            request_timeout_secs: default_request_timeout_secs(),
+            max_retries: default_max_retries(),
+            retry_backoff_ms: default_retry_backoff_ms(),
+            max_retry_backoff_ms: default_max_retry_backoff_ms(),
+        }
     }
+}
 
-    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        self.inner.supported_verify_schemes()
+impl RetryOptions {
+    /// Delay before the given retry (starting at 0); exponential backoff with full jitter
+    fn backoff(&self, retry: u32) -> Duration {
+        let max = self
+            .retry_backoff_ms
+            .saturating_mul(1 << retry.min(32))
+            .min(self.max_retry_backoff_ms);
+        Duration::from_millis(rand::rng().random_range(0..=max))
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ClientAuth {
-    tls_cert: PathBuf,
-    tls_key: PathBuf,
+fn default_key_size_bits() -> u64 {
+    DEFAULT_KEY_SIZE_BITS
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Etsi014Config {
-    url: String,
-    remote_sae_id: String,
+    url: KmeUrls,
+    /// May be omitted if every peer configures its own remote SAE
+    remote_sae_id: Option<String>,
     pub interval_secs: Option<u64>,
-    tls_cacert: Option<PathBuf>,
+    /// Size of the requested keys in bits
+    #[serde(default = "default_key_size_bits")]
+    key_size_bits: u64,
     #[serde(flatten)]
-    client_auth: Option<ClientAuth>,
-    #[serde(default)]
-    danger_allow_insecure_no_server_name_certificates: bool,
+    tls: TlsOptions,
+    #[serde(flatten)]
+    request_options: RequestOptions,
+    #[serde(flatten)]
+    retry_options: RetryOptions,
+    /// Prefetch keys instead of requesting them during the rekey
+    pub key_pool: Option<KeyPoolConfig>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Etsi014Key {
-    pub id: Uuid,
-    pub key: Key,
+/// Body of a POST `enc_keys` request
+#[derive(Serialize, Debug)]
+struct KeyRequest<'a> {
+    number: usize,
+    size: u64,
+    #[serde(
+        rename = "additional_slave_SAE_IDs",
+        skip_serializing_if = "<[_]>::is_empty"
+    )]
+    additional_slave_sae_ids: &'a [String],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    extension_mandatory: &'a [Map<String, Value>],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    extension_optional: &'a [Map<String, Value>],
 }
 
-impl Etsi014Key {
-    pub fn empty() -> Self {
-        Self {
-            id: Uuid::nil(),
-            key: Key::new_zeroed(),
-        }
-    }
+/// Body of a POST `dec_keys` request
+#[derive(Serialize, Debug)]
+struct KeyIds {
+    #[serde(rename = "key_IDs")]
+    key_ids: Vec<KeyIdEntry>,
+}
+
+#[derive(Serialize, Debug)]
+struct KeyIdEntry {
+    #[serde(rename = "key_ID")]
+    key_id: Uuid,
 }
 
-impl TryFrom<ResponseKey> for Etsi014Key {
+impl TryFrom<ResponseKey> for QkdKey {
     type Error = anyhow::Error;
 
     fn try_from(value: ResponseKey) -> Result<Self, Self::Error> {
         let ResponseKey { id, key } = value;
-        assert!(Base64::encoded_len(key.as_bytes()) != Key::LEN);
-        let mut dec_buf = Key::new_zeroed();
-        let decoded = Base64::decode(key.as_bytes(), &mut dec_buf).unwrap();
-        Ok(Self {
-            id,
-            key: decoded.try_into()?,
-        })
+        let mut key = Zeroizing::new(key.into_bytes());
+        let len = Base64::decode_in_place(&mut key)
+            .map_err(|e| anyhow!("Failed to decode key {id} returned by the KME: {e}"))?
+            .len();
+        key.truncate(len);
+        Ok(Self { id, key })
     }
 }
 
@@ -158,149 +230,898 @@ struct ResponseKeys {
     keys: Vec<ResponseKey>,
 }
 
-impl TryFrom<ResponseKeys> for Etsi014Key {
-    type Error = anyhow::Error;
+/// Body of an error response, as defined by ETSI GS QKD 014
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    message: String,
+    #[serde(default)]
+    details: Vec<Value>,
+}
 
-    fn try_from(value: ResponseKeys) -> Result<Self, Self::Error> {
-        ensure!(
-            value.keys.len() == 1,
-            "Expected exactly one key, but got {} keys",
-            value.keys.len()
-        );
-        value.keys.into_iter().next().unwrap().try_into()
+/// Error response of the KME
+#[derive(Debug, Clone)]
+pub struct ErrorResponse {
+    pub url: String,
+    pub status: StatusCode,
+    pub message: String,
+    pub details: Vec<Value>,
+}
+
+impl ErrorResponse {
+    /// Error codes given in the `details` objects, normalized to upper snake case
+    fn error_codes(&self) -> Vec<String> {
+        self.details
+            .iter()
+            .filter_map(Value::as_object)
+            .flat_map(|detail| ERROR_CODE_FIELDS.iter().filter_map(|f| detail.get(*f)))
+            .filter_map(Value::as_str)
+            .map(|code| {
+                code.chars()
+                    .map(|c| match c {
+                        c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+                        _ => '_',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Errors reported by the KME
+#[derive(Debug, Clone)]
+pub enum Etsi014Error {
+    /// The KME did not accept our credentials or SAE ID
+    Unauthorized(ErrorResponse),
+    /// The KME does not know the requested key ID, e.g. because it was already used
+    KeyNotFound(ErrorResponse),
+    /// The KME has not enough keys left to serve the request
+    KeyExhausted(ErrorResponse),
+    /// The KME rejected the request for another reason
+    BadRequest(ErrorResponse),
+    /// The KME failed to process the request
+    ServerError(ErrorResponse),
+}
+
+/// Fields of the `details` objects that KMEs use for machine-readable error codes
+const ERROR_CODE_FIELDS: &[&str] = &["code", "error", "error_code", "reason", "type"];
+/// Error codes meaning that the KME does not know the requested key
+const KEY_NOT_FOUND_CODES: &[&str] = &["KEY_NOT_FOUND", "KEY_ID_NOT_FOUND", "UNKNOWN_KEY"];
+/// Error codes meaning that the KME has not enough keys left
+const KEY_EXHAUSTED_CODES: &[&str] = &[
+    "KEY_EXHAUSTED",
+    "KEYS_EXHAUSTED",
+    "INSUFFICIENT_KEYS",
+    "NOT_ENOUGH_KEYS",
+    "NO_KEYS_AVAILABLE",
+];
+
+/// Fallback only: substrings of the free-text message of KMEs without error codes
+const KEY_NOT_FOUND_HINTS: &[&str] = &["not found", "unknown key", "does not exist", "no such"];
+const KEY_EXHAUSTED_HINTS: &[&str] = &["exhaust", "insufficient", "not enough", "no key"];
+
+impl Etsi014Error {
+    /// Parse the error body of an unsuccessful response
+    pub async fn from_response(response: Response) -> Self {
+        let url = response.url().to_string();
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let (message, details) = match serde_json::from_str::<ErrorBody>(&text) {
+            Ok(body) => (body.message, body.details),
+            Err(_) => (text, Vec::new()),
+        };
+        Self::classify(ErrorResponse {
+            url,
+            status,
+            message,
+            details,
+        })
+    }
+
+    /// Classify by the HTTP status and the error codes in `details`
+    ///
+    /// ETSI 014 signals both unknown and exhausted keys using status 400. Only if the KME
+    /// gives no error code, the message text of a 400 response is searched for hints.
+    fn classify(response: ErrorResponse) -> Self {
+        let codes = response.error_codes();
+        let has_code = |known: &[&str]| codes.iter().any(|code| known.contains(&code.as_str()));
+        match response.status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Unauthorized(response),
+            _ if has_code(KEY_NOT_FOUND_CODES) => Self::KeyNotFound(response),
+            _ if has_code(KEY_EXHAUSTED_CODES) => Self::KeyExhausted(response),
+            StatusCode::NOT_FOUND => Self::KeyNotFound(response),
+            StatusCode::BAD_REQUEST if codes.is_empty() => Self::classify_by_message(response),
+            status if status.is_server_error() => Self::ServerError(response),
+            _ => Self::BadRequest(response),
+        }
+    }
+
+    /// Fallback heuristics for KMEs that only give a free-text message
+    fn classify_by_message(response: ErrorResponse) -> Self {
+        let message = response.message.to_lowercase();
+        let hints = |hints: &[&str]| hints.iter().any(|hint| message.contains(hint));
+        let error = if hints(KEY_NOT_FOUND_HINTS) {
+            Self::KeyNotFound(response)
+        } else if hints(KEY_EXHAUSTED_HINTS) {
+            Self::KeyExhausted(response)
+        } else {
+            return Self::BadRequest(response);
+        };
+        debug!("Classified KME error by its message text: {error}");
+        error
+    }
+
+    pub fn response(&self) -> &ErrorResponse {
+        match self {
+            Self::Unauthorized(response)
+            | Self::KeyNotFound(response)
+            | Self::KeyExhausted(response)
+            | Self::BadRequest(response)
+            | Self::ServerError(response) => response,
+        }
+    }
+
+    /// Whether the same request may succeed later
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::KeyExhausted(_) | Self::ServerError(_))
+    }
+}
+
+impl std::fmt::Display for Etsi014Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Self::Unauthorized(_) => "rejected our credentials",
+            Self::KeyNotFound(_) => "does not know the requested key",
+            Self::KeyExhausted(_) => "has not enough keys left",
+            Self::BadRequest(_) => "rejected the request",
+            Self::ServerError(_) => "failed to process the request",
+        };
+        let response = self.response();
+        write!(
+            f,
+            "ETSI 014 URL {} {reason} (status {}): {}",
+            response.url, response.status, response.message
+        )?;
+        if !response.details.is_empty() {
+            write!(f, " {}", Value::from(response.details.clone()))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Etsi014Error {}
+
+/// Response of the ETSI 014 `status` endpoint
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Etsi014Status {
+    #[serde(rename = "source_KME_ID")]
+    pub source_kme_id: String,
+    #[serde(rename = "target_KME_ID")]
+    pub target_kme_id: String,
+    #[serde(rename = "master_SAE_ID")]
+    pub master_sae_id: Option<String>,
+    #[serde(rename = "slave_SAE_ID")]
+    pub slave_sae_id: Option<String>,
+    /// Default size of the keys in bits
+    pub key_size: u64,
+    pub stored_key_count: u64,
+    pub max_key_count: Option<u64>,
+    pub max_key_per_request: u64,
+    pub max_key_size: Option<u64>,
+    pub min_key_size: Option<u64>,
+    #[serde(rename = "max_SAE_ID_count")]
+    pub max_sae_id_count: u64,
+}
+
+impl Etsi014Status {
+    /// Whether keys of the given size in bits can be requested
+    pub fn supports_key_size(&self, bits: u64) -> bool {
+        match (self.min_key_size, self.max_key_size) {
+            _ if self.key_size == bits => true,
+            (Some(min), Some(max)) => (min..=max).contains(&bits),
+            _ => false,
+        }
     }
 }
 
+#[derive(Debug, Default)]
+struct EndpointHealth {
+    consecutive_failures: u32,
+    /// The URL is skipped until then
+    open_until: Option<Instant>,
+}
+
+/// One of the equivalent URLs of a KME, with a circuit breaker
 #[derive(Debug)]
-pub struct Etsi014Connection {
+struct KmeEndpoint {
     url: String,
+    health: Mutex<EndpointHealth>,
+}
+
+impl KmeEndpoint {
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            health: Mutex::new(EndpointHealth::default()),
+        }
+    }
+
+    /// Whether the URL failed repeatedly and should not be used for now
+    fn is_open(&self) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .open_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.consecutive_failures >= CIRCUIT_BREAKER_THRESHOLD {
+            info!("ETSI 014 KME at {} is reachable again", self.url);
+        }
+        *health = EndpointHealth::default();
+    }
+
+    fn record_failure(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= CIRCUIT_BREAKER_THRESHOLD {
+            if health.consecutive_failures == CIRCUIT_BREAKER_THRESHOLD {
+                warn!(
+                    "ETSI 014 KME at {} failed {} times in a row; skipping it for {}s",
+                    self.url,
+                    health.consecutive_failures,
+                    CIRCUIT_BREAKER_COOLDOWN.as_secs()
+                );
+            }
+            health.open_until = Some(Instant::now() + CIRCUIT_BREAKER_COOLDOWN);
+        }
+    }
+}
+
+impl Etsi014Config {
+    /// The remote SAE of a peer, unless the peer configures its own one
+    pub fn remote_sae_id<'a>(&'a self, peer_remote_sae_id: Option<&'a str>) -> Result<&'a str> {
+        peer_remote_sae_id
+            .or(self.remote_sae_id.as_deref())
+            .context(
+                "No remote_sae_id configured, neither for the ETSI014 key source nor for the peer",
+            )
+    }
+}
+
+/// Connection to a KME for requesting keys shared with a single remote SAE
+///
+/// The HTTP client is shared with the connections created using [`Self::with_remote_sae_id`].
+/// The circuit breakers of the URLs are not, as a KME may fail for a single remote SAE only.
+#[derive(Debug)]
+pub struct Etsi014Connection {
+    /// Equivalent URLs of the KME, in order of preference
+    endpoints: Vec<KmeEndpoint>,
     remote_sae_id: String,
-    client: Client,
+    /// Replaced when the TLS files change; requests in flight keep using the previous client
+    client: Arc<RwLock<Client>>,
+    /// TLS settings the client is rebuilt from; absent if the client was passed in
+    tls: Option<Arc<TlsOptions>>,
+    /// Modification times of the TLS files the current client was built from
+    tls_files_modified: Arc<Mutex<Vec<Option<SystemTime>>>>,
+    key_size_bits: u64,
+    request_options: RequestOptions,
+    retry_options: RetryOptions,
+    /// Most recent answer of the status endpoint
+    last_status: Mutex<Option<Etsi014Status>>,
 }
 
 impl Etsi014Connection {
-    pub fn new(url: String, remote_sae_id: String, client: Client) -> Self {
+    pub fn new(urls: Vec<String>, remote_sae_id: String, client: Client) -> Self {
         Self {
-            url,
+            endpoints: urls.into_iter().map(KmeEndpoint::new).collect(),
             remote_sae_id,
-            client,
+            client: Arc::new(RwLock::new(client)),
+            tls: None,
+            tls_files_modified: Arc::new(Mutex::new(Vec::new())),
+            key_size_bits: DEFAULT_KEY_SIZE_BITS,
+            request_options: RequestOptions::default(),
+            retry_options: RetryOptions::default(),
+            last_status: Mutex::new(None),
         }
     }
 
-    pub fn from_config(config: &Etsi014Config) -> Result<Self> {
-        let client_builder = Client::builder().use_rustls_tls();
-        let client_builder = match Self::configure_rustls(config)? {
-            Some(rustls_config) => client_builder.use_preconfigured_tls(rustls_config),
-            None => client_builder,
-        };
+    /// A connection to the same KME for another remote SAE, sharing the HTTP client
+    pub fn with_remote_sae_id(&self, remote_sae_id: &str) -> Self {
+        let urls = self.endpoints.iter().map(|e| e.url.clone());
+        Self {
+            endpoints: urls.map(KmeEndpoint::new).collect(),
+            remote_sae_id: remote_sae_id.to_owned(),
+            client: self.client.clone(),
+            tls: self.tls.clone(),
+            tls_files_modified: self.tls_files_modified.clone(),
+            key_size_bits: self.key_size_bits,
+            request_options: self.request_options.clone(),
+            retry_options: self.retry_options.clone(),
+            last_status: Mutex::new(None),
+        }
+    }
 
-        Ok(Self::new(
-            config.url.clone(),
-            config.remote_sae_id.clone(),
-            client_builder.build()?,
-        ))
+    fn client(&self) -> Client {
+        self.client.read().unwrap().clone()
     }
 
-    fn configure_rustls(config: &Etsi014Config) -> Result<Option<rustls::ClientConfig>> {
-        rustls::crypto::ring::default_provider()
-            .install_default()
-            .expect("Failed to install rustls crypto provider");
-
-        let mut roots = RootCertStore::empty();
-
-        // Load CA certificate if provided
-        if let Some(cacert_path) = &config.tls_cacert {
-            let cacert = CertificateDer::from_pem_file(cacert_path).with_context(|| {
-                format!(
-                    "Failed to read TLS CA certificate from file {:?}",
-                    cacert_path
-                )
-            })?;
-            roots
-                .add(cacert)
-                .context("Failed to add TLS CA certificate to RootCertStore")?;
+    /// Rebuild the HTTP client if the TLS files changed since it was built
+    ///
+    /// With `force`, the client is rebuilt even if the files seem unchanged. If the new files
+    /// cannot be loaded, the previous client stays in use.
+    pub fn reload_tls(&self, force: bool) -> Result<()> {
+        let Some(tls) = &self.tls else {
+            return Ok(());
+        };
+        let modified = tls.files_modified();
+        if !force && *self.tls_files_modified.lock().unwrap() == modified {
+            return Ok(());
         }
 
-        let tls_roots = Arc::new(roots);
+        let client = build_client(&self.retry_options, tls).with_context(|| {
+            format!(
+                "Failed to reload TLS files for ETSI 014 KME {}",
+                self.urls()
+            )
+        })?;
+        *self.client.write().unwrap() = client;
+        *self.tls_files_modified.lock().unwrap() = modified;
+        info!("Reloaded TLS files for ETSI 014 KME {}", self.urls());
+        Ok(())
+    }
 
-        let mut rustls_config: ClientConfig;
+    /// Size of the requested keys in bytes
+    pub fn key_size(&self) -> usize {
+        (self.key_size_bits / 8) as usize
+    }
 
-        // Handle client authentication if configured
-        if let Some(client_auth) = &config.client_auth {
-            let (cert_path, key_path) = match client_auth {
-                ClientAuth { tls_cert, tls_key } => (tls_cert, tls_key),
+    /// All URLs of the KME, for log messages
+    pub fn urls(&self) -> String {
+        let urls: Vec<_> = self.endpoints.iter().map(|e| e.url.as_str()).collect();
+        urls.join(", ")
+    }
+
+    pub fn last_status(&self) -> Option<Etsi014Status> {
+        self.last_status.lock().unwrap().clone()
+    }
+
+    /// Send a request, retrying with backoff as long as no KME URL answers
+    async fn send(
+        &self,
+        path: &str,
+        build: impl Fn(&str) -> RequestBuilder,
+    ) -> Result<(Response, &str)> {
+        let opts = &self.retry_options;
+        let mut retry = 0;
+        loop {
+            match self.send_once(path, &build).await {
+                Ok(res) => return Ok(res),
+                Err(err) if retry < opts.max_retries => {
+                    let backoff = opts.backoff(retry);
+                    retry += 1;
+                    warn!(
+                        "ETSI 014 request failed, retry {retry}/{} in {}ms: {err:#}",
+                        opts.max_retries,
+                        backoff.as_millis()
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("ETSI 014 request failed after {} retries", opts.max_retries)
+                    })
+                }
+            }
+        }
+    }
+
+    /// Send a request to the first healthy KME URL, failing over to the others
+    ///
+    /// Connection errors, timeouts and server errors count as failures of the URL; any other
+    /// response is returned together with the URL that served it.
+    async fn send_once(
+        &self,
+        path: &str,
+        build: impl Fn(&str) -> RequestBuilder,
+    ) -> Result<(Response, &str)> {
+        let healthy: Vec<_> = self.endpoints.iter().filter(|e| !e.is_open()).collect();
+        let candidates = match healthy.is_empty() {
+            // Better to retry failing URLs than to give up right away
+            true => self.endpoints.iter().collect(),
+            false => healthy,
+        };
+
+        let mut last_err = None;
+        for (idx, endpoint) in candidates.iter().enumerate() {
+            let uri = format!("{}/api/v1/keys/{}/{path}", endpoint.url, self.remote_sae_id);
+            let err = match build(&uri).send().await {
+                Ok(response) if response.status().is_server_error() => {
+                    Etsi014Error::from_response(response).await.into()
+                }
+                Ok(response) => {
+                    endpoint.record_success();
+                    return Ok((response, &endpoint.url));
+                }
+                Err(err) => anyhow::Error::from(err)
+                    .context(format!("Failed to send request to ETSI 014 URL {uri}")),
             };
+            endpoint.record_failure();
+            if idx + 1 < candidates.len() {
+                warn!("{err:#}; trying the next KME URL");
+            }
+            last_err = Some(err);
+        }
+        Err(last_err.context("No KME URL configured")?)
+    }
 
-            info!(
-                "Using client authentification with certificate {:?} and key {:?}",
-                cert_path, key_path
-            );
+    pub async fn fetch_status(&self) -> Result<Etsi014Status> {
+        let (response, _) = self.send("status", |uri| self.client().get(uri)).await?;
 
-            let cert = CertificateDer::from_pem_file(cert_path).with_context(|| {
-                format!(
-                    "Failed to read TLS client certificate from file {:?}",
-                    cert_path
-                )
-            })?;
-            let key = PrivateKeyDer::from_pem_file(key_path).with_context(|| {
-                format!("Failed to read TLS client key from file {:?}", key_path)
-            })?;
-
-            rustls_config = ClientConfig::builder()
-                .with_root_certificates(tls_roots.clone())
-                .with_client_auth_cert(vec![cert], key)?;
-        } else {
-            // Start with a base client config using root certificates
-            rustls_config = ClientConfig::builder()
-                .with_root_certificates(tls_roots.clone())
-                .with_no_client_auth();
+        if !response.status().is_success() {
+            return Err(Etsi014Error::from_response(response).await.into());
+        }
+
+        let status: Etsi014Status = response.json().await?;
+        *self.last_status.lock().unwrap() = Some(status.clone());
+        Ok(status)
+    }
+
+    /// Make sure the KME can serve the keys we are going to request
+    fn validate_status(&self, status: &Etsi014Status) -> Result<()> {
+        match &status.slave_sae_id {
+            Some(slave_sae_id) => ensure!(
+                slave_sae_id == &self.remote_sae_id,
+                "KME {} reports slave SAE {slave_sae_id:?}, but remote_sae_id is {:?}",
+                status.source_kme_id,
+                self.remote_sae_id
+            ),
+            None => info!(
+                "KME {} does not report its slave SAE; skipping the check of remote_sae_id {:?}",
+                status.source_kme_id, self.remote_sae_id
+            ),
         }
+        ensure!(
+            status.supports_key_size(self.key_size_bits),
+            "KME {} serves keys of {} bits (min {:?}, max {:?}), but {} bits are configured",
+            status.source_kme_id,
+            status.key_size,
+            status.min_key_size,
+            status.max_key_size,
+            self.key_size_bits
+        );
+        ensure!(
+            status.max_key_per_request >= 1,
+            "KME {} does not allow requesting any keys",
+            status.source_kme_id
+        );
+        let additional_slave_sae_ids = self.request_options.additional_slave_sae_ids.len();
+        ensure!(
+            additional_slave_sae_ids as u64 <= status.max_sae_id_count,
+            "KME {} supports at most {} additional slave SAEs, but {additional_slave_sae_ids} are configured",
+            status.source_kme_id,
+            status.max_sae_id_count
+        );
+        Ok(())
+    }
 
-        // Allow insecure certificates if configured
-        if config.danger_allow_insecure_no_server_name_certificates {
-            warn!("Allowing insecure server name verification for ETSI014 certificates");
+    pub fn from_config(config: &Etsi014Config, remote_sae_id: &str) -> Result<Self> {
+        let tls_files_modified = config.tls.files_modified();
+        let client = build_client(&config.retry_options, &config.tls)?;
 
-            ClientConfig::dangerous(&mut rustls_config).set_certificate_verifier(Arc::new(
-                NoServerNameVerification::from_roots(tls_roots)?,
-            ));
+        ensure!(
+            config.key_size_bits > 0 && config.key_size_bits.is_multiple_of(8),
+            "key_size_bits must be a positive multiple of 8"
+        );
+        let urls = config.url.as_slice();
+        ensure!(!urls.is_empty(), "At least one ETSI014 URL is required");
+        ensure!(
+            config.tls.can_verify_servers() || !urls.iter().any(|url| url.starts_with("https:")),
+            "No CA certificates configured for HTTPS; set tls_cacert, tls_system_roots, tls_webpki_roots or tls_pin_only"
+        );
+        config.request_options.validate()?;
+        if config.request_options.request_method == RequestMethod::Post {
+            info!("Using POST requests for ETSI014 URL {}", urls.join(", "));
         }
 
-        Ok(Some(rustls_config))
+        Ok(Self {
+            request_options: config.request_options.clone(),
+            retry_options: config.retry_options.clone(),
+            key_size_bits: config.key_size_bits,
+            tls: Some(Arc::new(config.tls.clone())),
+            tls_files_modified: Arc::new(Mutex::new(tls_files_modified)),
+            ..Self::new(urls.to_vec(), remote_sae_id.to_owned(), client)
+        })
     }
 
-    pub async fn fetch_any_key(&self) -> Result<Etsi014Key> {
-        self.fetch_key_internal(&format!(
-            "{}/api/v1/keys/{}/enc_keys?number=1&key_length=256",
-            self.url, self.remote_sae_id
-        ))
-        .await
-        .context("Error Fetching unspecific key from ETSI014 URL.")
+    /// Request the given number of fresh keys
+    pub async fn fetch_any_keys(&self, number: usize) -> Result<Vec<QkdKey>> {
+        let opts = &self.request_options;
+        let size = self.key_size_bits;
+        let keys = self
+            .fetch_key_internal("enc_keys", |uri| match opts.request_method {
+                RequestMethod::Get => self
+                    .client()
+                    .get(format!("{uri}?number={number}&size={size}")),
+                RequestMethod::Post => self.client().post(uri).json(&KeyRequest {
+                    number,
+                    size,
+                    additional_slave_sae_ids: &opts.additional_slave_sae_ids,
+                    extension_mandatory: &opts.extension_mandatory,
+                    extension_optional: &opts.extension_optional,
+                }),
+            })
+            .await?;
+        ensure!(
+            keys.len() == number,
+            "Requested {number} keys, but got {} keys",
+            keys.len()
+        );
+        Ok(keys)
     }
 
-    pub async fn fetch_specific_key(&self, id: Uuid) -> Result<Etsi014Key> {
-        self.fetch_key_internal(&format!(
-            "{}/api/v1/keys/{}/dec_keys?key_ID={}",
-            self.url, self.remote_sae_id, id
-        ))
-        .await
-        .context("Error Fetching specific key from ETSI014 URL. (key id={id})")
+    /// Request the keys with the given IDs; the keys are returned in the same order
+    ///
+    /// With POST, all keys are requested at once. GET only supports a single key ID per request.
+    pub async fn fetch_specific_keys(&self, ids: &[Uuid]) -> Result<Vec<QkdKey>> {
+        let mut keys = match self.request_options.request_method {
+            RequestMethod::Get => {
+                let mut keys = Vec::with_capacity(ids.len());
+                for id in ids {
+                    keys.extend(
+                        self.fetch_key_internal("dec_keys", |uri| {
+                            self.client().get(format!("{uri}?key_ID={id}"))
+                        })
+                        .await?,
+                    );
+                }
+                keys
+            }
+            RequestMethod::Post => {
+                let body = KeyIds {
+                    key_ids: ids.iter().map(|&key_id| KeyIdEntry { key_id }).collect(),
+                };
+                self.fetch_key_internal("dec_keys", |uri| self.client().post(uri).json(&body))
+                    .await?
+            }
+        };
+
+        ensure!(
+            keys.len() == ids.len(),
+            "Requested {} keys, but got {} keys",
+            ids.len(),
+            keys.len()
+        );
+        let mut ordered = Vec::with_capacity(ids.len());
+        for id in ids {
+            let pos = keys
+                .iter()
+                .position(|key| key.id == *id)
+                .with_context(|| format!("KME did not return the requested key {id}"))?;
+            ordered.push(keys.swap_remove(pos));
+        }
+        Ok(ordered)
     }
 
-    async fn fetch_key_internal(&self, uri: &str) -> Result<Etsi014Key> {
-        let response = self.client.get(uri).send().await?;
+    async fn fetch_key_internal(
+        &self,
+        path: &str,
+        build: impl Fn(&str) -> RequestBuilder,
+    ) -> Result<Vec<QkdKey>> {
+        let (response, url) = self.send(path, build).await?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await?;
-            return Err(anyhow::anyhow!(
-                "ETSI 014 URL {} returned status code {}: {}",
-                &uri,
-                status,
-                text
-            ));
+            return Err(Etsi014Error::from_response(response).await.into());
         }
 
-        let response: ResponseKeys = response.json().await?;
-        response.try_into()
+        let body = read_body_zeroizing(response).await?;
+        let response: ResponseKeys = serde_json::from_slice(&body)?;
+        let keys = response
+            .keys
+            .into_iter()
+            .map(QkdKey::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        for key in &keys {
+            ensure!(
+                key.key.len() == self.key_size(),
+                "KME returned key {} with {} bits instead of {} bits",
+                key.id,
+                key.key.len() * 8,
+                self.key_size_bits
+            );
+            info!("Key {} served by ETSI 014 KME at {url}", key.id);
+        }
+        Ok(keys)
+    }
+}
+
+/// Upper bound for the buffer allocated up front for a response body
+const MAX_PREALLOCATED_BODY_LEN: usize = 64 * 1024;
+
+/// Read the response body into memory that is zeroized when dropped
+///
+/// The buffers of the HTTP client itself are out of our reach, but this leaves no further
+/// copies of the key material behind.
+async fn read_body_zeroizing(mut response: Response) -> Result<Zeroizing<Vec<u8>>> {
+    let capacity = response
+        .content_length()
+        .map_or(0, |len| (len as usize).min(MAX_PREALLOCATED_BODY_LEN));
+    let mut body = Zeroizing::new(Vec::with_capacity(capacity));
+    while let Some(chunk) = response.chunk().await? {
+        extend_zeroizing(&mut body, &chunk);
+    }
+    Ok(body)
+}
+
+fn build_client(retry_options: &RetryOptions, tls: &TlsOptions) -> Result<Client> {
+    let client = Client::builder()
+        .use_rustls_tls()
+        .connect_timeout(Duration::from_secs(retry_options.connect_timeout_secs))
+        .timeout(Duration::from_secs(retry_options.request_timeout_secs))
+        .use_preconfigured_tls(tls.client_config()?)
+        .build()?;
+    Ok(client)
+}
+
+impl QkdKeySource for Etsi014Connection {
+    fn reload_tls(&self, force: bool) -> Result<()> {
+        Etsi014Connection::reload_tls(self, force)
+    }
+
+    async fn check_status(&self) -> Result<()> {
+        // An unreachable KME fails the startup; later checks only warn about it
+        let status = self
+            .fetch_status()
+            .await
+            .with_context(|| format!("Failed to query ETSI 014 status at {}", self.urls()))?;
+        self.validate_status(&status)?;
+
+        info!(
+            "ETSI 014 KME {} (peer KME {}) has {} keys stored",
+            status.source_kme_id, status.target_kme_id, status.stored_key_count
+        );
+        if status.stored_key_count == 0 {
+            warn!(
+                "ETSI 014 KME {} has no keys stored; rekeying will fail until keys are available",
+                status.source_kme_id
+            );
+        }
+        Ok(())
+    }
+
+    async fn fetch_any_key(&self) -> Result<QkdKey> {
+        let keys = self
+            .fetch_any_keys(1)
+            .await
+            .context("Error Fetching unspecific key from ETSI014 URL.")?;
+        Ok(keys.into_iter().next().unwrap())
+    }
+
+    async fn fetch_specific_key(&self, id: Uuid) -> Result<QkdKey> {
+        let keys = self.fetch_specific_keys(&[id]).await.with_context(|| {
+            format!("Error Fetching specific key from ETSI014 URL. (key id={id})")
+        })?;
+        Ok(keys.into_iter().next().unwrap())
+    }
+}
+
+/// Whether the error was reported by a KME and repeating the request later may succeed
+pub fn is_transient_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<Etsi014Error>()
+        .is_some_and(Etsi014Error::is_transient)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(url: &str) -> Etsi014Connection {
+        let config: Etsi014Config = toml::from_str(&format!(
+            r#"
+            url = "{url}"
+            remote_sae_id = "SAE_002"
+            "#
+        ))
+        .unwrap();
+        Etsi014Connection::from_config(&config, "SAE_002").unwrap()
+    }
+
+    /// Answer a single HTTP request with the given status and JSON body; returns the KME URL
+    async fn serve_once(status: u16, body: String) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 {status} Status\r\ncontent-type: application/json\r\n\
+                content-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    fn status(slave_sae_id: Option<&str>) -> Etsi014Status {
+        Etsi014Status {
+            source_kme_id: "KME_001".to_owned(),
+            target_kme_id: "KME_002".to_owned(),
+            master_sae_id: Some("SAE_001".to_owned()),
+            slave_sae_id: slave_sae_id.map(str::to_owned),
+            key_size: DEFAULT_KEY_SIZE_BITS,
+            stored_key_count: 10,
+            max_key_count: None,
+            max_key_per_request: 1,
+            max_key_size: None,
+            min_key_size: None,
+            max_sae_id_count: 0,
+        }
+    }
+
+    #[test]
+    fn status_with_matching_slave_sae_is_accepted() {
+        let connection = connection("http://127.0.0.1:1");
+        connection
+            .validate_status(&status(Some("SAE_002")))
+            .unwrap();
+    }
+
+    #[test]
+    fn status_with_other_slave_sae_is_rejected() {
+        let connection = connection("http://127.0.0.1:1");
+        let err = connection
+            .validate_status(&status(Some("SAE_003")))
+            .unwrap_err();
+        assert!(err.to_string().contains("SAE_003"), "{err}");
+    }
+
+    #[test]
+    fn status_without_slave_sae_is_accepted() {
+        let connection = connection("http://127.0.0.1:1");
+        connection.validate_status(&status(None)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unreachable_kme_fails_the_status_check() {
+        // Reserve a port and close it again, so nothing is listening there
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let connection = connection(&format!("http://{addr}"));
+        assert!(QkdKeySource::check_status(&connection).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keys_are_read_from_the_response() {
+        let id = Uuid::from_u128(7);
+        let key = Base64::encode_string(&[7u8; KEY_LENGTH]);
+        let url = serve_once(
+            200,
+            format!(r#"{{"keys": [{{"key_ID": "{id}", "key": "{key}"}}]}}"#),
+        )
+        .await;
+
+        let keys = connection(&url).fetch_any_keys(1).await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].id, id);
+        assert_eq!(*keys[0].key, vec![7u8; KEY_LENGTH]);
+    }
+
+    fn error(status: u16, message: &str, details: Vec<Value>) -> Etsi014Error {
+        Etsi014Error::classify(ErrorResponse {
+            url: "http://kme.example/api/v1/keys/SAE_002/enc_keys".to_owned(),
+            status: StatusCode::from_u16(status).unwrap(),
+            message: message.to_owned(),
+            details,
+        })
+    }
+
+    #[test]
+    fn unauthorized_is_classified_by_status() {
+        for status in [401, 403] {
+            let err = error(status, "Key not found", Vec::new());
+            assert!(matches!(err, Etsi014Error::Unauthorized(_)), "{err}");
+        }
+    }
+
+    #[test]
+    fn not_found_status_means_unknown_key() {
+        let err = error(404, "", Vec::new());
+        assert!(matches!(err, Etsi014Error::KeyNotFound(_)), "{err}");
+    }
+
+    #[test]
+    fn key_not_found_code_is_recognized() {
+        let details = vec![serde_json::json!({"code": "KEY_NOT_FOUND"})];
+        let err = error(400, "Invalid request", details);
+        assert!(matches!(err, Etsi014Error::KeyNotFound(_)), "{err}");
+    }
+
+    #[test]
+    fn key_exhausted_code_is_recognized() {
+        let details = vec![serde_json::json!({"error": "insufficient-keys"})];
+        let err = error(400, "Invalid request", details);
+        assert!(matches!(err, Etsi014Error::KeyExhausted(_)), "{err}");
+        assert!(err.is_transient());
+    }
+
+    #[test]
+    fn error_code_wins_over_status() {
+        let details = vec![serde_json::json!({"reason": "key_exhausted"})];
+        let err = error(503, "Service unavailable", details);
+        assert!(matches!(err, Etsi014Error::KeyExhausted(_)), "{err}");
+    }
+
+    #[test]
+    fn error_code_wins_over_message() {
+        let details = vec![serde_json::json!({"code": "KEYS_EXHAUSTED"})];
+        let err = error(400, "No such key", details);
+        assert!(matches!(err, Etsi014Error::KeyExhausted(_)), "{err}");
+    }
+
+    #[test]
+    fn message_is_searched_without_error_code() {
+        let err = error(400, "Key with this ID does not exist", Vec::new());
+        assert!(matches!(err, Etsi014Error::KeyNotFound(_)), "{err}");
+        let err = error(400, "Not enough keys available", Vec::new());
+        assert!(matches!(err, Etsi014Error::KeyExhausted(_)), "{err}");
+    }
+
+    #[test]
+    fn message_is_ignored_with_unknown_error_code() {
+        let details = vec![serde_json::json!({"code": "INVALID_SIZE"})];
+        let err = error(400, "Key not found", details);
+        assert!(matches!(err, Etsi014Error::BadRequest(_)), "{err}");
+    }
+
+    #[test]
+    fn message_is_only_searched_for_bad_requests() {
+        let err = error(500, "Key not found", Vec::new());
+        assert!(matches!(err, Etsi014Error::ServerError(_)), "{err}");
+        assert!(err.is_transient());
+    }
+
+    #[test]
+    fn other_bad_requests_are_not_transient() {
+        let err = error(
+            400,
+            "Invalid key size",
+            vec![serde_json::json!("free text")],
+        );
+        assert!(matches!(err, Etsi014Error::BadRequest(_)), "{err}");
+        assert!(!err.is_transient());
+    }
+
+    #[tokio::test]
+    async fn error_body_is_parsed() {
+        let url = serve_once(
+            400,
+            r#"{"message": "Invalid request", "details": [{"code": "KEY_NOT_FOUND"}]}"#.to_owned(),
+        )
+        .await;
+        let err = connection(&url)
+            .fetch_specific_keys(&[Uuid::from_u128(1)])
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<Etsi014Error>().unwrap();
+        assert!(matches!(err, Etsi014Error::KeyNotFound(_)), "{err}");
+        assert_eq!(err.response().message, "Invalid request");
+    }
+
+    #[test]
+    fn circuit_breaker_is_kept_per_remote_sae() {
+        let connection = connection("http://kme.example");
+        let other = connection.with_remote_sae_id("SAE_003");
+        for _ in 0..CIRCUIT_BREAKER_THRESHOLD {
+            connection.endpoints[0].record_failure();
+        }
+        assert!(connection.endpoints[0].is_open());
+        assert!(!other.endpoints[0].is_open());
     }
 }
@@ -0,0 +1,424 @@
+//! Pool of prefetched ETSI 014 keys
+//!
+//! Without a pool, every rekey waits for a round trip to the KME. The pool requests keys in
+//! batches, keeps them in memory that is locked into RAM and zeroized once a key is used or
+//! expired, and refills itself in the background.
+//!
+//! Only keys requested via `enc_keys` can be pooled; the peer fetches the matching keys by ID.
+//! The pool starts filling once the first key is taken, so a peer that only ever responds to
+//! rekey requests does not drain keys from its KME.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, task::JoinHandle};
+use uuid::Uuid;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::internal::{
+    etsi014::Etsi014Connection,
+    key_source::{QkdKey, QkdKeySource},
+};
+
+/// Time to wait before requesting keys again after a failed refill
+const REFILL_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeyPoolConfig {
+    /// Maximum number of keys kept in the pool
+    pub size: usize,
+    /// Keys older than this are discarded instead of being used
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+    /// Refill once fewer keys are left; defaults to half the pool size
+    pub refill_threshold: Option<usize>,
+}
+
+fn default_max_age_secs() -> u64 {
+    3600
+}
+
+/// Fixed-size key slots in memory that is locked into RAM and zeroized when dropped
+struct LockedKeys {
+    bytes: Box<[u8]>,
+    key_size: usize,
+    locked: bool,
+}
+
+impl LockedKeys {
+    fn new(capacity: usize, key_size: usize) -> Self {
+        let bytes = vec![0u8; capacity * key_size].into_boxed_slice();
+        // SAFETY: The pointer and length describe the allocation owned by `bytes`
+        let locked = unsafe { libc::mlock(bytes.as_ptr().cast(), bytes.len()) } == 0;
+        if !locked {
+            warn!(
+                "Failed to lock key pool memory; pooled keys may be swapped to disk: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Self {
+            bytes,
+            key_size,
+            locked,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.bytes.len() / self.key_size
+    }
+
+    fn slot(&self, slot: usize) -> &[u8] {
+        &self.bytes[slot * self.key_size..][..self.key_size]
+    }
+
+    fn slot_mut(&mut self, slot: usize) -> &mut [u8] {
+        &mut self.bytes[slot * self.key_size..][..self.key_size]
+    }
+}
+
+impl Drop for LockedKeys {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+        if self.locked {
+            // SAFETY: Same region as locked in LockedKeys::new
+            unsafe {
+                libc::munlock(self.bytes.as_ptr().cast(), self.bytes.len());
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PoolEntry {
+    id: Uuid,
+    slot: usize,
+    fetched_at: Instant,
+}
+
+/// Keys in the pool, oldest first
+struct PooledKeys {
+    storage: LockedKeys,
+    entries: VecDeque<PoolEntry>,
+    free_slots: Vec<usize>,
+}
+
+impl std::fmt::Debug for PooledKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PooledKeys")
+            .field("len", &self.entries.len())
+            .field("capacity", &self.storage.capacity())
+            .finish()
+    }
+}
+
+impl PooledKeys {
+    fn new(capacity: usize, key_size: usize) -> Self {
+        Self {
+            storage: LockedKeys::new(capacity, key_size),
+            entries: VecDeque::with_capacity(capacity),
+            free_slots: (0..capacity).rev().collect(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn missing(&self) -> usize {
+        self.free_slots.len()
+    }
+
+    /// Copy the key into the pool; fails if the pool is full
+    fn insert(&mut self, key: &QkdKey, fetched_at: Instant) -> Result<()> {
+        ensure!(
+            key.key.len() == self.storage.key_size,
+            "Key {} has {} bytes, but the key pool holds keys of {} bytes",
+            key.id,
+            key.key.len(),
+            self.storage.key_size
+        );
+        let slot = self
+            .free_slots
+            .pop()
+            .with_context(|| format!("Key pool is full, can not add key {}", key.id))?;
+        self.storage.slot_mut(slot).copy_from_slice(&key.key);
+        self.entries.push_back(PoolEntry {
+            id: key.id,
+            slot,
+            fetched_at,
+        });
+        Ok(())
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.storage.slot_mut(slot).zeroize();
+        self.free_slots.push(slot);
+    }
+
+    /// Discard all keys older than `max_age`; returns the number of discarded keys
+    fn expire(&mut self, max_age: Duration) -> usize {
+        let mut expired = 0;
+        while let Some(entry) = self.entries.front() {
+            if entry.fetched_at.elapsed() < max_age {
+                break;
+            }
+            let entry = self.entries.pop_front().unwrap();
+            self.release(entry.slot);
+            expired += 1;
+        }
+        expired
+    }
+
+    /// Take the oldest key that has not expired yet
+    fn take(&mut self, max_age: Duration) -> Option<QkdKey> {
+        self.expire(max_age);
+        let entry = self.entries.pop_front()?;
+        let key = QkdKey {
+            id: entry.id,
+            key: Zeroizing::new(self.storage.slot(entry.slot).to_vec()),
+        };
+        self.release(entry.slot);
+        Some(key)
+    }
+
+    /// Time at which the oldest key expires
+    fn next_expiry(&self, max_age: Duration) -> Option<Instant> {
+        self.entries.front().map(|entry| entry.fetched_at + max_age)
+    }
+}
+
+#[derive(Debug)]
+struct PoolShared {
+    connection: Etsi014Connection,
+    max_age: Duration,
+    refill_threshold: usize,
+    keys: Mutex<PooledKeys>,
+    /// Wakes up the refill task
+    refill: Notify,
+}
+
+impl PoolShared {
+    async fn refill_task(self: Arc<Self>) {
+        // Wait for the first key to be taken
+        self.refill.notified().await;
+        info!(
+            "Filling key pool with up to {} keys",
+            self.keys.lock().unwrap().storage.capacity()
+        );
+
+        // Once the pool drops below the threshold, it is filled up completely
+        let mut filling = false;
+        loop {
+            let (len, missing, next_expiry) = {
+                let mut keys = self.keys.lock().unwrap();
+                let expired = keys.expire(self.max_age);
+                if expired > 0 {
+                    debug!("Discarded {expired} expired keys from the key pool");
+                }
+                (keys.len(), keys.missing(), keys.next_expiry(self.max_age))
+            };
+
+            filling = (filling || len < self.refill_threshold) && missing > 0;
+            if filling {
+                if let Err(err) = self.refill_once(missing).await {
+                    warn!("Failed to refill key pool: {err:?}");
+                    tokio::time::sleep(REFILL_RETRY_INTERVAL).await;
+                }
+                continue;
+            }
+
+            // Sleep until a key is taken or the oldest key expires
+            let wake_at = next_expiry.unwrap_or_else(|| Instant::now() + self.max_age);
+            tokio::select! {
+                _ = self.refill.notified() => {}
+                _ = tokio::time::sleep_until(wake_at.into()) => {}
+            }
+        }
+    }
+
+    /// Request up to `missing` keys and add them to the pool
+    ///
+    /// Only the refill task adds keys, while taking or expiring keys frees slots. The slots
+    /// that were free when the request was sent are therefore still free when the response
+    /// arrives, and no fetched key has to be discarded.
+    async fn refill_once(&self, missing: usize) -> Result<()> {
+        let max_per_request = self
+            .connection
+            .last_status()
+            .map(|status| status.max_key_per_request as usize)
+            .unwrap_or(missing);
+        let number = missing.min(max_per_request).max(1);
+
+        let fetched = self.connection.fetch_any_keys(number).await?;
+        let fetched_at = Instant::now();
+
+        let mut keys = self.keys.lock().unwrap();
+        for key in &fetched {
+            keys.insert(key, fetched_at)?;
+        }
+        debug!(
+            "Added {number} keys to the key pool, {} keys available",
+            keys.len()
+        );
+        Ok(())
+    }
+}
+
+/// An ETSI 014 connection with a pool of prefetched keys
+#[derive(Debug)]
+pub struct KeyPool {
+    shared: Arc<PoolShared>,
+    refill_task: JoinHandle<()>,
+}
+
+impl KeyPool {
+    /// Must be called from within a Tokio runtime, since it spawns the refill task
+    pub fn new(connection: Etsi014Connection, config: &KeyPoolConfig) -> Result<Self> {
+        ensure!(config.size >= 1, "key_pool.size must be at least 1");
+        let refill_threshold = config.refill_threshold.unwrap_or(config.size.div_ceil(2));
+        ensure!(
+            (1..=config.size).contains(&refill_threshold),
+            "key_pool.refill_threshold must be between 1 and key_pool.size"
+        );
+
+        let keys = PooledKeys::new(config.size, connection.key_size());
+        let shared = Arc::new(PoolShared {
+            connection,
+            max_age: Duration::from_secs(config.max_age_secs),
+            refill_threshold,
+            keys: Mutex::new(keys),
+            refill: Notify::new(),
+        });
+        let refill_task = tokio::spawn(shared.clone().refill_task());
+
+        Ok(Self {
+            shared,
+            refill_task,
+        })
+    }
+
+    /// Number of keys currently in the pool, including expired keys not yet discarded
+    pub fn len(&self) -> usize {
+        self.shared.keys.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for KeyPool {
+    fn drop(&mut self) {
+        self.refill_task.abort();
+    }
+}
+
+impl QkdKeySource for KeyPool {
+    async fn fetch_any_key(&self) -> Result<QkdKey> {
+        let (key, len) = {
+            let mut keys = self.shared.keys.lock().unwrap();
+            let key = keys.take(self.shared.max_age);
+            (key, keys.len())
+        };
+        if len < self.shared.refill_threshold {
+            self.shared.refill.notify_one();
+        }
+
+        match key {
+            Some(key) => {
+                debug!("Using pooled key {}, {len} keys left in the pool", key.id);
+                Ok(key)
+            }
+            None => {
+                warn!("Key pool is empty, requesting key from the KME directly");
+                self.shared.connection.fetch_any_key().await
+            }
+        }
+    }
+
+    async fn fetch_specific_key(&self, id: Uuid) -> Result<QkdKey> {
+        self.shared.connection.fetch_specific_key(id).await
+    }
+
+    fn reload_tls(&self, force: bool) -> Result<()> {
+        self.shared.connection.reload_tls(force)
+    }
+
+    async fn check_status(&self) -> Result<()> {
+        self.shared.connection.check_status().await?;
+        debug!("Key pool holds {} keys", self.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: u128) -> QkdKey {
+        QkdKey {
+            id: Uuid::from_u128(id),
+            key: Zeroizing::new(vec![id as u8; 32]),
+        }
+    }
+
+    #[test]
+    fn keys_are_taken_oldest_first() {
+        let mut keys = PooledKeys::new(2, 32);
+        keys.insert(&key(1), Instant::now()).unwrap();
+        keys.insert(&key(2), Instant::now()).unwrap();
+
+        let taken = keys.take(Duration::from_secs(60)).unwrap();
+        assert_eq!(taken.id, Uuid::from_u128(1));
+        assert_eq!(*taken.key, vec![1u8; 32]);
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys.missing(), 1);
+    }
+
+    #[test]
+    fn taking_keys_frees_slots_for_a_pending_refill() {
+        let mut keys = PooledKeys::new(2, 32);
+        keys.insert(&key(1), Instant::now()).unwrap();
+        let missing = keys.missing();
+
+        // A key is taken while the refill request for the missing keys is in flight
+        keys.take(Duration::from_secs(60)).unwrap();
+        for id in 0..missing {
+            keys.insert(&key(10 + id as u128), Instant::now()).unwrap();
+        }
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys.missing(), 1);
+    }
+
+    #[test]
+    fn full_pool_rejects_keys() {
+        let mut keys = PooledKeys::new(1, 32);
+        keys.insert(&key(1), Instant::now()).unwrap();
+        assert!(keys.insert(&key(2), Instant::now()).is_err());
+        assert_eq!(keys.len(), 1);
+    }
+
+    #[test]
+    fn keys_of_the_wrong_size_are_rejected() {
+        let mut keys = PooledKeys::new(1, 16);
+        assert!(keys.insert(&key(1), Instant::now()).is_err());
+        assert_eq!(keys.missing(), 1);
+    }
+
+    #[test]
+    fn expired_keys_are_discarded() {
+        let mut keys = PooledKeys::new(2, 32);
+        keys.insert(&key(1), Instant::now()).unwrap();
+        keys.insert(&key(2), Instant::now()).unwrap();
+
+        assert!(keys.take(Duration::ZERO).is_none());
+        assert_eq!(keys.missing(), 2);
+    }
+}
@@ -0,0 +1,477 @@
+//! Abstraction over QKD key delivery interfaces and combining keys from multiple key sources
+
+use std::{future::Future, marker::PhantomData, sync::Arc, time::Duration};
+
+use anyhow::{ensure, Context, Result};
+use log::{info, warn};
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::internal::{
+    etsi004::{Etsi004Config, Etsi004Connection},
+    etsi014::{Etsi014Config, Etsi014Connection},
+    key_pool::KeyPool,
+};
+
+/// A key delivered by a QKD key source, independent of the key delivery interface
+#[derive(Debug, Clone)]
+pub struct QkdKey {
+    pub id: Uuid,
+    /// Key material of any length; zeroized when dropped
+    pub key: Zeroizing<Vec<u8>>,
+}
+
+/// A key delivery interface of a QKD device
+///
+/// The initiating peer fetches any fresh key and sends its ID to the other peer, which then
+/// fetches the matching key from its own QKD device.
+pub trait QkdKeySource {
+    fn fetch_any_key(&self) -> impl Future<Output = Result<QkdKey>> + Send;
+    fn fetch_specific_key(&self, id: Uuid) -> impl Future<Output = Result<QkdKey>> + Send;
+
+    /// Make sure the key source is compatible and report its status
+    ///
+    /// Called at startup and periodically afterwards. Key sources without a status
+    /// interface need not implement this.
+    fn check_status(&self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Reload TLS certificates and keys if their files changed, or unconditionally with `force`
+    ///
+    /// Key sources without TLS need not implement this.
+    fn reload_tls(&self, _force: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Release what the key source holds at the KME, e.g. open key streams
+    ///
+    /// Called on shutdown. Key sources without such state need not implement this.
+    fn close(&self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Interval at which the status of the key sources is checked after startup
+pub const KEY_SOURCE_STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Interval at which the TLS files of the key sources are checked for changes
+pub const TLS_RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Rekey requests encode the number of QKD keys in a single byte
+pub const MAX_KEY_SOURCES: usize = u8::MAX as usize;
+
+/// Either a single section (e.g. `[etsi014]`) or a list of sections (e.g. `[[etsi014]]`)
+///
+/// Deserialized by looking at the shape of the value instead of trying both variants, so
+/// errors in the sections themselves are reported instead of "data did not match any variant".
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum KeySourceConfigs<C> {
+    Single(C),
+    Multiple(Vec<C>),
+}
+
+impl<'de, C: Deserialize<'de>> Deserialize<'de> for KeySourceConfigs<C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ConfigsVisitor<C>(PhantomData<C>);
+
+        impl<'de, C: Deserialize<'de>> Visitor<'de> for ConfigsVisitor<C> {
+            type Value = KeySourceConfigs<C>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a key source section or a list of key source sections")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                C::deserialize(MapAccessDeserializer::new(map)).map(KeySourceConfigs::Single)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(KeySourceConfigs::Multiple)
+            }
+        }
+
+        deserializer.deserialize_any(ConfigsVisitor(PhantomData))
+    }
+}
+
+impl<C> Default for KeySourceConfigs<C> {
+    fn default() -> Self {
+        Self::Multiple(Vec::new())
+    }
+}
+
+impl<C> KeySourceConfigs<C> {
+    pub fn as_slice(&self) -> &[C] {
+        match self {
+            Self::Single(config) => std::slice::from_ref(config),
+            Self::Multiple(configs) => configs,
+        }
+    }
+}
+
+/// Any of the supported key delivery interfaces
+#[derive(Debug)]
+pub enum AnyKeySource {
+    Etsi014(Box<Etsi014Connection>),
+    Etsi014Pooled(KeyPool),
+    Etsi004(Box<Etsi004Connection>),
+}
+
+impl AnyKeySource {
+    /// One key source per peer, all sharing the HTTP client of the KME
+    fn from_etsi014_config(
+        config: &Etsi014Config,
+        remote_sae_ids: &[Option<&str>],
+    ) -> Result<Vec<Self>> {
+        let mut connections: Vec<Etsi014Connection> = Vec::with_capacity(remote_sae_ids.len());
+        for remote_sae_id in remote_sae_ids {
+            let remote_sae_id = config.remote_sae_id(*remote_sae_id)?;
+            let connection = match connections.first() {
+                Some(shared) => shared.with_remote_sae_id(remote_sae_id),
+                None => Etsi014Connection::from_config(config, remote_sae_id)?,
+            };
+            connections.push(connection);
+        }
+        connections
+            .into_iter()
+            .map(|connection| {
+                Ok(match &config.key_pool {
+                    Some(pool_config) => {
+                        Self::Etsi014Pooled(KeyPool::new(connection, pool_config)?)
+                    }
+                    None => Self::Etsi014(Box::new(connection)),
+                })
+            })
+            .collect()
+    }
+}
+
+impl QkdKeySource for AnyKeySource {
+    async fn fetch_any_key(&self) -> Result<QkdKey> {
+        match self {
+            Self::Etsi014(source) => source.fetch_any_key().await,
+            Self::Etsi014Pooled(source) => source.fetch_any_key().await,
+            Self::Etsi004(source) => source.fetch_any_key().await,
+        }
+    }
+
+    async fn fetch_specific_key(&self, id: Uuid) -> Result<QkdKey> {
+        match self {
+            Self::Etsi014(source) => source.fetch_specific_key(id).await,
+            Self::Etsi014Pooled(source) => source.fetch_specific_key(id).await,
+            Self::Etsi004(source) => source.fetch_specific_key(id).await,
+        }
+    }
+
+    async fn check_status(&self) -> Result<()> {
+        match self {
+            Self::Etsi014(source) => source.check_status().await,
+            Self::Etsi014Pooled(source) => source.check_status().await,
+            Self::Etsi004(source) => source.check_status().await,
+        }
+    }
+
+    fn reload_tls(&self, force: bool) -> Result<()> {
+        match self {
+            Self::Etsi014(source) => source.reload_tls(force),
+            Self::Etsi014Pooled(source) => source.reload_tls(force),
+            Self::Etsi004(source) => source.reload_tls(force),
+        }
+    }
+
+    async fn close(&self) -> Result<()> {
+        match self {
+            Self::Etsi014(_) | Self::Etsi014Pooled(_) => Ok(()),
+            Self::Etsi004(source) => source.close().await,
+        }
+    }
+}
+
+/// A list of independent QKD key sources, each contributing one key to every rekey
+///
+/// Both peers must list the key sources in the same order; the n-th key ID in a rekey
+/// request is fetched from the n-th key source. The set does not implement [QkdKeySource]
+/// itself, since every key keeps its own ID instead of being combined into a single key.
+/// ETSI 014 key sources come before ETSI 004 key sources.
+#[derive(Debug)]
+pub struct KeySourceSet<K: QkdKeySource> {
+    sources: Vec<K>,
+}
+
+impl KeySourceSet<AnyKeySource> {
+    /// Set up the key sources of every peer
+    ///
+    /// `remote_sae_ids` holds one entry per peer; if given, it replaces the `remote_sae_id`
+    /// of all ETSI 014 key sources for that peer. The peers share one HTTP client per KME.
+    pub fn for_peers(
+        etsi014: &KeySourceConfigs<Etsi014Config>,
+        etsi004: &KeySourceConfigs<Etsi004Config>,
+        remote_sae_ids: &[Option<&str>],
+    ) -> Result<Vec<Self>> {
+        ensure!(
+            etsi004.as_slice().is_empty() || remote_sae_ids.len() <= 1,
+            "ETSI 004 key streams connect exactly two peers and can not be used with several peers"
+        );
+
+        let mut peer_sources: Vec<Vec<AnyKeySource>> =
+            remote_sae_ids.iter().map(|_| Vec::new()).collect();
+        for (idx, config) in etsi014.as_slice().iter().enumerate() {
+            let sources = AnyKeySource::from_etsi014_config(config, remote_sae_ids)
+                .with_context(|| format!("Failed to set up QKD key source #{idx}"))?;
+            for (peer, source) in peer_sources.iter_mut().zip(sources) {
+                peer.push(source);
+            }
+        }
+        let offset = etsi014.as_slice().len();
+        for (idx, config) in etsi004.as_slice().iter().enumerate() {
+            for peer in peer_sources.iter_mut() {
+                let source = Etsi004Connection::from_config(config).with_context(|| {
+                    format!("Failed to set up QKD key source #{}", offset + idx)
+                })?;
+                peer.push(AnyKeySource::Etsi004(Box::new(source)));
+            }
+        }
+
+        peer_sources.into_iter().map(Self::new).collect()
+    }
+}
+
+impl<K: QkdKeySource> KeySourceSet<K> {
+    pub fn new(sources: Vec<K>) -> Result<Self> {
+        ensure!(
+            !sources.is_empty(),
+            "At least one QKD key source is required"
+        );
+        ensure!(
+            sources.len() <= MAX_KEY_SOURCES,
+            "At most {MAX_KEY_SOURCES} QKD key sources are supported, but {} are configured",
+            sources.len()
+        );
+        Ok(Self { sources })
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    pub async fn check_status(&self) -> Result<()> {
+        for (idx, source) in self.sources.iter().enumerate() {
+            source
+                .check_status()
+                .await
+                .with_context(|| format!("QKD key source #{idx} is not usable"))?;
+        }
+        Ok(())
+    }
+
+    /// Close all key sources, e.g. on shutdown
+    pub async fn close(&self) {
+        for (idx, source) in self.sources.iter().enumerate() {
+            if let Err(err) = source.close().await {
+                warn!("Failed to close QKD key source #{idx}: {err:?}");
+            }
+        }
+    }
+
+    /// Periodically check the status of all key sources; runs forever
+    pub async fn monitor_status(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(err) = self.check_status().await {
+                warn!("{err:?}");
+            }
+        }
+    }
+
+    /// Reload the TLS files of all key sources if they changed; runs forever
+    ///
+    /// SIGHUP reloads the TLS files of all key sources, even if they seem unchanged. Since
+    /// only the TLS clients are replaced, rekeys in progress and the current PSK are not
+    /// affected.
+    pub async fn watch_tls(self: Arc<Self>, interval: Duration) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup()).context("Failed to handle SIGHUP")?;
+        loop {
+            let force = tokio::select! {
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading TLS files");
+                    true
+                }
+                _ = tokio::time::sleep(interval) => false,
+            };
+            for (idx, source) in self.sources.iter().enumerate() {
+                if let Err(err) = source.reload_tls(force) {
+                    warn!("QKD key source #{idx}: {err:?}");
+                }
+            }
+        }
+    }
+
+    /// Fetch a fresh key from every key source that has not delivered one to `keys` yet
+    ///
+    /// The n-th key in `keys` comes from the n-th key source. If a key source fails, the
+    /// keys fetched so far stay in `keys`, so calling this again only asks the key sources
+    /// that are still missing instead of consuming more keys from the others.
+    pub async fn fetch_any_keys(&self, keys: &mut Vec<QkdKey>) -> Result<()> {
+        for (idx, source) in self.sources.iter().enumerate().skip(keys.len()) {
+            let key = source
+                .fetch_any_key()
+                .await
+                .with_context(|| format!("Failed to fetch key from QKD key source #{idx}"))?;
+            keys.push(key);
+        }
+        Ok(())
+    }
+
+    /// Fetch the keys with the given IDs; one from every key source, in order
+    pub async fn fetch_specific_keys(&self, ids: &[Uuid]) -> Result<Vec<QkdKey>> {
+        ensure!(
+            ids.len() == self.sources.len(),
+            "Peer requested {} QKD keys, but {} key sources are configured. \
+            Both peers need to use the same key sources.",
+            ids.len(),
+            self.sources.len()
+        );
+        let mut keys = Vec::with_capacity(self.sources.len());
+        for (idx, (source, id)) in self.sources.iter().zip(ids).enumerate() {
+            let key = source
+                .fetch_specific_key(*id)
+                .await
+                .with_context(|| format!("Failed to fetch key from QKD key source #{idx}"))?;
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Key source that counts the keys requested and fails while `fail` is set
+    #[derive(Default)]
+    struct TestSource {
+        fail: AtomicBool,
+        requests: AtomicUsize,
+    }
+
+    impl QkdKeySource for TestSource {
+        async fn fetch_any_key(&self) -> Result<QkdKey> {
+            let n = self.requests.fetch_add(1, Ordering::SeqCst);
+            ensure!(!self.fail.load(Ordering::SeqCst), "KME unavailable");
+            Ok(QkdKey {
+                id: Uuid::from_u128(n as u128),
+                key: Zeroizing::new(vec![0; 32]),
+            })
+        }
+
+        async fn fetch_specific_key(&self, _id: Uuid) -> Result<QkdKey> {
+            unimplemented!()
+        }
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Config {
+        etsi014: KeySourceConfigs<Etsi014Config>,
+    }
+
+    fn parse(toml: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn single_section() {
+        let config = parse(
+            r#"
+            [etsi014]
+            url = "https://kme.example"
+            remote_sae_id = "bob"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(config.etsi014, KeySourceConfigs::Single(_)));
+        assert_eq!(config.etsi014.as_slice().len(), 1);
+    }
+
+    #[test]
+    fn list_of_sections() {
+        let config = parse(
+            r#"
+            [[etsi014]]
+            url = "https://kme1.example"
+            [[etsi014]]
+            url = "https://kme2.example"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(config.etsi014, KeySourceConfigs::Multiple(_)));
+        assert_eq!(config.etsi014.as_slice().len(), 2);
+    }
+
+    #[test]
+    fn field_errors_of_a_single_section_are_reported() {
+        let err = parse(
+            r#"
+            [etsi014]
+            ulr = "https://kme.example"
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("missing field `url`"), "{err}");
+    }
+
+    #[test]
+    fn field_errors_of_a_list_are_reported() {
+        let err = parse(
+            r#"
+            [[etsi014]]
+            url = "https://kme.example"
+            [etsi014.key_pool]
+            sise = 4
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown field `sise`"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn retry_only_asks_failed_key_sources() {
+        let set = KeySourceSet::new(vec![TestSource::default(), TestSource::default()]).unwrap();
+        set.sources[1].fail.store(true, Ordering::SeqCst);
+
+        let mut keys = Vec::new();
+        let err = set.fetch_any_keys(&mut keys).await.unwrap_err();
+        assert!(format!("{err:#}").contains("key source #1"), "{err:#}");
+        assert_eq!(keys.len(), 1);
+
+        set.sources[1].fail.store(false, Ordering::SeqCst);
+        set.fetch_any_keys(&mut keys).await.unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(set.sources[0].requests.load(Ordering::SeqCst), 1);
+        assert_eq!(set.sources[1].requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn other_values_are_rejected() {
+        let err = parse(r#"etsi014 = "https://kme.example""#).unwrap_err();
+        assert!(
+            err.to_string().contains("a key source section or a list"),
+            "{err}"
+        );
+    }
+}
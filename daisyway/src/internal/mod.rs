@@ -1,4 +1,9 @@
 pub mod daisyway;
+pub mod etsi004;
 pub mod etsi014;
+pub mod key_pool;
+pub mod key_source;
 pub mod osk;
+pub mod pkcs11;
+pub mod tls;
 pub mod util;
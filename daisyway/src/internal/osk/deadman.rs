@@ -1,14 +1,25 @@
 use std::{future::Future, time::Duration};
 
-use anyhow::Result;
-use tokio::{sync::mpsc, time::timeout_at};
+use anyhow::{Context, Result};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{timeout_at, Instant},
+};
 
 use super::{OskHandler, SetOskReason};
 use crate::internal::daisyway::crypto::Key;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Time after which a failed erasure of the output key is retried
+const ERASE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
 enum DeadmanRequest {
-    SetOsk { key: Key, reason: SetOskReason },
+    SetOsk {
+        key: Key,
+        reason: SetOskReason,
+        /// Receives the result of the underlying handler
+        reply: oneshot::Sender<Result<()>>,
+    },
 }
 
 /// [OskHandler] that automatically erases output keys.
@@ -22,6 +33,10 @@ enum DeadmanRequest {
 ///
 /// Once all [OskDeadman] instances have been dropped, the worker thread will automatically be
 /// closed and the OSK will be erased.
+///
+/// Errors of the underlying handler do not stop the worker: a failed update is reported to
+/// the caller of [OskHandler::set_osk] and leaves the previous key in place until it expires,
+/// and a failed erasure is logged and retried after a few seconds.
 #[derive(Debug, Clone)]
 pub struct OskDeadman {
     client: mpsc::Sender<DeadmanRequest>,
@@ -38,10 +53,13 @@ impl OskDeadman {
     }
 
     async fn set_osk_impl(&self, key: Key, reason: SetOskReason) -> Result<()> {
+        let (reply, result) = oneshot::channel();
         self.client
-            .send(DeadmanRequest::SetOsk { key, reason })
+            .send(DeadmanRequest::SetOsk { key, reason, reply })
             .await?;
-        Ok(())
+        result
+            .await
+            .context("Output key worker exited without setting the key")?
     }
 }
 
@@ -95,28 +113,118 @@ where
     }
 
     async fn event_loop(&mut self) -> Result<()> {
-        log::trace!("Shutting down internal output key broker. Erasing output key.");
-        self.broker.erase_stale_osk().await?;
+        log::trace!("Starting internal output key broker. Erasing output key.");
+        let mut next_erase = self.erase().await;
 
-        let mut next_erase;
         loop {
-            next_erase = tokio::time::Instant::now() + self.erase_after;
             let req = timeout_at(next_erase, self.requests.recv()).await.ok();
             match req {
-                Some(Some(DeadmanRequest::SetOsk { key, reason })) => {
+                Some(Some(DeadmanRequest::SetOsk { key, reason, reply })) => {
                     log::debug!("Output key DeadmanWorker received SetOsk request – updating OSK.");
-                    self.broker.set_osk(key, reason).await?;
+                    let res = self.broker.set_osk(key, reason).await;
+                    if res.is_ok() {
+                        next_erase = Instant::now() + self.erase_after;
+                    }
+                    // The caller may have given up waiting
+                    let _ = reply.send(res);
                 }
                 Some(None) => {
                     log::info!("Shutting down internal output key broker. Erasing output key.");
-                    self.broker.erase_stale_osk().await?;
+                    self.erase().await;
                     return Ok(());
                 }
                 None => {
                     log::warn!("Output key lifetime ended – erasing key");
-                    self.broker.erase_stale_osk().await?;
+                    next_erase = self.erase().await;
                 }
             }
         }
     }
+
+    /// Erase the output key; returns the time of the next erasure
+    async fn erase(&mut self) -> Instant {
+        match self.broker.erase_stale_osk().await {
+            Ok(()) => Instant::now() + self.erase_after,
+            Err(err) => {
+                log::error!(
+                    "Failed to erase the output key; retrying in {}s: {err:?}",
+                    ERASE_RETRY_INTERVAL.as_secs()
+                );
+                Instant::now() + ERASE_RETRY_INTERVAL
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use anyhow::ensure;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    struct TestBroker {
+        fail: Arc<AtomicBool>,
+        attempts: Arc<AtomicUsize>,
+        keys: Arc<Mutex<Vec<(Key, SetOskReason)>>>,
+    }
+
+    impl OskHandler for TestBroker {
+        async fn set_osk(&self, key: Key, reason: SetOskReason) -> Result<()> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            ensure!(!self.fail.load(Ordering::Relaxed), "WireGuard is gone");
+            self.keys.lock().unwrap().push((key, reason));
+            Ok(())
+        }
+    }
+
+    /// Wait until the broker received the given number of keys
+    async fn wait_for_keys(broker: &TestBroker, count: usize) {
+        for _ in 0..500 {
+            if broker.keys.lock().unwrap().len() >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("The broker did not receive {count} keys");
+    }
+
+    #[tokio::test]
+    async fn worker_survives_broker_errors() {
+        let broker = TestBroker::default();
+        broker.fail.store(true, Ordering::Relaxed);
+        let worker_broker = broker.clone();
+        let deadman = OskDeadman::start(Duration::from_secs(3600), move || worker_broker);
+
+        let err = deadman.set_fresh_osk([1; 32]).await.unwrap_err();
+        assert!(format!("{err:#}").contains("WireGuard is gone"), "{err:#}");
+        // The initial erasure and the first key failed
+        assert_eq!(broker.attempts.load(Ordering::Relaxed), 2);
+        broker.fail.store(false, Ordering::Relaxed);
+        deadman.set_fresh_osk([2; 32]).await.unwrap();
+
+        assert_eq!(
+            *broker.keys.lock().unwrap(),
+            [([2; 32], SetOskReason::Fresh)]
+        );
+    }
+
+    #[tokio::test]
+    async fn key_is_erased_after_its_lifetime() {
+        let broker = TestBroker::default();
+        let worker_broker = broker.clone();
+        let deadman = OskDeadman::start(Duration::from_millis(200), move || worker_broker);
+
+        deadman.set_fresh_osk([1; 32]).await.unwrap();
+        wait_for_keys(&broker, 3).await;
+        let keys = broker.keys.lock().unwrap();
+        assert_eq!(keys[0].1, SetOskReason::Stale);
+        assert_eq!(keys[1], ([1; 32], SetOskReason::Fresh));
+        assert_eq!(keys[2].1, SetOskReason::Stale);
+    }
 }
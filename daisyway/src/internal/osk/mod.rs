@@ -9,9 +9,11 @@ use crate::internal::daisyway::crypto::Key;
 
 mod deadman;
 mod outfile;
+mod userspace_wireguard;
 
 pub use deadman::*;
 pub use outfile::*;
+pub use userspace_wireguard::*;
 
 #[cfg(target_os = "linux")]
 mod wireguard;
@@ -0,0 +1,302 @@
+use std::{
+    fmt::Write as _,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+use zeroize::Zeroizing;
+
+use super::{OskHandler, SetOskReason};
+use crate::internal::{
+    daisyway::crypto::Key,
+    util::{base64_to_key, extend_zeroizing, key_to_base64},
+};
+
+/// Directory containing the UAPI sockets of userspace WireGuard implementations
+pub const USERSPACE_WIREGUARD_SOCKET_DIR: &str = "/var/run/wireguard";
+
+/// Time allowed for a single request to the UAPI socket
+const UAPI_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keys of the response lines we use; the others, e.g. the private key of the interface,
+/// are skipped
+const UAPI_RESPONSE_KEYS: &[&str] = &["public_key", "errno"];
+
+/// Sets the PSK through the text UAPI of userspace WireGuard implementations
+///
+/// This is used for wireguard-go and boringtun, which expose the UAPI on
+/// `/var/run/wireguard/<interface>.sock`. Since not every implementation supports
+/// `update_only`, the peer is looked up before every update, so a removed peer is not
+/// recreated.
+#[derive(Debug, Clone)]
+pub struct UserspaceWireGuardOskHandler {
+    pub socket_path: PathBuf,
+    pub peer_id: Key,
+}
+
+impl UserspaceWireGuardOskHandler {
+    /// The UAPI socket of the given interface
+    pub fn default_socket_path(interface: &str) -> PathBuf {
+        Path::new(USERSPACE_WIREGUARD_SOCKET_DIR).join(format!("{interface}.sock"))
+    }
+
+    pub async fn setup(socket_path: &Path, peer_id: &str) -> Result<Self> {
+        let peer_id_u8 = base64_to_key(peer_id.as_bytes())
+            .with_context(|| format!("Invalid WireGuard peer id {peer_id:?}"))?;
+        let handler = Self {
+            socket_path: socket_path.to_owned(),
+            peer_id: peer_id_u8,
+        };
+        ensure!(
+            handler.peer_exists().await?,
+            "Could not find WireGuard peer {peer_id}"
+        );
+        Ok(handler)
+    }
+
+    /// The public keys of all peers of the interface
+    pub async fn peers(socket_path: &Path) -> Result<Vec<Key>> {
+        let response = uapi_request(socket_path, "get=1\n\n").await?;
+        response
+            .iter()
+            .filter(|(key, _)| key == "public_key")
+            .map(|(_, value)| decode_hex_key(value))
+            .collect()
+    }
+
+    async fn peer_exists(&self) -> Result<bool> {
+        let peers = Self::peers(&self.socket_path).await?;
+        Ok(peers.contains(&self.peer_id))
+    }
+
+    async fn set_osk_impl(&self, key: Key, reason: SetOskReason) -> Result<()> {
+        use SetOskReason as R;
+        let socket_path = &self.socket_path;
+        match reason {
+            R::Fresh => info!("Injecting fresh PSK into userspace WireGuard at {socket_path:?}"),
+            R::Stale => error!(
+                "Erasing stale PSK in userspace WireGuard at {socket_path:?} by overwriting with a random key"
+            ),
+        };
+
+        if !self.peer_exists().await? {
+            let peer_id = key_to_base64(&self.peer_id);
+            match reason {
+                R::Fresh => bail!("WireGuard peer {peer_id} no longer exists"),
+                R::Stale => {
+                    warn!("WireGuard peer {peer_id} has been removed; there is no PSK to erase");
+                    return Ok(());
+                }
+            }
+        }
+
+        let request = Zeroizing::new(format!(
+            "set=1\npublic_key={}\npreshared_key={}\n\n",
+            encode_hex(&self.peer_id),
+            *Zeroizing::new(encode_hex(&key)),
+        ));
+        uapi_request(socket_path, &request).await?;
+
+        Ok(())
+    }
+}
+
+impl OskHandler for UserspaceWireGuardOskHandler {
+    fn set_osk(&self, key: Key, reason: SetOskReason) -> impl Future<Output = Result<()>> {
+        self.set_osk_impl(key, reason)
+    }
+}
+
+/// Send a request to the UAPI socket and return the key-value pairs of the response
+///
+/// Only the lines listed in [UAPI_RESPONSE_KEYS] are returned; the response is read into
+/// memory that is zeroized when dropped. Fails if the response carries a nonzero `errno`.
+async fn uapi_request(socket_path: &Path, request: &str) -> Result<Vec<(String, String)>> {
+    let exchange = async {
+        let mut stream = UnixStream::connect(socket_path).await?;
+        stream.write_all(request.as_bytes()).await?;
+
+        // The response ends with an empty line
+        let mut raw = Zeroizing::new(Vec::new());
+        let mut chunk = Zeroizing::new([0u8; 4096]);
+        while !raw.ends_with(b"\n\n") {
+            let len = stream.read(&mut chunk[..]).await?;
+            ensure!(len > 0, "Connection closed before the end of the response");
+            extend_zeroizing(&mut raw, &chunk[..len]);
+        }
+
+        let mut response = Vec::new();
+        let lines = std::str::from_utf8(&raw).context("Response is not valid UTF-8")?;
+        for line in lines.lines().take_while(|line| !line.is_empty()) {
+            // The line is not logged, as it may hold a key
+            let (key, value) = line.split_once('=').context("Invalid response line")?;
+            if UAPI_RESPONSE_KEYS.contains(&key) {
+                response.push((key.to_owned(), value.to_owned()));
+            }
+        }
+        anyhow::Ok(response)
+    };
+    let response = tokio::time::timeout(UAPI_TIMEOUT, exchange)
+        .await
+        .context("Timed out")
+        .and_then(|res| res)
+        .with_context(|| format!("Failed to access WireGuard UAPI socket {socket_path:?}"))?;
+
+    match response.last() {
+        Some((key, errno)) if key == "errno" => {
+            ensure!(
+                errno == "0",
+                "WireGuard UAPI socket {socket_path:?} returned errno {errno}"
+            );
+        }
+        _ => bail!("WireGuard UAPI socket {socket_path:?} did not return an errno"),
+    }
+    Ok(response)
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * data.len());
+    for byte in data {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
+
+fn decode_hex_key(hex: &str) -> Result<Key> {
+    let mut key = Key::default();
+    ensure!(
+        hex.len() == 2 * key.len() && hex.is_ascii(),
+        "Invalid WireGuard key {hex:?}"
+    );
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .with_context(|| format!("Invalid WireGuard key {hex:?}"))?;
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    const PEER: Key = [0xab; 32];
+    const OTHER_PEER: Key = [0xcd; 32];
+
+    /// Socket answering one connection after another with the given responses
+    ///
+    /// Returns the socket path and the requests received so far.
+    fn fake_uapi(test: &str, responses: Vec<String>) -> (PathBuf, Arc<Mutex<Vec<String>>>) {
+        let path =
+            std::env::temp_dir().join(format!("daisyway-uapi-{test}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\n\n") {
+                    let mut buf = [0u8; 1024];
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                }
+                received
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8(request).unwrap());
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (path, requests)
+    }
+
+    /// Response to `get=1` for an interface with the given peers
+    fn get_response(peers: &[Key]) -> String {
+        let mut response = format!("private_key={}\nlisten_port=51820\n", encode_hex(&[7; 32]));
+        for peer in peers {
+            response += &format!(
+                "public_key={}\npreshared_key={}\nallowed_ip=10.0.0.2/32\n",
+                encode_hex(peer),
+                encode_hex(&[9; 32])
+            );
+        }
+        response + "errno=0\n\n"
+    }
+
+    fn handler(socket_path: PathBuf) -> UserspaceWireGuardOskHandler {
+        UserspaceWireGuardOskHandler {
+            socket_path,
+            peer_id: PEER,
+        }
+    }
+
+    #[tokio::test]
+    async fn get_returns_only_public_keys() {
+        let response = get_response(&[PEER, OTHER_PEER]);
+        let (path, requests) = fake_uapi("get", vec![response.clone(), response]);
+
+        let peers = UserspaceWireGuardOskHandler::peers(&path).await.unwrap();
+        assert_eq!(peers, [PEER, OTHER_PEER]);
+        assert_eq!(*requests.lock().unwrap(), ["get=1\n\n"]);
+
+        let lines = uapi_request(&path, "get=1\n\n").await.unwrap();
+        assert!(lines
+            .iter()
+            .all(|(key, _)| UAPI_RESPONSE_KEYS.contains(&key.as_str())));
+    }
+
+    #[tokio::test]
+    async fn fresh_key_is_set() {
+        let responses = vec![get_response(&[PEER]), "errno=0\n\n".to_owned()];
+        let (path, requests) = fake_uapi("set", responses);
+
+        handler(path)
+            .set_osk([0x11; 32], SetOskReason::Fresh)
+            .await
+            .unwrap();
+        let expected = format!(
+            "set=1\npublic_key={}\npreshared_key={}\n\n",
+            encode_hex(&PEER),
+            encode_hex(&[0x11; 32])
+        );
+        assert_eq!(requests.lock().unwrap()[1], expected);
+    }
+
+    #[tokio::test]
+    async fn missing_peer_is_not_recreated() {
+        let responses = vec![get_response(&[OTHER_PEER]), get_response(&[OTHER_PEER])];
+        let (path, requests) = fake_uapi("missing", responses);
+        let handler = handler(path);
+
+        assert!(handler
+            .set_osk([0x11; 32], SetOskReason::Fresh)
+            .await
+            .is_err());
+        handler.erase_stale_osk().await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), ["get=1\n\n", "get=1\n\n"]);
+    }
+
+    #[tokio::test]
+    async fn nonzero_errno_fails() {
+        let responses = vec![get_response(&[PEER]), "errno=-22\n\n".to_owned()];
+        let (path, _) = fake_uapi("errno", responses);
+
+        let err = handler(path)
+            .set_osk([0x11; 32], SetOskReason::Fresh)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("errno -22"), "{err:#}");
+    }
+}
@@ -5,7 +5,6 @@ use std::{
 
 use anyhow::{ensure, Context, Result};
 use log::{error, info};
-#[cfg(target_os = "linux")]
 use wireguard_uapi::{DeviceInterface, WgSocket};
 
 use super::{OskHandler, SetOskReason};
@@ -19,24 +18,25 @@ pub struct WireGuardOskHandler {
 }
 
 impl WireGuardOskHandler {
-    pub fn setup(peer_id: &str, interface: &str) -> Result<Self> {
-        let peer_id_u8 = base64_to_key(peer_id.as_bytes()).expect("Invalid peer_id");
-
-        #[cfg(target_os = "linux")]
-        let socket = {
-            let mut socket =
-                WgSocket::connect().context("Failed to connect to WireGuard control socket.")?;
+    /// Connect to the WireGuard netlink interface; the socket can be shared by several peers
+    pub fn connect() -> Result<Arc<Mutex<WgSocket>>> {
+        let socket =
+            WgSocket::connect().context("Failed to connect to WireGuard control socket.")?;
+        Ok(Arc::new(Mutex::new(socket)))
+    }
 
-            let device = socket
-                .get_device(DeviceInterface::from_name(interface.to_string()))
-                .with_context(|| format!("Failed to access WireGuard interface {interface}"))?;
-            ensure!(
-                device.peers.iter().any(|p| p.public_key == peer_id_u8),
-                "Could not find WireGuard peer {peer_id}"
-            );
+    pub fn setup(socket: Arc<Mutex<WgSocket>>, peer_id: &str, interface: &str) -> Result<Self> {
+        let peer_id_u8 = base64_to_key(peer_id.as_bytes()).expect("Invalid peer_id");
 
-            Arc::new(Mutex::new(socket))
-        };
+        let device = socket
+            .lock()
+            .unwrap()
+            .get_device(DeviceInterface::from_name(interface.to_string()))
+            .with_context(|| format!("Failed to access WireGuard interface {interface}"))?;
+        ensure!(
+            device.peers.iter().any(|p| p.public_key == peer_id_u8),
+            "Could not find WireGuard peer {peer_id}"
+        );
 
         Ok(Self {
             socket,
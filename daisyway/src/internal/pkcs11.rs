@@ -0,0 +1,500 @@
+//! TLS client keys stored on a PKCS#11 token
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use cryptoki::{
+    context::{CInitializeArgs, CInitializeFlags, Pkcs11},
+    error::{Error as Pkcs11Error, RvError},
+    mechanism::{
+        rsa::{PkcsMgfType, PkcsPssParams},
+        Mechanism, MechanismType,
+    },
+    object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+    types::{AuthPin, Ulong},
+};
+use log::info;
+use rustls::{
+    pki_types::CertificateDer,
+    sign::{CertifiedKey, Signer, SigningKey},
+    SignatureAlgorithm, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::internal::tls::read_secret;
+
+/// DER encoded OIDs of the supported elliptic curves, as found in `CKA_EC_PARAMS`
+const SECP256R1_PARAMS: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const SECP384R1_PARAMS: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// Signature schemes offered for RSA keys, in order of preference
+const RSA_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA512,
+    SignatureScheme::RSA_PKCS1_SHA256,
+    SignatureScheme::RSA_PKCS1_SHA384,
+    SignatureScheme::RSA_PKCS1_SHA512,
+];
+
+/// A PKCS#11 module may only be initialized once per process, so reloads reuse it
+static MODULES: Mutex<Vec<(PathBuf, Pkcs11)>> = Mutex::new(Vec::new());
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Pkcs11Options {
+    /// Shared library implementing PKCS#11, e.g. the SoftHSM module
+    module: PathBuf,
+    /// Label of the token holding the key; may be omitted if there is a single token
+    token_label: Option<String>,
+    /// Label (`CKA_LABEL`) of the private key
+    key_label: Option<String>,
+    /// Hex encoded identifier (`CKA_ID`) of the private key
+    key_id: Option<String>,
+    /// File containing the user PIN
+    pin_file: Option<PathBuf>,
+    /// Environment variable containing the user PIN
+    pin_env: Option<String>,
+}
+
+impl Pkcs11Options {
+    /// Files to watch for changes besides the token itself
+    pub fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.pin_file.iter()
+    }
+
+    /// Open a session on the token and find the private key
+    ///
+    /// The certificate chain is read from the token, using the certificate with the `CKA_ID`
+    /// of the key, unless it is given.
+    pub fn load(&self, cert_chain: Option<Vec<CertificateDer<'static>>>) -> Result<CertifiedKey> {
+        let pkcs11 = module(&self.module)?;
+        let slot = self.find_slot(&pkcs11)?;
+        let session = pkcs11
+            .open_ro_session(slot)
+            .context("Failed to open PKCS#11 session")?;
+
+        let pin = read_secret(
+            self.pin_file.as_deref(),
+            self.pin_env.as_deref(),
+            "PKCS#11 PIN",
+        )?;
+        if let Some(pin) = pin {
+            match session.login(UserType::User, Some(&AuthPin::from(pin.as_str()))) {
+                // Login state is shared by all sessions, e.g. with the key loaded before a reload
+                Ok(()) | Err(Pkcs11Error::Pkcs11(RvError::UserAlreadyLoggedIn, _)) => {}
+                Err(err) => return Err(err).context("Failed to log into PKCS#11 token"),
+            }
+        }
+
+        let mut template = vec![Attribute::Class(ObjectClass::PRIVATE_KEY)];
+        if let Some(label) = &self.key_label {
+            template.push(Attribute::Label(label.as_bytes().to_vec()));
+        }
+        if let Some(id) = &self.key_id {
+            template.push(Attribute::Id(decode_hex(id)?));
+        }
+        let key = single_object(&session, &template, "private key")?;
+
+        let attributes = [AttributeType::KeyType, AttributeType::Id];
+        let mut key_type = None;
+        let mut key_id = Vec::new();
+        for attribute in session.get_attributes(key, &attributes)? {
+            match attribute {
+                Attribute::KeyType(value) => key_type = Some(value),
+                Attribute::Id(value) => key_id = value,
+                _ => {}
+            }
+        }
+        let kind = match key_type {
+            Some(KeyType::RSA) => KeyKind::Rsa,
+            Some(KeyType::EC) => {
+                let params = match session.get_attributes(key, &[AttributeType::EcParams])?[..] {
+                    [Attribute::EcParams(ref params)] => params.clone(),
+                    _ => bail!("PKCS#11 EC key has no curve parameters"),
+                };
+                match &params[..] {
+                    SECP256R1_PARAMS => KeyKind::Ecdsa(SignatureScheme::ECDSA_NISTP256_SHA256),
+                    SECP384R1_PARAMS => KeyKind::Ecdsa(SignatureScheme::ECDSA_NISTP384_SHA384),
+                    _ => bail!("Unsupported curve of PKCS#11 EC key; use P-256 or P-384"),
+                }
+            }
+            other => bail!("Unsupported PKCS#11 key type {other:?}; use an RSA or EC key"),
+        };
+
+        let cert_chain = match cert_chain {
+            Some(cert_chain) => cert_chain,
+            None => {
+                let template = [
+                    Attribute::Class(ObjectClass::CERTIFICATE),
+                    Attribute::Id(key_id),
+                ];
+                let cert = single_object(&session, &template, "certificate")?;
+                match session.get_attributes(cert, &[AttributeType::Value])?[..] {
+                    [Attribute::Value(ref der)] => vec![CertificateDer::from(der.clone())],
+                    _ => bail!("PKCS#11 certificate has no value"),
+                }
+            }
+        };
+
+        info!(
+            "Using client authentification with a {kind:?} key on PKCS#11 token {:?}",
+            self.token_label.as_deref().unwrap_or("(any)")
+        );
+        let key = Pkcs11Key {
+            inner: Arc::new(Pkcs11KeyInner {
+                session: Mutex::new(session),
+                key,
+                kind,
+            }),
+        };
+        Ok(CertifiedKey::new(cert_chain, Arc::new(key)))
+    }
+
+    fn find_slot(&self, pkcs11: &Pkcs11) -> Result<cryptoki::slot::Slot> {
+        let mut slots = Vec::new();
+        for slot in pkcs11.get_slots_with_token()? {
+            let info = pkcs11.get_token_info(slot)?;
+            if self
+                .token_label
+                .as_ref()
+                .is_none_or(|label| info.label() == label)
+            {
+                slots.push(slot);
+            }
+        }
+        match (&slots[..], &self.token_label) {
+            ([slot], _) => Ok(*slot),
+            ([], Some(label)) => bail!("No PKCS#11 token labeled {label:?} found"),
+            ([], None) => bail!("No PKCS#11 token found"),
+            (_, _) => bail!("Found several PKCS#11 tokens; set token_label"),
+        }
+    }
+}
+
+/// Load and initialize a PKCS#11 module, or return the one loaded before
+fn module(path: &Path) -> Result<Pkcs11> {
+    let mut modules = MODULES.lock().unwrap();
+    if let Some((_, pkcs11)) = modules.iter().find(|(p, _)| p == path) {
+        return Ok(pkcs11.clone());
+    }
+
+    let pkcs11 =
+        Pkcs11::new(path).with_context(|| format!("Failed to load PKCS#11 module {path:?}"))?;
+    pkcs11
+        .initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK))
+        .with_context(|| format!("Failed to initialize PKCS#11 module {path:?}"))?;
+    modules.push((path.to_owned(), pkcs11.clone()));
+    Ok(pkcs11)
+}
+
+fn single_object(session: &Session, template: &[Attribute], what: &str) -> Result<ObjectHandle> {
+    match session.find_objects(template)?[..] {
+        [object] => Ok(object),
+        [] => bail!("No matching PKCS#11 {what} found"),
+        _ => bail!("Found several matching PKCS#11 {what}s; set key_label or key_id"),
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    ensure!(
+        hex.len().is_multiple_of(2) && hex.is_ascii(),
+        "Invalid hex string {hex:?}"
+    );
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .with_context(|| format!("Invalid hex string {hex:?}"))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum KeyKind {
+    Rsa,
+    /// The only scheme usable with the curve of the key
+    Ecdsa(SignatureScheme),
+}
+
+#[derive(Debug)]
+struct Pkcs11KeyInner {
+    /// Sessions must not be used concurrently
+    session: Mutex<Session>,
+    key: ObjectHandle,
+    kind: KeyKind,
+}
+
+/// Private key that never leaves the token; signatures are computed by the token
+#[derive(Debug)]
+struct Pkcs11Key {
+    inner: Arc<Pkcs11KeyInner>,
+}
+
+impl SigningKey for Pkcs11Key {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        let scheme = match self.inner.kind {
+            KeyKind::Rsa => *RSA_SCHEMES.iter().find(|scheme| offered.contains(scheme))?,
+            KeyKind::Ecdsa(scheme) => *offered.iter().find(|&&s| s == scheme)?,
+        };
+        Some(Box::new(Pkcs11Signer {
+            key: self.inner.clone(),
+            scheme,
+        }))
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        match self.inner.kind {
+            KeyKind::Rsa => SignatureAlgorithm::RSA,
+            KeyKind::Ecdsa(_) => SignatureAlgorithm::ECDSA,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pkcs11Signer {
+    key: Arc<Pkcs11KeyInner>,
+    scheme: SignatureScheme,
+}
+
+impl Pkcs11Signer {
+    fn pss(hash_alg: MechanismType, mgf: PkcsMgfType, salt_len: usize) -> PkcsPssParams {
+        PkcsPssParams {
+            hash_alg,
+            mgf,
+            s_len: Ulong::new(salt_len as _),
+        }
+    }
+}
+
+impl Signer for Pkcs11Signer {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        use ring::digest::{digest, SHA256, SHA384};
+        use MechanismType as M;
+
+        // Tokens commonly lack hashing ECDSA mechanisms, so ECDSA messages are hashed here
+        let ecdsa_digest;
+        let (mechanism, data) = match self.scheme {
+            SignatureScheme::RSA_PSS_SHA256 => (
+                Mechanism::Sha256RsaPkcsPss(Self::pss(M::SHA256, PkcsMgfType::MGF1_SHA256, 32)),
+                message,
+            ),
+            SignatureScheme::RSA_PSS_SHA384 => (
+                Mechanism::Sha384RsaPkcsPss(Self::pss(M::SHA384, PkcsMgfType::MGF1_SHA384, 48)),
+                message,
+            ),
+            SignatureScheme::RSA_PSS_SHA512 => (
+                Mechanism::Sha512RsaPkcsPss(Self::pss(M::SHA512, PkcsMgfType::MGF1_SHA512, 64)),
+                message,
+            ),
+            SignatureScheme::RSA_PKCS1_SHA256 => (Mechanism::Sha256RsaPkcs, message),
+            SignatureScheme::RSA_PKCS1_SHA384 => (Mechanism::Sha384RsaPkcs, message),
+            SignatureScheme::RSA_PKCS1_SHA512 => (Mechanism::Sha512RsaPkcs, message),
+            SignatureScheme::ECDSA_NISTP256_SHA256 => {
+                ecdsa_digest = digest(&SHA256, message);
+                (Mechanism::Ecdsa, ecdsa_digest.as_ref())
+            }
+            SignatureScheme::ECDSA_NISTP384_SHA384 => {
+                ecdsa_digest = digest(&SHA384, message);
+                (Mechanism::Ecdsa, ecdsa_digest.as_ref())
+            }
+            scheme => {
+                return Err(rustls::Error::General(format!(
+                    "Unsupported signature scheme {scheme:?}"
+                )))
+            }
+        };
+
+        let signature = self
+            .key
+            .session
+            .lock()
+            .unwrap()
+            .sign(&mechanism, self.key.key, data)
+            .map_err(|err| rustls::Error::General(format!("PKCS#11 signing failed: {err}")))?;
+
+        match self.key.kind {
+            KeyKind::Rsa => Ok(signature),
+            KeyKind::Ecdsa(_) => ecdsa_signature_to_der(&signature),
+        }
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+/// Convert an ECDSA signature from the PKCS#11 format `r || s` to the DER format used by TLS
+fn ecdsa_signature_to_der(signature: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+    fn integer(out: &mut Vec<u8>, bytes: &[u8]) {
+        let bytes = &bytes[bytes.iter().take_while(|&&b| b == 0).count()..];
+        let pad = bytes.first().is_none_or(|&b| b & 0x80 != 0);
+        out.push(0x02);
+        out.push((bytes.len() + pad as usize) as u8);
+        if pad {
+            out.push(0);
+        }
+        out.extend_from_slice(bytes);
+    }
+
+    // Short form DER lengths suffice for the supported curves
+    if signature.is_empty() || !signature.len().is_multiple_of(2) || signature.len() > 2 * 60 {
+        return Err(rustls::Error::General(
+            "Invalid ECDSA signature from PKCS#11 token".to_string(),
+        ));
+    }
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let mut body = Vec::with_capacity(signature.len() + 6);
+    integer(&mut body, r);
+    integer(&mut body, s);
+
+    let mut der = vec![0x30, body.len() as u8];
+    der.extend_from_slice(&body);
+    Ok(der)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn der_signature_of_small_integers() {
+        let mut signature = [0u8; 64];
+        signature[31] = 0x01;
+        signature[63] = 0x7f;
+        assert_eq!(
+            ecdsa_signature_to_der(&signature).unwrap(),
+            [0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x7f]
+        );
+    }
+
+    #[test]
+    fn der_signature_pads_high_bit() {
+        let mut signature = [0x11u8; 64];
+        signature[0] = 0x80;
+        signature[32] = 0xff;
+        let der = ecdsa_signature_to_der(&signature).unwrap();
+        assert_eq!(der[..2], [0x30, 70]);
+        assert_eq!(der[2..5], [0x02, 33, 0x00]);
+        assert_eq!(der[5..37], signature[..32]);
+        assert_eq!(der[37..40], [0x02, 33, 0x00]);
+        assert_eq!(der[40..], signature[32..]);
+    }
+
+    #[test]
+    fn der_signature_strips_leading_zeros() {
+        let mut signature = [0x22u8; 96];
+        signature[..2].fill(0);
+        signature[2] = 0x90;
+        signature[48] = 0;
+        signature[49] = 0x05;
+        let der = ecdsa_signature_to_der(&signature).unwrap();
+        // r keeps one zero byte before its high bit, s loses its only leading zero
+        assert_eq!(der[..2], [0x30, 2 + 47 + 2 + 47]);
+        assert_eq!(der[2..5], [0x02, 47, 0x00]);
+        assert_eq!(der[5..51], signature[2..48]);
+        assert_eq!(der[51..53], [0x02, 47]);
+        assert_eq!(der[53..], signature[49..]);
+    }
+
+    #[test]
+    fn der_signature_of_zero() {
+        assert_eq!(
+            ecdsa_signature_to_der(&[0; 64]).unwrap(),
+            [0x30, 0x06, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn der_signature_rejects_invalid_lengths() {
+        assert!(ecdsa_signature_to_der(&[]).is_err());
+        assert!(ecdsa_signature_to_der(&[1; 63]).is_err());
+        assert!(ecdsa_signature_to_der(&[1; 122]).is_err());
+    }
+
+    /// Signs with a P-256 key generated on a fresh SoftHSM token
+    ///
+    /// Run with `cargo test -- --ignored`; set `SOFTHSM2_MODULE` if the module is not installed
+    /// at the Debian location.
+    #[test]
+    #[ignore = "requires SoftHSM"]
+    fn softhsm_ecdsa_signature() {
+        use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+        let module_path = std::env::var_os("SOFTHSM2_MODULE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| "/usr/lib/softhsm/libsofthsm2.so".into());
+        let dir = std::env::temp_dir().join(format!("daisyway-softhsm-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("tokens")).unwrap();
+        let conf = dir.join("softhsm2.conf");
+        std::fs::write(
+            &conf,
+            format!("directories.tokendir = {}\n", dir.join("tokens").display()),
+        )
+        .unwrap();
+        std::env::set_var("SOFTHSM2_CONF", &conf);
+        let pin_file = dir.join("pin");
+        std::fs::write(&pin_file, "1234").unwrap();
+
+        let pkcs11 = module(&module_path).unwrap();
+        let slot = pkcs11.get_all_slots().unwrap()[0];
+        let so_pin = AuthPin::from("5678");
+        pkcs11.init_token(slot, &so_pin, "daisyway").unwrap();
+        // SoftHSM moves an initialized token to a new slot
+        let slot = pkcs11.get_slots_with_token().unwrap()[0];
+        let session = pkcs11.open_rw_session(slot).unwrap();
+        session.login(UserType::So, Some(&so_pin)).unwrap();
+        session.init_pin(&AuthPin::from("1234")).unwrap();
+        session.logout().unwrap();
+        session
+            .login(UserType::User, Some(&AuthPin::from("1234")))
+            .unwrap();
+        let (public_key, _) = session
+            .generate_key_pair(
+                &Mechanism::EccKeyPairGen,
+                &[
+                    Attribute::Token(true),
+                    Attribute::EcParams(SECP256R1_PARAMS.to_vec()),
+                    Attribute::Verify(true),
+                ],
+                &[
+                    Attribute::Token(true),
+                    Attribute::Private(true),
+                    Attribute::Sign(true),
+                    Attribute::Label(b"client".to_vec()),
+                    Attribute::Id(vec![1]),
+                ],
+            )
+            .unwrap();
+        // The point is wrapped in a DER octet string
+        let point = match session
+            .get_attributes(public_key, &[AttributeType::EcPoint])
+            .unwrap()[..]
+        {
+            [Attribute::EcPoint(ref point)] => point[2..].to_vec(),
+            _ => panic!("No EC point"),
+        };
+
+        let options: Pkcs11Options = toml::from_str(&format!(
+            "module = {module_path:?}\ntoken_label = \"daisyway\"\nkey_label = \"client\"\npin_file = {pin_file:?}"
+        ))
+        .unwrap();
+        let certified_key = options
+            .load(Some(vec![CertificateDer::from(Vec::new())]))
+            .unwrap();
+        let signer = certified_key
+            .key
+            .choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256])
+            .unwrap();
+        let signature = signer.sign(b"message").unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point)
+            .verify(b"message", &signature)
+            .unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
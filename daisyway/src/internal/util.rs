@@ -1,6 +1,7 @@
 use anyhow::Result;
 use base64ct::{Base64, Encoding};
 use zerocopy::FromZeros;
+use zeroize::Zeroizing;
 
 use crate::internal::daisyway::crypto::{Key, KEY_LENGTH_B64};
 
@@ -46,6 +47,10 @@ pub fn base64_to_key(encoded_key: &[u8]) -> Result<Key> {
     Ok(key)
 }
 
+pub fn key_to_base64(key: &Key) -> String {
+    Base64::encode_string(key)
+}
+
 pub fn load_base64_key_file(file: &std::path::Path) -> Result<Key> {
     let mut psk_b64 = [0u8; KEY_LENGTH_B64];
     let psk_b64_len = std::fs::File::open(file)?.read_to_end_up_to(&mut psk_b64)?;
@@ -57,6 +62,48 @@ pub fn load_base64_key_file(file: &std::path::Path) -> Result<Key> {
     base64_to_key(psk_b64)
 }
 
+/// Load a file containing exactly `N` bytes of base64 encoded data
+pub fn load_base64_file<const N: usize>(file: &std::path::Path) -> Result<[u8; N]> {
+    let encoded = std::fs::read_to_string(file)?;
+    let mut buf = [0u8; N];
+    let len = Base64::decode(encoded.trim_end().as_bytes(), &mut buf)
+        .map_err(|e| anyhow::anyhow!(e))?
+        .len();
+    anyhow::ensure!(len == N, "Expected {N} bytes of data, but found {len}");
+    Ok(buf)
+}
+
+/// Write base64 encoded data to a file, which is only readable by the current user
+pub fn store_base64_file(file: &std::path::Path, data: &[u8]) -> Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    let mut encoded = vec![0u8; Base64::encoded_len(data)];
+    Base64::encode(data, &mut encoded).map_err(|e| anyhow::anyhow!(e))?;
+    encoded.push(b'\n');
+
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(file)?;
+    f.write_all(&encoded)?;
+    Ok(())
+}
+
+/// Append data to a buffer that is zeroized when dropped
+///
+/// The buffer is grown by hand, so the old buffer is zeroized instead of being freed as is.
+pub fn extend_zeroizing(buf: &mut Zeroizing<Vec<u8>>, data: &[u8]) {
+    let len = buf.len() + data.len();
+    if len > buf.capacity() {
+        let mut grown = Zeroizing::new(Vec::with_capacity(len.max(2 * buf.capacity())));
+        grown.extend_from_slice(buf);
+        *buf = grown;
+    }
+    buf.extend_from_slice(data);
+}
+
 // TODO: This can be replaced with the IoErrorKind trait in Rosenpass itself
 // if an implementation for anyhow errors is added
 pub fn io_error_kind(e: &anyhow::Error) -> Option<std::io::ErrorKind> {
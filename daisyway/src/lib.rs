@@ -1,3 +1,3 @@
 pub mod internal;
 
-pub use internal::daisyway::{Daisyway, DaisywayConfig};
+pub use internal::daisyway::{generate_kem_keys, Daisyway, DaisywayConfig};
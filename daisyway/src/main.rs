@@ -2,7 +2,7 @@ use std::{io::stdout, path::PathBuf};
 
 use anyhow::{bail, ensure, Context, Result};
 use clap::{CommandFactory, Parser};
-use daisyway::{Daisyway, DaisywayConfig};
+use daisyway::{generate_kem_keys, Daisyway, DaisywayConfig};
 use log::{debug, info};
 use shadow_rs::shadow;
 use tokio::{self, io::AsyncWriteExt};
@@ -106,6 +106,7 @@ impl Cli {
 #[derive(Debug, clap::Subcommand)]
 enum Commands {
     Exchange(ExchangeCommand),
+    GenKemKeys(GenKemKeysCommand),
     Manpage(ManpageCommand),
     ExportManpages(ExportManpagesCommand),
     ShellCompletion(ShellCompletion),
@@ -116,6 +117,7 @@ impl Commands {
         use Commands as C;
         match self {
             C::Exchange(cmd) => cmd.run(cli).await,
+            C::GenKemKeys(cmd) => cmd.run(cli).await,
             C::Manpage(cmd) => cmd.run(cli).await,
             C::ExportManpages(cmd) => cmd.run(cli).await,
             C::ShellCompletion(cmd) => cmd.run(cli).await,
@@ -142,6 +144,8 @@ enum ManpageSelection {
     Daisyway,
     #[clap(alias = "daisyway-exchange(1)", alias = "daisyway-exchange")]
     Exchange,
+    #[clap(alias = "daisyway-gen-kem-keys(1)", alias = "daisyway-gen-kem-keys")]
+    GenKemKeys,
     #[clap(alias = "daisyway-manpage(1)", alias = "daisyway-manpage")]
     Manpage,
     #[clap(
@@ -209,6 +213,7 @@ impl ManpageCommand {
         match self.selection {
             S::Daisyway => Some(cmd),
             S::Exchange => cmd.find_subcommand("exchange"),
+            S::GenKemKeys => cmd.find_subcommand("gen-kem-keys"),
             S::Manpage => cmd.find_subcommand("manpage"),
             S::ExportManpages => cmd.find_subcommand("export-manpages"),
             S::ShellCompletion => cmd.find_subcommand("shell-comletion"),
@@ -288,6 +293,28 @@ impl ExchangeCommand {
     }
 }
 
+/// Generate an ML-KEM key pair for the hybrid post-quantum key exchange
+#[derive(Debug, clap::Args)]
+struct GenKemKeysCommand {
+    /// File to write the secret key to; keep this one private
+    #[arg(long)]
+    secret_key: PathBuf,
+    /// File to write the public key to; hand this one to the peer
+    #[arg(long)]
+    public_key: PathBuf,
+}
+
+impl GenKemKeysCommand {
+    async fn run(&self, _cli: &Cli) -> Result<()> {
+        generate_kem_keys(&self.secret_key, &self.public_key)?;
+        info!(
+            "Wrote ML-KEM secret key to {:?} and public key to {:?}",
+            self.secret_key, self.public_key
+        );
+        Ok(())
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -0,0 +1,41 @@
+#!/bin/sh
+# Regenerates the TLS fixtures used by the tests in src/internal/tls.rs
+set -e
+cd "$(dirname "$0")"
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+days=36500
+
+ec_key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1"
+}
+
+ca() {
+    ec_key "$tmp/$1.key"
+    openssl req -x509 -new -key "$tmp/$1.key" -subj "/CN=$2" -days $days \
+        -addext basicConstraints=critical,CA:TRUE -addext keyUsage=critical,keyCertSign \
+        -out "$1.pem"
+}
+
+leaf() {
+    openssl req -new -key "$1.key" -subj "/CN=$2" -out "$tmp/$1.csr"
+    printf 'basicConstraints=CA:FALSE\nsubjectAltName=DNS:%s\nextendedKeyUsage=%s\n' "$2" "$3" >"$tmp/$1.ext"
+    openssl x509 -req -in "$tmp/$1.csr" -CA ca.pem -CAkey "$tmp/ca.key" -CAcreateserial \
+        -days $days -extfile "$tmp/$1.ext" -out "$1.pem"
+}
+
+ca ca "Daisyway Test CA"
+ca other-ca "Other Test CA"
+cat ca.pem other-ca.pem >ca-bundle.pem
+
+ec_key server.key
+leaf server kme.example serverAuth
+ec_key client.key
+leaf client sae.example clientAuth
+
+printf 'daisyway\n' >passphrase.txt
+printf 'wrong\n' >wrong-passphrase.txt
+openssl pkcs8 -topk8 -in client.key -passout pass:daisyway -out client-encrypted.key
+openssl pkcs12 -export -inkey client.key -in client.pem -certfile ca.pem \
+    -passout pass:daisyway -out client.p12
+rm -f ca.srl
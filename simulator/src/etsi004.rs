@@ -0,0 +1,429 @@
+//! ETSI GS QKD 004 application interface, exchanged as one JSON object per line
+//!
+//! Both applications open the same key stream using OPEN_CONNECT with their own URI as
+//! `source` and the other one's as `destination`. Keys are only handed out while both are
+//! connected, and every key is random and handed out at most once to each of them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use base64ct::{Base64, Encoding};
+use log::{debug, error, info};
+use rand::Rng;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+use uuid::Uuid;
+
+const STATUS_SUCCESSFUL: u32 = 0;
+const STATUS_PEER_NOT_CONNECTED: u32 = 1;
+const STATUS_INSUFFICIENT_KEY_AVAILABLE: u32 = 2;
+const STATUS_PEER_NOT_CONNECTED_GET_KEY: u32 = 3;
+const STATUS_NO_QKD_CONNECTION_AVAILABLE: u32 = 4;
+const STATUS_KEY_STREAM_ID_IN_USE: u32 = 5;
+const STATUS_QOS_NOT_MET: u32 = 7;
+
+const DEFAULT_KEY_CHUNK_SIZE: u64 = 32;
+const MAX_KEY_CHUNK_SIZE: u64 = 1024;
+
+/// A key stream shared by two applications
+#[derive(Debug)]
+struct KeyStream {
+    /// URIs of the two applications
+    apps: [String; 2],
+    /// Applications that currently have the key stream open
+    connected: HashSet<String>,
+    key_chunk_size: usize,
+    /// Index of the first key; later keys follow without gaps
+    first_index: u32,
+    /// Keys that were not yet handed out to both applications
+    keys: HashMap<u32, Vec<u8>>,
+    /// Applications each key was handed out to
+    delivered: HashMap<u32, HashSet<String>>,
+}
+
+impl KeyStream {
+    fn peer_of(&self, app: &str) -> &str {
+        if self.apps[0] == app {
+            &self.apps[1]
+        } else {
+            &self.apps[0]
+        }
+    }
+
+    fn is_delivered(&self, index: u32, app: &str) -> bool {
+        self.delivered
+            .get(&index)
+            .is_some_and(|apps| apps.contains(app))
+    }
+
+    /// Hand out the key at the given index, or the next key this application has not seen
+    fn take_key(&mut self, app: &str, index: Option<u32>) -> Result<(u32, Vec<u8>), u32> {
+        let index = match index {
+            Some(index) if index < self.first_index || self.is_delivered(index, app) => {
+                return Err(STATUS_INSUFFICIENT_KEY_AVAILABLE);
+            }
+            Some(index) => index,
+            None => (self.first_index..=u32::MAX)
+                .find(|index| !self.is_delivered(*index, app))
+                .ok_or(STATUS_INSUFFICIENT_KEY_AVAILABLE)?,
+        };
+
+        let key_chunk_size = self.key_chunk_size;
+        let key = self
+            .keys
+            .entry(index)
+            .or_insert_with(|| {
+                let mut key = vec![0; key_chunk_size];
+                rand::rng().fill(&mut key[..]);
+                key
+            })
+            .clone();
+        let delivered = self.delivered.entry(index).or_default();
+        delivered.insert(app.to_owned());
+        if delivered.len() == 2 {
+            // Both applications have the key now; forget it
+            self.keys.remove(&index);
+        }
+        Ok((index, key))
+    }
+}
+
+/// Key streams by ID
+type KeyStreams = Arc<Mutex<HashMap<Uuid, KeyStream>>>;
+
+/// Key streams opened over a single connection, with the URI of the application
+type OpenedStreams = HashMap<Uuid, String>;
+
+/// Serve the ETSI 004 application interface, over TLS if an acceptor is given
+pub async fn serve(listener: TcpListener, tls_acceptor: Option<TlsAcceptor>) -> Result<()> {
+    info!("Starting ETSI 004 server on {}", listener.local_addr()?);
+    let streams = KeyStreams::default();
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let streams = streams.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+            let res = match tls_acceptor {
+                Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+                    Ok(stream) => handle_connection(stream, streams).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => handle_connection(stream, streams).await,
+            };
+            if let Err(e) = res {
+                error!("ETSI 004 connection from {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection<S>(stream: S, streams: KeyStreams) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    let mut opened = OpenedStreams::new();
+
+    let mut line = String::new();
+    let res = loop {
+        line.clear();
+        match stream.read_line(&mut line).await {
+            Ok(0) => break Ok(()),
+            Ok(_) => {}
+            Err(e) => break Err(e.into()),
+        }
+        debug!("ETSI 004 request: {}", line.trim_end());
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handle_call(&request, &streams, &mut opened),
+            Err(e) => error_response(&format!("Invalid JSON: {e}")),
+        };
+        let mut response = response.to_string();
+        response.push('\n');
+        let writer = stream.get_mut();
+        if let Err(e) = writer.write_all(response.as_bytes()).await {
+            break Err(e.into());
+        }
+        if let Err(e) = writer.flush().await {
+            break Err(e.into());
+        }
+    };
+
+    // Applications that disconnect without CLOSE leave their key streams as well
+    close_all(&mut opened, &streams);
+    res
+}
+
+fn handle_call(request: &Value, streams: &KeyStreams, opened: &mut OpenedStreams) -> Value {
+    let key_stream_id = request["key_stream_id"]
+        .as_str()
+        .and_then(|id| Uuid::parse_str(id).ok());
+
+    match request["command"].as_str() {
+        Some("OPEN_CONNECT") => {
+            let (Some(source), Some(destination)) =
+                (request["source"].as_str(), request["destination"].as_str())
+            else {
+                return error_response("source and destination are required");
+            };
+            let key_chunk_size = request["qos"]["key_chunk_size"]
+                .as_u64()
+                .unwrap_or(DEFAULT_KEY_CHUNK_SIZE);
+            if key_chunk_size == 0 || key_chunk_size > MAX_KEY_CHUNK_SIZE {
+                return json!({ "status": STATUS_QOS_NOT_MET });
+            }
+            let key_stream_id = key_stream_id.unwrap_or_else(Uuid::new_v4);
+            open_connect(
+                streams,
+                opened,
+                key_stream_id,
+                source,
+                destination,
+                key_chunk_size as usize,
+            )
+        }
+        Some("GET_KEY") => {
+            let Some(key_stream_id) = key_stream_id else {
+                return error_response("key_stream_id is required");
+            };
+            let index = request["index"].as_u64().map(|index| index as u32);
+            get_key(streams, opened, key_stream_id, index)
+        }
+        Some("CLOSE") => {
+            let Some(key_stream_id) = key_stream_id else {
+                return error_response("key_stream_id is required");
+            };
+            let Some(app) = opened.remove(&key_stream_id) else {
+                return json!({ "status": STATUS_NO_QKD_CONNECTION_AVAILABLE });
+            };
+            leave(&mut streams.lock().unwrap(), key_stream_id, &app);
+            json!({ "status": STATUS_SUCCESSFUL })
+        }
+        _ => error_response("Unknown command"),
+    }
+}
+
+fn open_connect(
+    streams: &KeyStreams,
+    opened: &mut OpenedStreams,
+    key_stream_id: Uuid,
+    source: &str,
+    destination: &str,
+    key_chunk_size: usize,
+) -> Value {
+    let mut streams = streams.lock().unwrap();
+    let stream = streams.entry(key_stream_id).or_insert_with(|| KeyStream {
+        apps: [source.to_owned(), destination.to_owned()],
+        connected: HashSet::new(),
+        key_chunk_size,
+        first_index: first_key_index(),
+        keys: HashMap::new(),
+        delivered: HashMap::new(),
+    });
+
+    let same_apps =
+        stream.apps.contains(&source.to_owned()) && stream.peer_of(source) == destination;
+    if !same_apps || stream.connected.contains(source) {
+        info!("Key stream {key_stream_id} is already in use; rejecting {source}");
+        return json!({ "status": STATUS_KEY_STREAM_ID_IN_USE });
+    }
+    if stream.key_chunk_size != key_chunk_size {
+        return json!({ "status": STATUS_QOS_NOT_MET });
+    }
+
+    stream.connected.insert(source.to_owned());
+    opened.insert(key_stream_id, source.to_owned());
+    let status = match stream.connected.contains(destination) {
+        true => STATUS_SUCCESSFUL,
+        false => STATUS_PEER_NOT_CONNECTED,
+    };
+    info!("{source} opened key stream {key_stream_id}");
+    json!({ "status": status, "key_stream_id": key_stream_id })
+}
+
+fn get_key(
+    streams: &KeyStreams,
+    opened: &OpenedStreams,
+    key_stream_id: Uuid,
+    index: Option<u32>,
+) -> Value {
+    let mut streams = streams.lock().unwrap();
+    let (Some(app), Some(stream)) = (opened.get(&key_stream_id), streams.get_mut(&key_stream_id))
+    else {
+        return json!({ "status": STATUS_NO_QKD_CONNECTION_AVAILABLE });
+    };
+    if !stream.connected.contains(stream.peer_of(app)) {
+        return json!({ "status": STATUS_PEER_NOT_CONNECTED_GET_KEY });
+    }
+
+    match stream.take_key(app, index) {
+        Ok((index, key)) => {
+            info!("Key {index} of key stream {key_stream_id} handed out to {app}");
+            json!({
+                "status": STATUS_SUCCESSFUL,
+                "index": index,
+                "key_buffer": Base64::encode_string(&key),
+            })
+        }
+        Err(status) => json!({ "status": status }),
+    }
+}
+
+/// Let an application leave a key stream; the key stream ends once both left
+fn leave(streams: &mut HashMap<Uuid, KeyStream>, key_stream_id: Uuid, app: &str) {
+    let Some(stream) = streams.get_mut(&key_stream_id) else {
+        return;
+    };
+    stream.connected.remove(app);
+    info!("{app} closed key stream {key_stream_id}");
+    if stream.connected.is_empty() {
+        streams.remove(&key_stream_id);
+    }
+}
+
+fn close_all(opened: &mut OpenedStreams, streams: &KeyStreams) {
+    let mut streams = streams.lock().unwrap();
+    for (key_stream_id, app) in opened.drain() {
+        leave(&mut streams, key_stream_id, &app);
+    }
+}
+
+/// Start key indices at the current time, so key IDs stay unique across simulator restarts
+fn first_key_index() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+fn error_response(msg: &str) -> Value {
+    error!("Bad request: {msg}");
+    json!({ "status": u32::MAX, "message": msg })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "daisyway://alice";
+    const BOB: &str = "daisyway://bob";
+
+    /// One simulated KME connection of an application
+    struct App {
+        uri: &'static str,
+        peer: &'static str,
+        opened: OpenedStreams,
+    }
+
+    impl App {
+        fn new(uri: &'static str, peer: &'static str) -> Self {
+            Self {
+                uri,
+                peer,
+                opened: OpenedStreams::new(),
+            }
+        }
+
+        fn call(&mut self, streams: &KeyStreams, request: Value) -> Value {
+            handle_call(&request, streams, &mut self.opened)
+        }
+
+        fn open(&mut self, streams: &KeyStreams, id: Uuid) -> Value {
+            let request = json!({
+                "command": "OPEN_CONNECT",
+                "source": self.uri,
+                "destination": self.peer,
+                "key_stream_id": id,
+            });
+            self.call(streams, request)
+        }
+
+        fn get_key(&mut self, streams: &KeyStreams, id: Uuid, index: Option<u64>) -> Value {
+            let request = json!({ "command": "GET_KEY", "key_stream_id": id, "index": index });
+            self.call(streams, request)
+        }
+    }
+
+    fn connected_pair(streams: &KeyStreams, id: Uuid) -> (App, App) {
+        let (mut alice, mut bob) = (App::new(ALICE, BOB), App::new(BOB, ALICE));
+        assert_eq!(alice.open(streams, id)["status"], STATUS_PEER_NOT_CONNECTED);
+        assert_eq!(bob.open(streams, id)["status"], STATUS_SUCCESSFUL);
+        (alice, bob)
+    }
+
+    #[test]
+    fn both_applications_get_the_same_key() {
+        let (streams, id) = (KeyStreams::default(), Uuid::new_v4());
+        let (mut alice, mut bob) = connected_pair(&streams, id);
+
+        let first = alice.get_key(&streams, id, None);
+        let second = alice.get_key(&streams, id, None);
+        assert_eq!(first["status"], STATUS_SUCCESSFUL);
+        assert_ne!(first["key_buffer"], second["key_buffer"]);
+        assert_eq!(second["index"], first["index"].as_u64().unwrap() + 1);
+
+        let index = first["index"].as_u64();
+        let matching = bob.get_key(&streams, id, index);
+        assert_eq!(matching["key_buffer"], first["key_buffer"]);
+
+        // Every key is handed out once per application
+        let again = bob.get_key(&streams, id, index);
+        assert_eq!(again["status"], STATUS_INSUFFICIENT_KEY_AVAILABLE);
+    }
+
+    #[test]
+    fn keys_require_a_connected_peer() {
+        let (streams, id) = (KeyStreams::default(), Uuid::new_v4());
+        let mut alice = App::new(ALICE, BOB);
+        alice.open(&streams, id);
+        let response = alice.get_key(&streams, id, None);
+        assert_eq!(response["status"], STATUS_PEER_NOT_CONNECTED_GET_KEY);
+
+        let mut bob = App::new(BOB, ALICE);
+        let response = bob.get_key(&streams, id, None);
+        assert_eq!(response["status"], STATUS_NO_QKD_CONNECTION_AVAILABLE);
+    }
+
+    #[test]
+    fn key_stream_is_reserved_for_its_applications() {
+        let (streams, id) = (KeyStreams::default(), Uuid::new_v4());
+        let (mut alice, _bob) = connected_pair(&streams, id);
+
+        let mut mallory = App::new("daisyway://mallory", BOB);
+        assert_eq!(
+            mallory.open(&streams, id)["status"],
+            STATUS_KEY_STREAM_ID_IN_USE
+        );
+        let mut alice_again = App::new(ALICE, BOB);
+        assert_eq!(
+            alice_again.open(&streams, id)["status"],
+            STATUS_KEY_STREAM_ID_IN_USE
+        );
+
+        // After closing, the application may open the key stream again
+        let close = json!({ "command": "CLOSE", "key_stream_id": id });
+        assert_eq!(alice.call(&streams, close)["status"], STATUS_SUCCESSFUL);
+        assert_eq!(alice_again.open(&streams, id)["status"], STATUS_SUCCESSFUL);
+    }
+
+    #[test]
+    fn disconnecting_leaves_the_key_stream() {
+        let (streams, id) = (KeyStreams::default(), Uuid::new_v4());
+        let (mut alice, mut bob) = connected_pair(&streams, id);
+
+        close_all(&mut alice.opened, &streams);
+        let response = bob.get_key(&streams, id, None);
+        assert_eq!(response["status"], STATUS_PEER_NOT_CONNECTED_GET_KEY);
+
+        close_all(&mut bob.opened, &streams);
+        assert!(streams.lock().unwrap().is_empty());
+    }
+}
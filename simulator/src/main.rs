@@ -4,22 +4,30 @@ use std::{
         Arc,
         atomic::{AtomicU64, Ordering},
     },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{Error, Result};
 use base64ct::{Base64, Encoding};
 use clap::Parser;
 use http_body_util::{BodyExt, Empty, Full, combinators::BoxBody};
-use hyper::{Request, Response, StatusCode, body::Bytes, server::conn::http1, service::service_fn};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+};
 use hyper_util::rt::TokioIo;
 use log::{debug, error, info};
 use rustls::{RootCertStore, ServerConfig, server::WebPkiClientVerifier};
 use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
-use serde_json::json;
+use serde_json::{Value, json};
 use tokio::net::TcpListener;
 use tokio_rustls::TlsAcceptor;
 use uuid::Uuid;
 
+mod etsi004;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -41,20 +49,43 @@ struct Args {
 
     #[arg(long)]
     danger_allow_insecure_no_server_name_certificates: bool,
+
+    /// Serve the ETSI 004 application interface instead of the ETSI 014 REST API
+    #[arg(long)]
+    etsi004: bool,
 }
 
 async fn handle_request(
-    req: Request<impl hyper::body::Body>,
+    req: Request<Incoming>,
     counter: Arc<AtomicU64>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    let path = req.uri().path();
-    let query = req.uri().query();
+    let path = req.uri().path().to_owned();
+    let path = path.as_str();
+    let query = req.uri().query().map(str::to_owned);
+    let query = query.as_deref();
 
     info!("Received request: {}", path);
     debug!("Query parameters: {:?}", query);
 
+    if let Some(sae_id) = path
+        .strip_prefix("/api/v1/keys/")
+        .and_then(|p| p.strip_suffix("/status"))
+    {
+        return Ok(handle_status(sae_id));
+    }
+
     if path.starts_with("/api/v1/keys/") {
-        return handle_keys(path, query, counter);
+        let body = match *req.method() {
+            Method::POST => {
+                let body = req.into_body().collect().await?.to_bytes();
+                match serde_json::from_slice::<Value>(&body) {
+                    Ok(body) => Some(body),
+                    Err(_) => return Ok(bad_request("Invalid JSON body")),
+                }
+            }
+            _ => None,
+        };
+        return handle_keys(path, query, body.as_ref(), counter);
     }
 
     info!("Request not found: {}", path);
@@ -77,9 +108,53 @@ fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
+fn handle_status(slave_sae_id: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
+    info!("Handling status request for SAE {}", slave_sae_id);
+
+    let response_body = json!({
+        "source_KME_ID": "KME_SIM_A",
+        "target_KME_ID": "KME_SIM_B",
+        "master_SAE_ID": "SAE_001",
+        "slave_SAE_ID": slave_sae_id,
+        "key_size": DEFAULT_KEY_SIZE,
+        "stored_key_count": 25000,
+        "max_key_count": 100000,
+        "max_key_per_request": MAX_KEY_PER_REQUEST,
+        "max_key_size": MAX_KEY_SIZE,
+        "min_key_size": MIN_KEY_SIZE,
+        "max_SAE_ID_count": 0
+    })
+    .to_string();
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", response_body.len().to_string())
+        .body(full(response_body))
+        .unwrap()
+}
+
+/// Largest number of keys served by a single request; reported by the status endpoint
+const MAX_KEY_PER_REQUEST: u64 = 128;
+
+/// Key sizes in bits; reported by the status endpoint
+const DEFAULT_KEY_SIZE: u64 = 256;
+const MIN_KEY_SIZE: u64 = 64;
+const MAX_KEY_SIZE: u64 = 1024;
+
+/// Key IDs carry the key size in their upper 64 bits, so `dec_keys` can serve keys of the
+/// size requested via `enc_keys`. Zero stands for the default size.
+fn key_size_of(key_id: &Uuid) -> u64 {
+    match (key_id.as_u128() >> 64) as u64 {
+        0 => DEFAULT_KEY_SIZE,
+        size => size,
+    }
+}
+
 fn handle_keys(
     path: &str,
     query: Option<&str>,
+    body: Option<&Value>,
     counter: Arc<AtomicU64>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     info!("Handling key request: {}", path);
@@ -89,12 +164,27 @@ fn handle_keys(
         return Ok(bad_request("Only one of /dec_keys or /enc_keys is allowed"));
     }
 
-    let key_id = if path.contains("dec_keys") {
-        match query {
-            Some(q) => {
+    let key_ids: Vec<Uuid> = if path.contains("dec_keys") {
+        match (body, query) {
+            (Some(body), _) => {
+                let ids: Option<Vec<Uuid>> = body["key_IDs"].as_array().and_then(|entries| {
+                    entries
+                        .iter()
+                        .map(|entry| entry["key_ID"].as_str()?.parse().ok())
+                        .collect()
+                });
+                match ids {
+                    Some(ids) if !ids.is_empty() => ids,
+                    _ => {
+                        error!("Invalid key_IDs in body: {}", body);
+                        return Ok(bad_request("Invalid key_IDs format"));
+                    }
+                }
+            }
+            (None, Some(q)) => {
                 if let Some(pos) = q.find("key_ID=") {
                     match Uuid::parse_str(&q[(pos + 7)..]) {
-                        Ok(id) => id,
+                        Ok(id) => vec![id],
                         Err(_) => {
                             error!("Invalid key_ID format in query: {}", q);
                             return Ok(bad_request("Invalid key_ID format"));
@@ -105,31 +195,59 @@ fn handle_keys(
                     return Ok(bad_request("Invalid key_ID format"));
                 }
             }
-            None => {
+            (None, None) => {
                 error!("key_ID parameter is required but missing");
                 return Ok(bad_request("key_ID parameter is required"));
             }
         }
     } else {
-        let count = counter.fetch_add(1, Ordering::SeqCst);
-        debug!("Incremented counter to: {}", count);
-
-        Uuid::from_u128(count as u128)
+        let param = |name| match body {
+            Some(body) => body[name].as_u64(),
+            None => query_param(query, name).and_then(|n| n.parse().ok()),
+        };
+        let number = param("number").unwrap_or(1);
+        if number == 0 || number > MAX_KEY_PER_REQUEST {
+            return Ok(bad_request("Invalid number of keys"));
+        }
+        let size = param("size").unwrap_or(DEFAULT_KEY_SIZE);
+        if !(MIN_KEY_SIZE..=MAX_KEY_SIZE).contains(&size) || size % 8 != 0 {
+            return Ok(bad_request("Invalid key size"));
+        }
+        let size_tag = match size {
+            DEFAULT_KEY_SIZE => 0,
+            size => size,
+        };
+
+        (0..number)
+            .map(|_| {
+                let count = counter.fetch_add(1, Ordering::SeqCst);
+                debug!("Incremented counter to: {}", count);
+                Uuid::from_u128(((size_tag as u128) << 64) | count as u128)
+            })
+            .collect()
     };
 
-    let mut key_input = [0u8; 32];
-    let key_id_bytes = key_id.as_bytes();
-    key_input[..16].copy_from_slice(key_id_bytes);
-    key_input[16..].copy_from_slice(key_id_bytes);
+    let keys: Vec<Value> = key_ids
+        .iter()
+        .map(|key_id| {
+            // The key is the key ID, repeated to fill the key size
+            let key_input: Vec<u8> = key_id
+                .as_bytes()
+                .iter()
+                .copied()
+                .cycle()
+                .take((key_size_of(key_id) / 8) as usize)
+                .collect();
 
-    let mut enc_buf = [0u8; 128];
-    let encoded_key: &str = Base64::encode(&key_input, &mut enc_buf).unwrap();
-    debug!("Encoded key: {}", encoded_key);
+            let mut enc_buf = [0u8; 256];
+            let encoded_key: &str = Base64::encode(&key_input, &mut enc_buf).unwrap();
+            debug!("Encoded key: {}", encoded_key);
 
-    let response_body = json!({
-        "keys": [{ "key": encoded_key, "key_ID": key_id.to_string() }]
-    })
-    .to_string();
+            json!({ "key": encoded_key, "key_ID": key_id.to_string() })
+        })
+        .collect();
+
+    let response_body = json!({ "keys": keys }).to_string();
 
     let response = Response::builder()
         .status(StatusCode::OK)
@@ -138,16 +256,23 @@ fn handle_keys(
         .body(full(response_body))
         .unwrap();
 
-    info!("Key response generated for key_ID: {}", key_id);
+    info!("Key response generated for key_IDs: {:?}", key_ids);
     Ok(response)
 }
 
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+}
+
 fn bad_request(msg: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
     error!("Bad request: {}", msg);
 
     Response::builder()
         .status(StatusCode::BAD_REQUEST)
-        .body(full(msg.to_string()))
+        .header("Content-Type", "application/json")
+        .body(full(json!({ "message": msg }).to_string()))
         .unwrap()
 }
 
@@ -156,7 +281,10 @@ fn load_tls_config(
     key_path: &str,
     ca_path: Option<String>,
 ) -> Result<ServerConfig, Error> {
-    let cert = CertificateDer::from_pem_file(cert_path).expect("Failed to read certificate file");
+    // The certificate file may contain intermediate CA certificates after the server certificate
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .expect("Failed to read certificate file");
     let key = PrivateKeyDer::from_pem_file(key_path).expect("Failed to read private key file");
 
     let config: ServerConfig;
@@ -174,12 +302,12 @@ fn load_tls_config(
 
         config = ServerConfig::builder()
             .with_client_cert_verifier(verifier)
-            .with_single_cert(vec![cert], key)
+            .with_single_cert(certs, key)
             .expect("Failed to create server config");
     } else {
         config = ServerConfig::builder()
             .with_no_client_auth()
-            .with_single_cert(vec![cert], key)
+            .with_single_cert(certs, key)
             .unwrap();
     }
 
@@ -187,6 +315,8 @@ fn load_tls_config(
 }
 
 #[tokio::main]
+// The TLS setup predates the lints; keep it as is
+#[allow(clippy::needless_late_init, clippy::unnecessary_unwrap)]
 async fn main() -> anyhow::Result<()> {
     env_logger::init(); // Initialize logging
     let args = Args::parse();
@@ -198,10 +328,15 @@ async fn main() -> anyhow::Result<()> {
     }
 
     let addr = args.addr;
-    let counter = Arc::new(AtomicU64::new(1));
+
+    // Start key IDs at the current time, so they stay unique across simulator restarts
+    let first_key_id = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let counter = Arc::new(AtomicU64::new(first_key_id));
 
     let tls_acceptor: Option<TlsAcceptor>;
     if args.cert_path.is_some() && args.key_path.is_some() {
+        // Several crypto providers are compiled in, so rustls can not choose one by itself
+        let _ = rustls::crypto::ring::default_provider().install_default();
         let tls_config = load_tls_config(
             &args.cert_path.unwrap(),
             &args.key_path.unwrap(),
@@ -212,6 +347,9 @@ async fn main() -> anyhow::Result<()> {
         tls_acceptor = None;
     }
     let listener = TcpListener::bind(&addr).await?;
+    if args.etsi004 {
+        return etsi004::serve(listener, tls_acceptor).await;
+    }
     info!("Starting TLS server on https://{}", addr);
 
     loop {